@@ -1,92 +1,151 @@
 use clap::{AppSettings, Clap};
-use lapin::{
-    options::{BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, QueueDeclareOptions},
-    types::FieldTable,
-    BasicProperties, Channel, Connection, ConnectionProperties, Result,
+use lapin::{options::BasicAckOptions, BasicProperties, Channel, Result};
+use std::sync::Arc;
+use tracing::{info, warn};
+use tutorial_rs::{
+    topology::QueueSpec, CompressionOpts, ConnectionOpts, Consumer, ContentType, Dedup, DedupOpts,
+    EnvelopeOpts, Health, HealthOpts, Keyring, Metrics, MetricsOpts, Publisher, Shutdown,
+    ShutdownOpts, Supervisor, TelemetryOpts, TopologyOpts,
 };
-use tokio_amqp::LapinTokioExt;
 
 /// Basic receiver and sender example.
 #[derive(Debug, Clap)]
 #[clap(name = "RabbitMQ - Tutorial 01", setting = AppSettings::ColoredHelp)]
 struct Opts {
-    #[clap(long, default_value = "127.0.0.1")]
-    addr: String,
-    #[clap(long, default_value = "5672")]
-    port: u32,
+    #[clap(flatten)]
+    conn: ConnectionOpts,
+    #[clap(flatten)]
+    telemetry: TelemetryOpts,
+    #[clap(flatten)]
+    shutdown: ShutdownOpts,
+    #[clap(flatten)]
+    topology: TopologyOpts,
+    #[clap(flatten)]
+    envelope: EnvelopeOpts,
+    #[clap(flatten)]
+    metrics: MetricsOpts,
+    #[clap(flatten)]
+    health: HealthOpts,
+    #[clap(flatten)]
+    dedup: DedupOpts,
+    #[clap(flatten)]
+    compression: CompressionOpts,
+    /// Enable publisher confirms and report whether the broker acked, nacked or
+    /// returned the message as unroutable
+    #[clap(long)]
+    confirm: bool,
+    /// Encoding of the message body: `raw`, `json`, `msgpack` or `cbor`
+    #[clap(long, default_value = "raw")]
+    content_type: ContentType,
     /// Specify if the mode is `receive` or `send` if false
     #[clap(short, long)]
     receive: bool,
 }
 
-async fn send(channel: Channel) -> Result<()> {
+async fn send(queue: &str, content_type: ContentType, publisher: Publisher) -> Result<()> {
     let payload = "Hello World!";
 
-    let confirm = channel
-        .basic_publish(
+    let confirm = publisher
+        .publish_encoded(
             "",
-            "hello",
-            BasicPublishOptions::default(),
-            payload.as_bytes().to_vec(),
+            queue,
+            &content_type,
+            &payload,
             BasicProperties::default(),
         )
-        .await?
         .await?;
 
-    println!("[x] Sent {}\nconfirm: {:?}", payload, confirm);
+    info!(%confirm, "Sent {}", payload);
     Ok(())
 }
 
-async fn receive(channel: Channel) -> Result<()> {
-    let consumer = channel
-        .basic_consume(
-            "hello",
-            "",
-            BasicConsumeOptions::default(),
-            FieldTable::default(),
-        )
-        .await?;
+/// How the receiver gets at message bodies: opened with `keyring`, and decompressed
+/// to at most `max_decompressed` bytes.
+#[derive(Debug, Clone)]
+struct Decoding {
+    keyring: Option<Arc<Keyring>>,
+    max_decompressed: usize,
+}
 
-    let mut it = consumer.into_iter();
-    println!(" [*] Waiting for messages. To exit press CTRL+C");
-    while let Some(delivery) = it.next() {
-        match delivery {
-            Ok((_channel, delivery)) => {
-                delivery
-                    .ack(BasicAckOptions::default())
-                    .await
-                    .expect("basic_ack");
-                let msg = std::str::from_utf8(&delivery.data).expect("invalid string");
-                println!(" [x] Received {}", msg);
-            }
-            Err(error) => {
-                println!("Error caught in consumer: {}", error)
+async fn receive(
+    channel: Channel,
+    queue: String,
+    shutdown: Shutdown,
+    decoding: Decoding,
+    metrics: Option<Metrics>,
+    health: Option<Health>,
+    dedup: Option<Dedup>,
+) -> Result<()> {
+    let consumer = Consumer::<Vec<u8>>::start(&channel, &queue, 0)
+        .await?
+        .with_shutdown(shutdown)
+        .with_keyring(decoding.keyring)
+        .with_max_decompressed(decoding.max_decompressed)
+        .with_metrics(metrics)
+        .with_health(health)
+        .with_dedup(dedup);
+
+    info!("Waiting for messages. To exit press CTRL+C");
+    consumer
+        .for_each_concurrent(|delivery| async move {
+            delivery.ack(BasicAckOptions::default()).await?;
+            match delivery.decode::<String>() {
+                Ok(msg) => info!("Received {}", msg),
+                Err(error) => warn!("Dropping message: {}", error),
             }
-        }
-    }
+            Ok(())
+        })
+        .await
+}
 
-    Ok(())
+async fn declare_queue(channel: Channel, queue: QueueSpec) -> Result<()> {
+    queue.declare(&channel).await
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let opts = Opts::parse();
-    let addr = format!("amqp://{}:{}/%2f", opts.addr, opts.port);
-    let conn = Connection::connect(&addr, ConnectionProperties::default().with_tokio()).await?;
-    let channel = conn.create_channel().await?;
-
-    let _queue = channel
-        .queue_declare(
-            "hello",
-            QueueDeclareOptions::default(),
-            FieldTable::default(),
-        )
-        .await?;
+    let _telemetry = opts.telemetry.init(env!("CARGO_BIN_NAME"))?;
+    let keyring = opts.envelope.load()?;
+    let queue = opts.topology.load()?.queue("hello")?.clone();
 
     if opts.receive {
-        receive(channel).await?;
+        let metrics = opts.metrics.start(env!("CARGO_BIN_NAME"))?;
+        let health = opts.health.start()?;
+        let dedup = opts.dedup.open()?;
+        let shutdown = opts.shutdown.listen();
+        let decoding = Decoding {
+            keyring: keyring.clone(),
+            max_decompressed: opts.compression.max_decompressed,
+        };
+        let declared = queue.clone();
+        Supervisor::new(opts.conn)
+            .with_shutdown(shutdown.clone())
+            .with_health(health.clone())
+            .declare(move |channel| declare_queue(channel, declared.clone()))
+            .run(|channel| {
+                receive(
+                    channel,
+                    queue.name.clone(),
+                    shutdown.clone(),
+                    decoding.clone(),
+                    metrics.clone(),
+                    health.clone(),
+                    dedup.clone(),
+                )
+            })
+            .await?;
     } else {
-        send(channel).await?;
+        let metrics = opts.metrics.start(env!("CARGO_BIN_NAME"))?;
+        let conn = opts.conn.connect().await?;
+        let channel = conn.create_channel().await?;
+        declare_queue(channel.clone(), queue.clone()).await?;
+        let publisher = Publisher::new(channel, opts.confirm)
+            .await?
+            .with_compression(opts.compression.compression())
+            .with_keyring(keyring)
+            .with_metrics(metrics);
+        send(&queue.name, opts.content_type, publisher).await?;
     }
 
     Ok(())
@@ -1,16 +1,26 @@
-use std::time::Duration;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use clap::{AppSettings, Clap};
-use lapin::{
-    options::{
-        BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, BasicQosOptions,
-        QueueDeclareOptions,
-    },
-    types::FieldTable,
-    BasicProperties, Channel, Connection, ConnectionProperties, Result,
+use futures::future::{BoxFuture, FutureExt};
+use lapin::{options::BasicAckOptions, BasicProperties, Channel, Result};
+use serde_json::{json, Value};
+use tokio::time::{sleep, timeout, Instant};
+use tracing::{info, warn};
+use tutorial_rs::{
+    delay::MAX_DELAY,
+    topology::{ExchangeSpec, QueueSpec},
+    CompressionOpts, ConnectionOpts, Consumer, ContentType, Dedup, DedupOpts, DelayQueues,
+    Delivery, EnvelopeOpts, Failure, FailureAction, Health, HealthOpts, JobReporter, JobReports,
+    JobStatus, JobWatch, Keyring, Message, Metrics, MetricsOpts, Pool, Publisher, ResultStore,
+    RetryPolicy, Shutdown, ShutdownOpts, Supervisor, TaskHandler, TaskRegistry, TelemetryOpts,
+    TopologyOpts,
 };
-use tokio::time::sleep;
-use tokio_amqp::LapinTokioExt;
+use uuid::Uuid;
 
 /// This tutorial focuses on 2 things:
 ///
@@ -19,96 +29,542 @@ use tokio_amqp::LapinTokioExt;
 /// 2. Set the channel `prefetch_count` to control how many tasks a worker can have
 ///    at any time. Setting this to 1 will dispatch tasks only to workers that are
 ///    not busy.
+///
+/// With `--concurrency N`, a worker handles up to N tasks at once, each on a tokio
+/// task of its own, and sets its prefetch to N: it behaves like N workers with a
+/// prefetch of 1, and is only dispatched tasks while one of its slots is free.
+///
+/// Workers dispatch each task to the handler of its `--type`: `sleep`, the default,
+/// stays busy for a second per `.` in the task, and `wordcount` counts its words.
+///
+/// With `--dedup`, workers ack without running them the tasks they already handled,
+/// as when a worker dies between handling a task and acking it.
+///
+/// Every task is a job, identified by its `message_id`. Workers publish its
+/// `started`, `progress`, `succeeded` and `failed` events to the `task_status`
+/// topic exchange, routed by `<job id>.<state>`, or a `skipped` event when it is a
+/// duplicate, and with `--results` keep the latest of them in a SQLite file that
+/// `--job` looks jobs up in. `--wait` follows the events of the task it sends until
+/// the job is over, parked or skipped included.
+///
+/// With `--delay` or `--at`, tasks go through a `task_queue.delay.<ms>` queue first,
+/// where they expire into `task_queue` once due.
+///
+/// Failed tasks (simulated by tasks containing `fail`) are retried after each of the
+/// `--retry-delays`, then parked in `task_queue.parking-lot`. Malformed tasks, tasks
+/// of an unknown type, and with `--keyring` tasks that fail to open, are parked right
+/// away. An existing `task_queue` declared without these settings has to be deleted
+/// first. The retry queues and parking lot are named after the work
+/// queue, whatever name the topology gives it.
 #[derive(Debug, Clap)]
 #[clap(name = "RabbitMQ - Tutorial 02", setting = AppSettings::ColoredHelp)]
 struct Opts {
     #[clap(default_value = "Hello World!")]
     msg: String,
-    #[clap(long, default_value = "127.0.0.1")]
-    addr: String,
-    #[clap(long, default_value = "5672")]
-    port: u32,
+    #[clap(flatten)]
+    conn: ConnectionOpts,
+    #[clap(flatten)]
+    telemetry: TelemetryOpts,
+    #[clap(flatten)]
+    shutdown: ShutdownOpts,
+    #[clap(flatten)]
+    topology: TopologyOpts,
+    #[clap(flatten)]
+    envelope: EnvelopeOpts,
+    #[clap(flatten)]
+    metrics: MetricsOpts,
+    #[clap(flatten)]
+    health: HealthOpts,
+    #[clap(flatten)]
+    dedup: DedupOpts,
+    #[clap(flatten)]
+    compression: CompressionOpts,
+    /// Enable publisher confirms and report whether the broker acked, nacked or
+    /// returned the message as unroutable
+    #[clap(long)]
+    confirm: bool,
+    /// Type of the task, which picks the handler workers run it with
+    #[clap(long = "type")]
+    kind: Option<String>,
+    /// Hold the task back for this long before workers get it, e.g. `30s` or `1h 30m`
+    #[clap(long)]
+    delay: Option<humantime::Duration>,
+    /// Hold the task back until this RFC 3339 time, e.g. `2030-01-01T09:00:00Z`
+    #[clap(long, conflicts_with = "delay")]
+    at: Option<humantime::Timestamp>,
+    /// Encoding of the task body: `raw`, `json`, `msgpack` or `cbor`
+    #[clap(long, default_value = "raw")]
+    content_type: ContentType,
+    /// Enqueue `msg` this many times, suffixed with its index, in pipelined batches
+    #[clap(long)]
+    count: Option<usize>,
+    /// Enqueue every non-empty line of this file as a task, in pipelined batches
+    #[clap(long, conflicts_with = "count")]
+    from_file: Option<PathBuf>,
+    /// Maximum number of unconfirmed tasks in flight when batching
+    #[clap(long, default_value = "256")]
+    window: usize,
+    /// Number of tasks per reported batch
+    #[clap(long, default_value = "1000")]
+    batch_size: usize,
+    /// Follow the status of the task until its job succeeds or fails
+    #[clap(long, conflicts_with_all = &["count", "from-file"])]
+    wait: bool,
+    /// Seconds to follow the task with `--wait` before giving up
+    #[clap(long, default_value = "60")]
+    wait_timeout: u64,
+    /// SQLite file the worker records the outcome of jobs in
+    #[clap(long)]
+    results: Option<PathBuf>,
+    /// Print what `--results` recorded about this job, and exit
+    #[clap(long, requires = "results")]
+    job: Option<String>,
+    /// Number of tasks a worker handles at once
+    #[clap(long, default_value = "1")]
+    concurrency: u16,
+    /// Seconds between reports of how busy each of the worker's slots was
+    #[clap(long, default_value = "60")]
+    utilisation_interval: u64,
+    /// Times a nacked task is republished before giving up on it
+    #[clap(long, default_value = "3")]
+    max_retries: u32,
+    /// Seconds to wait before each retry of a failed task
+    #[clap(long, use_delimiter = true, default_value = "5,30,300")]
+    retry_delays: Vec<u64>,
     /// Specify if the mode is `receive` or `send` if false
     #[clap(short, long)]
     worker: bool,
 }
 
-async fn new_task(msg: String, channel: Channel) -> Result<()> {
-    let payload = msg.as_bytes().to_vec();
+fn task_properties(kind: Option<&str>, delay: Option<Duration>) -> BasicProperties {
+    let mut properties = BasicProperties::default().with_delivery_mode(2); // make message persistent
+    if let Some(kind) = kind {
+        properties = properties.with_kind(kind.into());
+    }
+    match delay {
+        Some(delay) => DelayQueues::properties(properties, delay),
+        None => properties,
+    }
+}
 
-    let confirm = channel
-        .basic_publish(
-            "",
-            "task_queue",
-            BasicPublishOptions::default(),
-            payload,
-            BasicProperties::default().with_delivery_mode(2), // make message persistent
+/// How long to hold tasks back, from `--delay` or `--at`, up to [`MAX_DELAY`].
+fn delay(opts: &Opts) -> Result<Option<Duration>> {
+    let delay = match (opts.delay, opts.at) {
+        (Some(delay), _) => delay.into(),
+        (None, Some(at)) => SystemTime::from(at)
+            .duration_since(SystemTime::now())
+            .unwrap_or_default(),
+        (None, None) => return Ok(None),
+    };
+    if delay > MAX_DELAY {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "cannot hold a task back for {}, the maximum is {}",
+                humantime::format_duration(delay),
+                humantime::format_duration(MAX_DELAY)
+            ),
         )
-        .await?
+        .into());
+    }
+    let delay = Duration::from_millis(delay.as_millis() as u64);
+    Ok(Some(delay).filter(|delay| !delay.is_zero()))
+}
+
+async fn new_task(
+    msg: String,
+    queue: &str,
+    job_id: &str,
+    properties: BasicProperties,
+    content_type: ContentType,
+    publisher: Publisher,
+) -> Result<()> {
+    let properties = properties.with_message_id(job_id.into());
+    let confirm = publisher
+        .publish_encoded("", queue, &content_type, &msg, properties)
         .await?;
 
-    println!("[x] Sent {}\nconfirm: {:?}", msg, confirm);
+    info!(%confirm, job = %job_id, "Sent {}", msg);
     Ok(())
 }
 
-async fn worker(channel: Channel) -> Result<()> {
-    channel.basic_qos(1, BasicQosOptions::default()).await?;
-
-    let consumer = channel
-        .basic_consume(
-            "task_queue",
-            "",
-            BasicConsumeOptions::default(),
-            FieldTable::default(),
+/// Logs the events of a job until it is over, and fails unless it succeeded.
+async fn wait(mut watch: JobWatch, job_id: &str, wait_timeout: Duration) -> Result<()> {
+    let events = async {
+        while let Some(event) = watch.next().await? {
+            info!("Job {}: {}", job_id, event.status);
+            if event.status.is_final() {
+                return Ok(Some(event.status));
+            }
+        }
+        Ok(None)
+    };
+    match timeout(wait_timeout, events).await {
+        Ok(Ok(Some(JobStatus::Succeeded { .. } | JobStatus::Skipped { .. }))) => Ok(()),
+        Ok(Ok(Some(status))) => Err(io::Error::other(format!("job {} {}", job_id, status)).into()),
+        Ok(Ok(None)) => {
+            Err(io::Error::other(format!("stopped receiving the status of job {}", job_id)).into())
+        }
+        Ok(Err(error)) => Err(error),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!(
+                "job {} still running after {}",
+                job_id,
+                humantime::format_duration(wait_timeout)
+            ),
         )
-        .await?;
+        .into()),
+    }
+}
+
+/// Prints what the results store knows about a job.
+async fn show_job(results: &Path, job_id: &str) -> Result<()> {
+    let record = ResultStore::open(results)?
+        .get(job_id)
+        .await?
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no result recorded for job {}", job_id),
+            )
+        })?;
+    let json = serde_json::to_string_pretty(&record).map_err(io::Error::other)?;
+    println!("{}", json);
+    Ok(())
+}
+
+async fn new_tasks(
+    tasks: Vec<String>,
+    queue: &str,
+    properties: BasicProperties,
+    publisher: Publisher,
+    opts: &Opts,
+) -> Result<()> {
+    for (i, batch) in tasks.chunks(opts.batch_size.max(1)).enumerate() {
+        let messages = batch
+            .iter()
+            .map(|task| Message::encode("", queue, &opts.content_type, task, properties.clone()))
+            .collect::<std::result::Result<_, _>>()?;
+        let report = publisher
+            .publish_batch(messages, opts.window, opts.max_retries)
+            .await?;
+        info!("Batch {}: {}", i + 1, report);
+    }
+
+    info!("Sent {} tasks", tasks.len());
+    Ok(())
+}
 
-    let mut it = consumer.into_iter();
-    println!(" [*] Waiting for messages. To exit press CTRL+C");
-    while let Some(delivery) = it.next() {
-        match delivery {
-            Ok((_ch, delivery)) => {
-                let msg = std::str::from_utf8(&delivery.data).expect("invalid string");
-                println!(" [x] Received {}", msg);
-                let sleep_duration = msg.chars().filter(|o| o == &'.').count();
-                sleep(Duration::from_secs(sleep_duration as u64)).await;
-                println!(" [x] Done");
-                delivery
-                    .ack(BasicAckOptions::default())
-                    .await
-                    .expect("basic_ack");
+fn received(delivery: &Delivery<Vec<u8>>) -> std::result::Result<String, Failure> {
+    let msg = delivery
+        .decode::<String>()
+        .map_err(|error| Failure::Permanent(error.to_string()))?;
+    info!("Received {}", msg);
+    Ok(msg)
+}
+
+/// Simulates work by sleeping a second per `.` in the task, reporting progress
+/// after each one.
+struct Sleep;
+
+impl TaskHandler for Sleep {
+    fn handle<'a>(
+        &'a self,
+        delivery: &'a Delivery<Vec<u8>>,
+        status: &'a JobReporter,
+    ) -> BoxFuture<'a, std::result::Result<Value, Failure>> {
+        async move {
+            let msg = received(delivery)?;
+            let sleep_duration = msg.chars().filter(|o| o == &'.').count();
+            for second in 1..=sleep_duration {
+                sleep(Duration::from_secs(1)).await;
+                if second < sleep_duration {
+                    status.progress((second * 100 / sleep_duration) as u8).await;
+                }
             }
-            Err(error) => {
-                println!("Error caught in consumer: {}", error)
+            if msg.contains("fail") {
+                return Err(Failure::Transient("task asked to fail".to_string()));
             }
+            Ok(Value::Null)
         }
+        .boxed()
     }
+}
 
-    Ok(())
+/// Counts the words of the task, which must have some.
+struct WordCount;
+
+impl TaskHandler for WordCount {
+    fn handle<'a>(
+        &'a self,
+        delivery: &'a Delivery<Vec<u8>>,
+        _: &'a JobReporter,
+    ) -> BoxFuture<'a, std::result::Result<Value, Failure>> {
+        async move {
+            let msg = received(delivery)?;
+            match msg.split_whitespace().count() {
+                0 => Err(Failure::Permanent("no words to count".to_string())),
+                words => {
+                    info!("Counted {} words", words);
+                    Ok(json!({ "words": words }))
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Runs each task with the handler of its type on a slot of the pool, settles it
+/// according to the outcome and reports on its job along the way.
+#[derive(Debug, Clone)]
+struct Dispatcher {
+    tasks: Arc<TaskRegistry>,
+    pool: Pool,
+    policy: RetryPolicy,
+    status_exchange: String,
+    results: Option<ResultStore>,
+    /// Largest body a task may decompress to.
+    max_decompressed: usize,
+}
+
+impl Dispatcher {
+    /// Handles `delivery`, republishing it through `retries` when it fails and
+    /// reporting on its job to `reports`.
+    async fn dispatch(
+        &self,
+        retries: &Publisher,
+        reports: &JobReports,
+        delivery: Delivery<Vec<u8>>,
+    ) -> Result<()> {
+        let tasks = self.tasks.clone();
+        let policy = self.policy.clone();
+        let retries = retries.clone();
+        let status = reports.reporter(&delivery.properties);
+        self.pool
+            .run(async move {
+                let attempt = RetryPolicy::retry_count(&delivery.properties) + 1;
+                status.report(JobStatus::Started { attempt }).await;
+                match tasks.handle(&delivery, &status).await {
+                    Ok(result) => {
+                        info!("Done");
+                        delivery.ack(BasicAckOptions::default()).await?;
+                        status.report(JobStatus::Succeeded { result }).await;
+                        Ok(())
+                    }
+                    Err(failure) => {
+                        let action = policy.fail(&retries, &delivery, &failure).await?;
+                        warn!("Failed ({}): {}", failure, action);
+                        let retrying = action != FailureAction::Parked;
+                        let error = failure.to_string();
+                        status.report(JobStatus::Failed { error, retrying }).await;
+                        Ok(())
+                    }
+                }
+            })
+            .await
+    }
+}
+
+async fn worker(
+    channel: Channel,
+    dispatcher: Dispatcher,
+    shutdown: Shutdown,
+    keyring: Option<Arc<Keyring>>,
+    metrics: Option<Metrics>,
+    health: Option<Health>,
+    dedup: Option<Dedup>,
+) -> Result<()> {
+    let prefetch = dispatcher.pool.size() as u16;
+    // Status events find no queue bound when nobody waits on their job
+    let publisher = Publisher::new(channel.clone(), false)
+        .await?
+        .with_mandatory(false)
+        .with_metrics(metrics.clone());
+    // A task is only let go once the broker confirmed its retry
+    let retries = Publisher::new(channel.clone(), true)
+        .await?
+        .with_keyring(keyring.clone())
+        .with_delivery_routing_key(Some(dispatcher.policy.queue.clone()))
+        .with_metrics(metrics.clone());
+    let reports = JobReports::new(publisher, &dispatcher.status_exchange)
+        .with_store(dispatcher.results.clone());
+    let consumer = Consumer::<Vec<u8>>::start(&channel, &dispatcher.policy.queue, prefetch)
+        .await?
+        .with_shutdown(shutdown)
+        .with_keyring(keyring)
+        .with_max_decompressed(dispatcher.max_decompressed)
+        .with_metrics(metrics)
+        .with_health(health)
+        .with_dedup(dedup)
+        .with_reports(Some(reports.clone()));
+
+    info!("Waiting for messages. To exit press CTRL+C");
+    consumer
+        .for_each_concurrent(|delivery| dispatcher.dispatch(&retries, &reports, delivery))
+        .await
+}
+
+/// Declares the work queue with its retry layout, and the status exchange.
+async fn declare_queue(
+    channel: Channel,
+    queue: QueueSpec,
+    status: ExchangeSpec,
+    policy: RetryPolicy,
+) -> Result<()> {
+    status.declare(&channel).await?;
+    policy
+        .declare_with_arguments(&channel, queue.options(), queue.arguments())
+        .await
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let opts = Opts::parse();
-    let addr = format!("amqp://{}:{}/%2f", opts.addr, opts.port);
-    let conn = Connection::connect(&addr, ConnectionProperties::default().with_tokio()).await?;
-    let channel = conn.create_channel().await?;
-
-    let _queue = channel
-        .queue_declare(
-            "task_queue",
-            QueueDeclareOptions {
-                durable: true,
-                ..Default::default()
-            },
-            FieldTable::default(),
-        )
-        .await?;
+    let _telemetry = opts.telemetry.init(env!("CARGO_BIN_NAME"))?;
+    if let (Some(results), Some(job_id)) = (&opts.results, &opts.job) {
+        return show_job(results, job_id).await;
+    }
+    let keyring = opts.envelope.load()?;
+    let topology = opts.topology.load()?;
+    let queue = topology.queue("task_queue")?.clone();
+    let status = topology.exchange("task_status")?.clone();
+    let policy = RetryPolicy::new(
+        &queue.name,
+        opts.retry_delays
+            .iter()
+            .map(|secs| Duration::from_secs(*secs))
+            .collect(),
+    );
 
     if opts.worker {
-        worker(channel).await?;
+        let metrics = opts.metrics.start(env!("CARGO_BIN_NAME"))?;
+        let health = opts.health.start()?;
+        let dedup = opts.dedup.open()?;
+        let shutdown = opts.shutdown.listen();
+        let tasks = TaskRegistry::new()
+            .register("sleep", Sleep)
+            .register("wordcount", WordCount)
+            .with_default("sleep");
+        let pool = Pool::new(opts.concurrency.max(1) as usize).with_metrics(metrics.clone());
+        let results = match &opts.results {
+            Some(path) => Some(ResultStore::open(path)?),
+            None => None,
+        };
+        let dispatcher = Dispatcher {
+            tasks: Arc::new(tasks),
+            pool: pool.clone(),
+            policy: policy.clone(),
+            status_exchange: status.name.clone(),
+            results,
+            max_decompressed: opts.compression.max_decompressed,
+        };
+        let reported = pool.clone();
+        let interval = Duration::from_secs(opts.utilisation_interval.max(1));
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval_at(Instant::now() + interval, interval);
+            loop {
+                ticks.tick().await;
+                info!("Slot utilisation: {}", reported.utilisation());
+            }
+        });
+        let declared = policy.clone();
+        Supervisor::new(opts.conn)
+            .with_shutdown(shutdown.clone())
+            .with_health(health.clone())
+            .declare(move |channel| {
+                declare_queue(channel, queue.clone(), status.clone(), declared.clone())
+            })
+            .run(|channel| {
+                worker(
+                    channel,
+                    dispatcher.clone(),
+                    shutdown.clone(),
+                    keyring.clone(),
+                    metrics.clone(),
+                    health.clone(),
+                    dedup.clone(),
+                )
+            })
+            .await?;
+        info!("Slot utilisation: {}", pool.utilisation());
     } else {
-        new_task(opts.msg, channel).await?;
+        let metrics = opts.metrics.start(env!("CARGO_BIN_NAME"))?;
+        let conn = opts.conn.connect().await?;
+        let channel = conn.create_channel().await?;
+        declare_queue(channel.clone(), queue.clone(), status.clone(), policy).await?;
+        let delay = delay(&opts)?;
+        let routing_key = match delay {
+            Some(delay) => {
+                let delayed = DelayQueues::new(&queue.name)
+                    .declare(&channel, delay)
+                    .await?;
+                info!(
+                    "Delaying by {} through {}",
+                    humantime::format_duration(delay),
+                    delayed
+                );
+                delayed
+            }
+            None => queue.name.clone(),
+        };
+        // Delayed tasks are sealed for the work queue they end up in
+        let delivered_as = delay.map(|_| queue.name.clone());
+        let properties = task_properties(opts.kind.as_deref(), delay);
+        let tasks = match (&opts.from_file, opts.count) {
+            (Some(path), _) => Some(
+                std::fs::read_to_string(path)?
+                    .lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .map(String::from)
+                    .collect::<Vec<_>>(),
+            ),
+            (None, Some(count)) => Some(
+                (1..=count)
+                    .map(|i| format!("{} #{}", opts.msg, i))
+                    .collect(),
+            ),
+            (None, None) => None,
+        };
+        match tasks {
+            Some(tasks) => {
+                // Batches rely on confirms to keep the window of in-flight messages
+                let publisher = Publisher::new(channel, true)
+                    .await?
+                    .with_compression(opts.compression.compression())
+                    .with_keyring(keyring)
+                    .with_delivery_routing_key(delivered_as)
+                    .with_metrics(metrics);
+                new_tasks(tasks, &routing_key, properties, publisher, &opts).await?;
+            }
+            None => {
+                let job_id = Uuid::new_v4().to_string();
+                // Bound before publishing, so even the first events reach it
+                let watch = if opts.wait {
+                    Some(JobWatch::start(&channel, &status.name, &job_id).await?)
+                } else {
+                    None
+                };
+                let publisher = Publisher::new(channel, opts.confirm)
+                    .await?
+                    .with_compression(opts.compression.compression())
+                    .with_keyring(keyring)
+                    .with_delivery_routing_key(delivered_as)
+                    .with_metrics(metrics);
+                new_task(
+                    opts.msg,
+                    &routing_key,
+                    &job_id,
+                    properties,
+                    opts.content_type,
+                    publisher,
+                )
+                .await?;
+                if let Some(watch) = watch {
+                    wait(watch, &job_id, Duration::from_secs(opts.wait_timeout)).await?;
+                }
+            }
+        }
     }
 
     Ok(())
@@ -1,14 +1,16 @@
 use clap::{AppSettings, Clap};
 use lapin::{
-    message::DeliveryResult,
-    options::{
-        BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, ExchangeDeclareOptions,
-        QueueBindOptions, QueueDeclareOptions,
-    },
+    options::{BasicAckOptions, QueueBindOptions, QueueDeclareOptions},
     types::FieldTable,
-    BasicProperties, Channel, Connection, ConnectionProperties, ExchangeKind, Result,
+    BasicProperties, Channel, Result,
+};
+use std::sync::Arc;
+use tracing::{info, warn};
+use tutorial_rs::{
+    topology::ExchangeSpec, CompressionOpts, ConnectionOpts, Consumer, ContentType, Dedup,
+    DedupOpts, EnvelopeOpts, Health, HealthOpts, Keyring, Metrics, MetricsOpts, Publisher,
+    Shutdown, ShutdownOpts, Supervisor, TelemetryOpts, TopologyOpts,
 };
-use tokio_amqp::LapinTokioExt;
 
 /// In this tutorial we use the "publish/subscribe" pattern to broadcast logs to all
 /// available receivers.
@@ -17,34 +19,73 @@ use tokio_amqp::LapinTokioExt;
 struct Opts {
     #[clap(default_value = "info: Hello World!")]
     msg: String,
-    #[clap(long, default_value = "127.0.0.1")]
-    addr: String,
-    #[clap(long, default_value = "5672")]
-    port: u32,
+    #[clap(flatten)]
+    conn: ConnectionOpts,
+    #[clap(flatten)]
+    telemetry: TelemetryOpts,
+    #[clap(flatten)]
+    shutdown: ShutdownOpts,
+    #[clap(flatten)]
+    topology: TopologyOpts,
+    #[clap(flatten)]
+    envelope: EnvelopeOpts,
+    #[clap(flatten)]
+    metrics: MetricsOpts,
+    #[clap(flatten)]
+    health: HealthOpts,
+    #[clap(flatten)]
+    dedup: DedupOpts,
+    #[clap(flatten)]
+    compression: CompressionOpts,
+    /// Enable publisher confirms and report whether the broker acked, nacked or
+    /// returned the message as unroutable
+    #[clap(long)]
+    confirm: bool,
+    /// Encoding of the message body: `raw`, `json`, `msgpack` or `cbor`
+    #[clap(long, default_value = "raw")]
+    content_type: ContentType,
     /// Specify if the mode is `receive` or `send` if false
     #[clap(short, long)]
     receiver: bool,
 }
 
-async fn emit_log(msg: String, channel: Channel) -> Result<()> {
-    let payload = msg.as_bytes().to_vec();
-
-    let confirm = channel
-        .basic_publish(
-            "logs",
+async fn emit_log(
+    msg: String,
+    exchange: &str,
+    content_type: ContentType,
+    publisher: Publisher,
+) -> Result<()> {
+    let confirm = publisher
+        .publish_encoded(
+            exchange,
             "",
-            BasicPublishOptions::default(),
-            payload,
+            &content_type,
+            &msg,
             BasicProperties::default(),
         )
-        .await?
         .await?;
 
-    println!("[x] Sent {}\nconfirm: {:?}", msg, confirm);
+    info!(%confirm, "Sent {}", msg);
     Ok(())
 }
 
-async fn receive_logs(channel: Channel) -> Result<()> {
+/// How the receiver gets at message bodies: opened with `keyring`, and decompressed
+/// to at most `max_decompressed` bytes.
+#[derive(Debug, Clone)]
+struct Decoding {
+    keyring: Option<Arc<Keyring>>,
+    max_decompressed: usize,
+}
+
+async fn receive_logs(
+    channel: Channel,
+    exchange: String,
+    shutdown: Shutdown,
+    decoding: Decoding,
+    metrics: Option<Metrics>,
+    health: Option<Health>,
+    dedup: Option<Dedup>,
+) -> Result<()> {
     let result = channel
         .queue_declare(
             "",
@@ -60,67 +101,82 @@ async fn receive_logs(channel: Channel) -> Result<()> {
     channel
         .queue_bind(
             queue_name,
-            "logs",
+            &exchange,
             "",
             QueueBindOptions::default(),
             FieldTable::default(),
         )
         .await?;
 
-    let consumer = channel
-        .basic_consume(
-            queue_name,
-            "",
-            BasicConsumeOptions::default(),
-            FieldTable::default(),
-        )
-        .await?;
+    let consumer = Consumer::<Vec<u8>>::start(&channel, queue_name, 0)
+        .await?
+        .with_shutdown(shutdown)
+        .with_keyring(decoding.keyring)
+        .with_max_decompressed(decoding.max_decompressed)
+        .with_metrics(metrics)
+        .with_health(health)
+        .with_dedup(dedup);
 
-    consumer.set_delegate(|delivery: DeliveryResult| async move {
-        match delivery {
-            Ok(delivery) => {
-                if let Some((_ch, delivery)) = delivery {
-                    let msg = std::str::from_utf8(&delivery.data).expect("invalid string");
-                    println!(" [x] {}", msg);
-                    delivery
-                        .ack(BasicAckOptions::default())
-                        .await
-                        .expect("basic_ack");
-                }
+    info!("Waiting for messages. To exit press CTRL+C");
+    consumer
+        .for_each_concurrent(|delivery| async move {
+            match delivery.decode::<String>() {
+                Ok(msg) => info!("Received {}", msg),
+                Err(error) => warn!("Dropping message: {}", error),
             }
-            Err(error) => {
-                println!("Error caught in consumer: {}", error)
-            }
-        };
-    })?;
-
-    let mut it = consumer.into_iter();
-    println!(" [*] Waiting for messages. To exit press CTRL+C");
-    while let Some(_delivery) = it.next() {}
+            delivery.ack(BasicAckOptions::default()).await
+        })
+        .await
+}
 
-    Ok(())
+async fn declare_exchange(channel: Channel, exchange: ExchangeSpec) -> Result<()> {
+    exchange.declare(&channel).await
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let opts = Opts::parse();
-    let addr = format!("amqp://{}:{}/%2f", opts.addr, opts.port);
-    let conn = Connection::connect(&addr, ConnectionProperties::default().with_tokio()).await?;
-    let channel = conn.create_channel().await?;
-
-    channel
-        .exchange_declare(
-            "logs",
-            ExchangeKind::Fanout,
-            ExchangeDeclareOptions::default(),
-            FieldTable::default(),
-        )
-        .await?;
+    let _telemetry = opts.telemetry.init(env!("CARGO_BIN_NAME"))?;
+    let keyring = opts.envelope.load()?;
+    let exchange = opts.topology.load()?.exchange("logs")?.clone();
 
     if opts.receiver {
-        receive_logs(channel).await?;
+        let metrics = opts.metrics.start(env!("CARGO_BIN_NAME"))?;
+        let health = opts.health.start()?;
+        let dedup = opts.dedup.open()?;
+        let shutdown = opts.shutdown.listen();
+        let decoding = Decoding {
+            keyring: keyring.clone(),
+            max_decompressed: opts.compression.max_decompressed,
+        };
+        let declared = exchange.clone();
+        Supervisor::new(opts.conn)
+            .with_shutdown(shutdown.clone())
+            .with_health(health.clone())
+            .declare(move |channel| declare_exchange(channel, declared.clone()))
+            .run(|channel| {
+                receive_logs(
+                    channel,
+                    exchange.name.clone(),
+                    shutdown.clone(),
+                    decoding.clone(),
+                    metrics.clone(),
+                    health.clone(),
+                    dedup.clone(),
+                )
+            })
+            .await?;
     } else {
-        emit_log(opts.msg, channel).await?;
+        let metrics = opts.metrics.start(env!("CARGO_BIN_NAME"))?;
+        let conn = opts.conn.connect().await?;
+        let channel = conn.create_channel().await?;
+        declare_exchange(channel.clone(), exchange.clone()).await?;
+        let publisher = Publisher::new(channel, opts.confirm)
+            .await?
+            .with_compression(opts.compression.compression())
+            .with_keyring(keyring)
+            .with_metrics(metrics);
+        emit_log(opts.msg, &exchange.name, opts.content_type, publisher).await?;
     }
 
     Ok(())
@@ -1,186 +1,237 @@
 use clap::{AppSettings, Clap};
-use lapin::{
-    options::{
-        BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, BasicQosOptions,
-        QueueDeclareOptions,
-    },
-    types::FieldTable,
-    BasicProperties, Channel, Connection, ConnectionProperties, Result,
+use futures::future::{self, BoxFuture, FutureExt};
+use lapin::{Channel, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{field, info, info_span, warn, Instrument};
+use tutorial_rs::{
+    rpc, topology::QueueSpec, ConnectionOpts, Consumer, ContentType, Dedup, DedupOpts,
+    EnvelopeOpts, Health, HealthOpts, Keyring, Metrics, MetricsOpts, RemoteError, ReplyMode,
+    RpcClient, RpcServer, Shutdown, ShutdownOpts, Supervisor, TelemetryOpts, TopologyOpts,
+    TraceContext,
 };
-use tokio_amqp::LapinTokioExt;
-use uuid::Uuid;
-
-const QUEUE_NAME: &str = "rpc_queue";
-const ROUTING_KEY: &str = "rpc_queue";
 
-/// RPC server/client for calculating fib(n)
+/// RPC server/client for calculating fib(n) or n!
 #[derive(Debug, Clap)]
 #[clap(name = "RabbitMQ - Tutorial 06", setting = AppSettings::ColoredHelp)]
 struct Opts {
-    /// To calculate fib(n)
+    /// Numbers to call the procedure with, all requests are sent concurrently
     #[clap(default_value = "30")]
-    n: i32,
-    #[clap(long, default_value = "127.0.0.1")]
-    addr: String,
-    #[clap(long, default_value = "5672")]
-    port: u32,
+    n: Vec<u64>,
+    /// Procedure to call: `fib` or `factorial`
+    #[clap(short, long, default_value = "fib")]
+    procedure: Procedure,
+    /// Seconds to wait for each reply
+    #[clap(long, default_value = "10")]
+    timeout: u64,
+    /// Receive replies on an exclusive queue instead of direct reply-to
+    #[clap(long)]
+    reply_queue: bool,
+    /// Encoding of the requests: `json`, `msgpack` or `cbor`
+    #[clap(long, default_value = "json")]
+    content_type: ContentType,
+    #[clap(flatten)]
+    conn: ConnectionOpts,
+    #[clap(flatten)]
+    telemetry: TelemetryOpts,
+    #[clap(flatten)]
+    shutdown: ShutdownOpts,
+    #[clap(flatten)]
+    topology: TopologyOpts,
+    #[clap(flatten)]
+    envelope: EnvelopeOpts,
+    #[clap(flatten)]
+    metrics: MetricsOpts,
+    #[clap(flatten)]
+    health: HealthOpts,
+    #[clap(flatten)]
+    dedup: DedupOpts,
     /// Specify if the mode is `receive` or `send` if false
     #[clap(short, long)]
     server: bool,
 }
 
-async fn rpc_client(n: i32, channel: Channel) -> Result<()> {
-    let payload = n.to_string().as_bytes().to_vec();
-    println!(" [x] Requesting fib({})", n);
-
-    let result = channel
-        .queue_declare(
-            "",
-            QueueDeclareOptions {
-                exclusive: true,
-                ..Default::default()
-            },
-            FieldTable::default(),
-        )
-        .await?;
-
-    let queue_name = result.name().as_str();
-    let correlation_id = Uuid::new_v4().to_string();
-
-    channel
-        .basic_publish(
-            "",
-            ROUTING_KEY,
-            BasicPublishOptions::default(),
-            payload,
-            BasicProperties::default()
-                .with_reply_to(queue_name.into())
-                .with_correlation_id(correlation_id.into()),
-        )
-        .await?
-        .await?;
-
-    let consumer = channel
-        .basic_consume(
-            queue_name,
-            "",
-            BasicConsumeOptions::default(),
-            FieldTable::default(),
-        )
-        .await?;
-
-    let mut it = consumer.into_iter();
-    while let Some(delivery) = it.next() {
-        match delivery {
-            Ok((_ch, delivery)) => {
-                let res: i32 = std::str::from_utf8(&delivery.data)
-                    .expect("invalid input")
-                    .parse()
-                    .expect("invalid input");
-                println!(" [.] Got {}", res);
-                break;
-            }
-            Err(error) => {
-                println!("Error caught in consumer: {}", error)
+#[derive(Debug, Clone, Copy)]
+enum Procedure {
+    Fib,
+    Factorial,
+}
+
+impl FromStr for Procedure {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "fib" => Ok(Procedure::Fib),
+            "factorial" => Ok(Procedure::Factorial),
+            s => Err(format!("Invalid procedure: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Request {
+    Fib(u64),
+    Factorial(u64),
+}
+
+impl Request {
+    fn new(procedure: Procedure, n: u64) -> Self {
+        match procedure {
+            Procedure::Fib => Request::Fib(n),
+            Procedure::Factorial => Request::Factorial(n),
+        }
+    }
+}
+
+async fn rpc_client(
+    numbers: Vec<u64>,
+    procedure: Procedure,
+    client: RpcClient<Request, u64>,
+) -> Result<()> {
+    let start = Instant::now();
+
+    // Each request starts a trace of its own, which the server continues
+    let calls = numbers.into_iter().map(|n| {
+        let request = Request::new(procedure, n);
+        let span = info_span!("request", trace_id = field::Empty, span_id = field::Empty);
+        let context = TraceContext::start(&span, None);
+        let client = &client;
+        let call = async move {
+            info!("Requesting {:?}", request);
+            match client.call(&request).await {
+                Ok(res) => info!("{:?} = {}", request, res),
+                Err(error) => warn!("{:?} failed: {}", request, error),
             }
         };
-    }
+        context.scope(call).instrument(span)
+    });
+    future::join_all(calls).await;
+    info!(
+        "Done in {:?} replying through `{}`",
+        start.elapsed(),
+        client.reply_to()
+    );
 
     Ok(())
 }
 
-fn fib(n: i32) -> i32 {
-    match n {
-        0 => 0,
-        1 => 1,
-        n => fib(n - 1) + fib(n - 2),
+/// Largest `n` whose fib(n) fits in a u64.
+const MAX_FIB: u64 = 93;
+
+fn fib(n: u64) -> u64 {
+    if n == 0 {
+        return 0;
+    }
+    // Stops at fib(n) itself, so fib(MAX_FIB) does not overflow computing the next one
+    let (mut previous, mut current) = (0u64, 1u64);
+    for _ in 1..n {
+        let next = previous + current;
+        previous = current;
+        current = next;
     }
+    current
 }
 
-async fn rpc_server(channel: Channel) -> Result<()> {
-    channel
-        .queue_declare(
-            QUEUE_NAME,
-            QueueDeclareOptions::default(),
-            FieldTable::default(),
-        )
-        .await?;
-
-    channel.basic_qos(1, BasicQosOptions::default()).await?;
-
-    let consumer = channel
-        .basic_consume(
-            QUEUE_NAME,
-            "",
-            BasicConsumeOptions::default(),
-            FieldTable::default(),
-        )
-        .await?;
-
-    println!(" [*] Awaiting RPC requests");
-
-    let mut it = consumer.into_iter();
-    while let Some(delivery) = it.next() {
-        match delivery {
-            Ok((ch, delivery)) => {
-                if delivery.data.is_empty() {
-                    return Ok(());
-                }
-                let n = std::str::from_utf8(&delivery.data)
-                    .expect("invalid input")
-                    .parse()
-                    .expect("invalid input");
-                println!(" [] fib({})", n);
-                let res = fib(n);
-                let payload = res.to_string().as_bytes().to_vec();
-
-                let reply_to = delivery
-                    .properties
-                    .reply_to()
-                    .as_ref()
-                    .expect("Error reading `reply_to`");
-
-                let correlation_id = delivery
-                    .properties
-                    .correlation_id()
-                    .clone()
-                    .expect("Error reading `correlation_id`");
-
-                ch.basic_publish(
-                    "",
-                    reply_to.as_str(),
-                    BasicPublishOptions::default(),
-                    payload,
-                    BasicProperties::default().with_correlation_id(correlation_id),
-                )
-                .await?
-                .await?;
+fn factorial(n: u64) -> Option<u64> {
+    (1..=n).try_fold(1u64, |acc, i| acc.checked_mul(i))
+}
 
-                delivery
-                    .ack(BasicAckOptions::default())
-                    .await
-                    .expect("basic_ack");
-            }
-            Err(error) => {
-                println!("Error caught in consumer: {}", error)
-            }
+struct MathServer;
+
+impl RpcServer for MathServer {
+    type Request = Request;
+    type Response = u64;
+
+    fn handle(&self, request: Request) -> BoxFuture<'_, std::result::Result<u64, RemoteError>> {
+        info!("Handling {:?}", request);
+        let res = match request {
+            Request::Fib(n) if n > MAX_FIB => Err(RemoteError::new(
+                "out_of_range",
+                format!("fib({}) does not fit in a u64", n),
+            )),
+            Request::Fib(n) => Ok(fib(n)),
+            Request::Factorial(n) => factorial(n).ok_or_else(|| {
+                RemoteError::new("out_of_range", format!("{}! does not fit in a u64", n))
+            }),
         };
+        future::ready(res).boxed()
     }
+}
 
-    Ok(())
+async fn rpc_server(
+    channel: Channel,
+    queue: String,
+    shutdown: Shutdown,
+    keyring: Option<Arc<Keyring>>,
+    metrics: Option<Metrics>,
+    health: Option<Health>,
+    dedup: Option<Dedup>,
+) -> Result<()> {
+    let consumer = Consumer::start(&channel, &queue, 1)
+        .await?
+        .with_shutdown(shutdown)
+        .with_keyring(keyring)
+        .with_metrics(metrics)
+        .with_health(health)
+        .with_dedup(dedup);
+
+    info!("Awaiting RPC requests");
+
+    rpc::serve(&MathServer, consumer).await
+}
+
+async fn declare_queue(channel: Channel, queue: QueueSpec) -> Result<()> {
+    queue.declare(&channel).await
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let opts = Opts::parse();
-    let addr = format!("amqp://{}:{}/%2f", opts.addr, opts.port);
-    let conn = Connection::connect(&addr, ConnectionProperties::default().with_tokio()).await?;
-    let channel = conn.create_channel().await?;
+    let _telemetry = opts.telemetry.init(env!("CARGO_BIN_NAME"))?;
+    let keyring = opts.envelope.load()?;
+    let queue = opts.topology.load()?.queue("rpc_queue")?.clone();
 
     if opts.server {
-        rpc_server(channel).await?;
+        let metrics = opts.metrics.start(env!("CARGO_BIN_NAME"))?;
+        let health = opts.health.start()?;
+        let dedup = opts.dedup.open()?;
+        let shutdown = opts.shutdown.listen();
+        let declared = queue.clone();
+        Supervisor::new(opts.conn)
+            .with_shutdown(shutdown.clone())
+            .with_health(health.clone())
+            .declare(move |channel| declare_queue(channel, declared.clone()))
+            .run(|channel| {
+                rpc_server(
+                    channel,
+                    queue.name.clone(),
+                    shutdown.clone(),
+                    keyring.clone(),
+                    metrics.clone(),
+                    health.clone(),
+                    dedup.clone(),
+                )
+            })
+            .await?;
     } else {
-        rpc_client(opts.n, channel).await?;
+        let conn = opts.conn.connect().await?;
+        let channel = conn.create_channel().await?;
+        let reply_mode = if opts.reply_queue {
+            ReplyMode::Queue
+        } else {
+            ReplyMode::Direct
+        };
+        let client = RpcClient::new(channel, &queue.name, reply_mode)
+            .await?
+            .with_timeout(Duration::from_secs(opts.timeout))
+            .with_content_type(opts.content_type)
+            .with_keyring(keyring);
+        rpc_client(opts.n, opts.procedure, client).await?;
     }
 
     Ok(())
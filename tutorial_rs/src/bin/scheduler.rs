@@ -0,0 +1,172 @@
+use chrono::Utc;
+use clap::{AppSettings, Clap};
+use lapin::{BasicProperties, Channel, Connection, Result};
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tokio::time::sleep;
+use tracing::{info, warn};
+use tutorial_rs::{
+    schedule::Job, supervisor::is_fatal, Compression, CompressionOpts, ConnectionOpts, ContentType,
+    DrainPolicy, EnvelopeOpts, Keyring, LeaderLock, Publisher, Schedule, Shutdown, ShutdownOpts,
+    Supervisor, TelemetryOpts, TopologyOpts,
+};
+
+/// Enqueues the jobs of a schedule file as tasks for the workers of tutorial 02, each
+/// on its cron schedule.
+///
+/// Several schedulers can run side by side: the one owning the exclusive
+/// `--lock-queue` fires the jobs, the others try to take it over every
+/// `--lock-retry` seconds. Runs that come due while no scheduler leads are skipped.
+#[derive(Debug, Clap)]
+#[clap(name = "RabbitMQ - Scheduler", setting = AppSettings::ColoredHelp)]
+struct Opts {
+    /// Schedule file (`.toml`, `.yaml` or `.yml`) listing the jobs
+    schedule: PathBuf,
+    #[clap(flatten)]
+    conn: ConnectionOpts,
+    #[clap(flatten)]
+    telemetry: TelemetryOpts,
+    #[clap(flatten)]
+    topology: TopologyOpts,
+    #[clap(flatten)]
+    envelope: EnvelopeOpts,
+    #[clap(flatten)]
+    compression: CompressionOpts,
+    /// Exclusive queue owned by the scheduler firing the jobs
+    #[clap(long, default_value = "scheduler.leader")]
+    lock_queue: String,
+    /// Seconds between attempts to take the lock while another scheduler holds it
+    #[clap(long, default_value = "5")]
+    lock_retry: u64,
+}
+
+/// Waits until the lock is ours and returns the channel holding it, or `None` once
+/// shutdown fires.
+async fn stand_by(
+    conn: &Connection,
+    lock: &LeaderLock,
+    retry: Duration,
+    shutdown: &Shutdown,
+) -> Result<Option<Channel>> {
+    let mut announced = false;
+    loop {
+        if let Some(channel) = lock.try_acquire(conn).await? {
+            return Ok(Some(channel));
+        }
+        if !announced {
+            info!("Standing by while another scheduler holds `{}`", lock.queue);
+            announced = true;
+        }
+        tokio::select! {
+            _ = sleep(retry) => {}
+            _ = shutdown.wait() => return Ok(None),
+        }
+    }
+}
+
+async fn enqueue(job: &Job, queue: &str, publisher: &Publisher) -> Result<()> {
+    let mut properties = BasicProperties::default().with_delivery_mode(2);
+    if let Some(kind) = &job.spec.kind {
+        properties = properties.with_kind(kind.as_str().into());
+    }
+    let confirm = publisher
+        .publish_encoded("", queue, &ContentType::Raw, &job.spec.task, properties)
+        .await?;
+    info!(job = %job.spec.name, %confirm, "Enqueued {}", job.spec.task);
+    Ok(())
+}
+
+/// Fires the jobs as they come due, until shutdown or the connection is lost. Tasks
+/// are compressed and sealed like those tutorial 02 sends.
+async fn lead(
+    schedule: &Schedule,
+    queue: &str,
+    channel: Channel,
+    shutdown: &Shutdown,
+    keyring: Option<Arc<Keyring>>,
+    compression: Option<Compression>,
+) -> Result<()> {
+    let publisher = Publisher::new(channel, true)
+        .await?
+        .with_compression(compression)
+        .with_keyring(keyring);
+    let mut last = Utc::now();
+    loop {
+        let (at, jobs) = match schedule.next_after(last) {
+            Some(next) => next,
+            None => {
+                info!("No job is due anymore");
+                shutdown.wait().await;
+                return Ok(());
+            }
+        };
+        let wait = (at - Utc::now()).to_std().unwrap_or_default();
+        tokio::select! {
+            _ = sleep(wait) => {}
+            _ = shutdown.wait() => return Ok(()),
+        }
+        for job in jobs {
+            enqueue(job, queue, &publisher).await?;
+        }
+        last = at;
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let opts = Opts::parse();
+    let _telemetry = opts.telemetry.init(env!("CARGO_BIN_NAME"))?;
+    let schedule = Schedule::load(&opts.schedule)?;
+    let keyring = opts.envelope.load()?;
+    let compression = opts.compression.compression();
+    let queue = opts.topology.load()?.queue("task_queue")?.name.clone();
+    let lock = LeaderLock::new(&opts.lock_queue);
+    let retry = Duration::from_secs(opts.lock_retry.max(1));
+    // Nothing is in flight between runs, so there is nothing to drain
+    let shutdown = ShutdownOpts {
+        drain_timeout: 0,
+        drain_policy: DrainPolicy::Finish,
+    }
+    .listen();
+    info!(
+        "Loaded {} jobs from {}",
+        schedule.jobs.len(),
+        opts.schedule.display()
+    );
+
+    let supervisor = Supervisor::new(opts.conn).with_shutdown(shutdown.clone());
+    while let Some((conn, channel)) = supervisor.connect().await? {
+        let led = match stand_by(&conn, &lock, retry, &shutdown).await {
+            Ok(Some(leading)) => {
+                info!("Leading, the jobs are fired from here");
+                lead(
+                    &schedule,
+                    &queue,
+                    leading,
+                    &shutdown,
+                    keyring.clone(),
+                    compression,
+                )
+                .await
+            }
+            Ok(None) => Ok(()),
+            Err(error) => Err(error),
+        };
+        if shutdown.is_triggered() {
+            if conn.status().connected() {
+                channel.close(200, "Shutting down").await?;
+                conn.close(200, "Shutting down").await?;
+            }
+            break;
+        }
+        match led {
+            Err(error) if is_fatal(&error) => return Err(error),
+            Err(error) => warn!("Scheduler failed: {}, reconnecting", error),
+            Ok(()) => {}
+        }
+        if conn.status().connected() {
+            let _ = conn.close(200, "Reconnecting").await;
+        }
+    }
+
+    Ok(())
+}
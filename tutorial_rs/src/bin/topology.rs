@@ -0,0 +1,223 @@
+use clap::{AppSettings, Clap};
+use lapin::{
+    options::{ExchangeDeclareOptions, QueueDeclareOptions},
+    protocol::AMQPSoftError,
+    types::FieldTable,
+    Channel, Connection, Error, Result,
+};
+use std::future::Future;
+use tracing::info;
+use tutorial_rs::{topology::Format, ConnectionOpts, TelemetryOpts, Topology, TopologyOpts};
+
+/// Declares the exchanges, queues and bindings of a topology file, by default the
+/// one the tutorials use.
+#[derive(Debug, Clap)]
+#[clap(name = "RabbitMQ - Topology", setting = AppSettings::ColoredHelp)]
+struct Opts {
+    #[clap(flatten)]
+    conn: ConnectionOpts,
+    #[clap(flatten)]
+    telemetry: TelemetryOpts,
+    #[clap(flatten)]
+    topology: TopologyOpts,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Clap)]
+enum Command {
+    /// Declare everything in the topology, leaving what already exists untouched
+    Apply,
+    /// Show which exchanges and queues `apply` would create, and which exist with
+    /// another type or other arguments
+    Diff,
+    /// Print the topology, e.g. to start a file from the built-in one
+    Export {
+        /// Output format: `toml` or `yaml`
+        #[clap(long, default_value = "toml")]
+        format: Format,
+    },
+}
+
+async fn apply(topology: &Topology, connection: &Connection) -> Result<()> {
+    let channel = connection.create_channel().await?;
+    for exchange in &topology.exchanges {
+        exchange.declare(&channel).await?;
+        info!("Declared exchange `{}` ({})", exchange.name, exchange.kind);
+    }
+    for queue in &topology.queues {
+        queue.declare(&channel).await?;
+        info!("Declared queue `{}`", queue.name);
+    }
+    for binding in &topology.bindings {
+        binding.declare(&channel).await?;
+        info!(
+            "Bound `{}` to `{}` with \"{}\"",
+            binding.queue, binding.exchange, binding.routing_key
+        );
+    }
+    channel.close(200, "OK").await
+}
+
+/// How an entity on the broker compares to its declaration in the topology.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Missing,
+    Declared,
+    /// Exists with another type or other arguments.
+    Differs,
+}
+
+impl State {
+    fn marker(self) -> &'static str {
+        match self {
+            State::Missing => "+",
+            State::Declared => "=",
+            State::Differs => "~",
+        }
+    }
+}
+
+/// Runs a declaration on a channel of its own, as a failed one closes the channel.
+/// Returns whether it succeeded, `false` when it failed with `expected`.
+async fn succeeds<F, Fut>(
+    connection: &Connection,
+    expected: AMQPSoftError,
+    declare: F,
+) -> Result<bool>
+where
+    F: FnOnce(Channel) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let channel = connection.create_channel().await?;
+    match declare(channel.clone()).await {
+        Ok(()) => {
+            channel.close(200, "OK").await?;
+            Ok(true)
+        }
+        Err(Error::ProtocolError(error)) if error.get_id() == expected.get_id() => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+/// Checks that an entity exists with a passive declaration, then redeclares it as
+/// the topology would: the broker refuses with PRECONDITION_FAILED when its type
+/// or arguments differ, and leaves it untouched otherwise.
+async fn state<P, PFut, D, DFut>(connection: &Connection, passive: P, declare: D) -> Result<State>
+where
+    P: FnOnce(Channel) -> PFut,
+    PFut: Future<Output = Result<()>>,
+    D: FnOnce(Channel) -> DFut,
+    DFut: Future<Output = Result<()>>,
+{
+    if !succeeds(connection, AMQPSoftError::NOTFOUND, passive).await? {
+        return Ok(State::Missing);
+    }
+    if !succeeds(connection, AMQPSoftError::PRECONDITIONFAILED, declare).await? {
+        return Ok(State::Differs);
+    }
+    Ok(State::Declared)
+}
+
+async fn diff(topology: &Topology, connection: &Connection) -> Result<()> {
+    let (mut missing, mut differing) = (0, 0);
+    let mut count = |state| match state {
+        State::Missing => missing += 1,
+        State::Differs => differing += 1,
+        State::Declared => {}
+    };
+    for exchange in &topology.exchanges {
+        let state = state(
+            connection,
+            |channel| async move {
+                channel
+                    .exchange_declare(
+                        &exchange.name,
+                        exchange.kind.into(),
+                        ExchangeDeclareOptions {
+                            passive: true,
+                            ..Default::default()
+                        },
+                        FieldTable::default(),
+                    )
+                    .await
+            },
+            |channel| async move { exchange.declare(&channel).await },
+        )
+        .await?;
+        count(state);
+        println!(
+            " [{}] exchange `{}` ({}){}",
+            state.marker(),
+            exchange.name,
+            exchange.kind,
+            if state == State::Differs {
+                " differs"
+            } else {
+                ""
+            }
+        );
+    }
+    for queue in &topology.queues {
+        let state = state(
+            connection,
+            |channel| async move {
+                channel
+                    .queue_declare(
+                        &queue.name,
+                        QueueDeclareOptions {
+                            passive: true,
+                            ..Default::default()
+                        },
+                        FieldTable::default(),
+                    )
+                    .await?;
+                Ok(())
+            },
+            |channel| async move { queue.declare(&channel).await },
+        )
+        .await?;
+        count(state);
+        println!(
+            " [{}] queue `{}`{}",
+            state.marker(),
+            queue.name,
+            if state == State::Differs {
+                " differs"
+            } else {
+                ""
+            }
+        );
+    }
+    // AMQP has no way to list bindings, and rebinding is harmless
+    println!(
+        " [*] {} to create, {} differing, {} bindings to (re)apply",
+        missing,
+        differing,
+        topology.bindings.len()
+    );
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let opts = Opts::parse();
+    let _telemetry = opts.telemetry.init(env!("CARGO_BIN_NAME"))?;
+    let topology = opts.topology.load()?;
+
+    match opts.command {
+        Command::Export { format } => print!("{}", topology.render(format)?),
+        Command::Apply => {
+            let conn = opts.conn.connect().await?;
+            apply(&topology, &conn).await?;
+            conn.close(200, "OK").await?;
+        }
+        Command::Diff => {
+            let conn = opts.conn.connect().await?;
+            diff(&topology, &conn).await?;
+            conn.close(200, "OK").await?;
+        }
+    }
+
+    Ok(())
+}
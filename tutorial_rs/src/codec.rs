@@ -0,0 +1,310 @@
+//! Message body encodings, negotiated through `content_type`.
+//!
+//! A [`Codec`] turns serde values into message bodies and back, and tags what it
+//! publishes with its `content_type` and `content_encoding`. On the consuming side
+//! [`ContentType::of`] picks the codec from the delivery's properties, so a queue
+//! can be fed JSON by one producer and MessagePack by another. Messages without a
+//! content type are treated as [`Raw`], which is what the tutorials used to send.
+//! [`ContentType::decode_message`] also undoes any compression the message's
+//! `content_encoding` announces.
+
+use crate::compression::{self, CompressionError};
+use lapin::BasicProperties;
+use serde::{
+    de::{
+        value::{BytesDeserializer, Error as ValueError, SeqDeserializer},
+        DeserializeOwned,
+    },
+    Serialize,
+};
+use std::{convert::TryFrom, fmt, io, str::FromStr};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodecError {
+    /// No codec handles this content type.
+    Unsupported(String),
+    Encode {
+        content_type: &'static str,
+        reason: String,
+    },
+    Decode {
+        content_type: &'static str,
+        reason: String,
+    },
+    /// The body could not be decompressed.
+    Compression(CompressionError),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Unsupported(content_type) => {
+                write!(f, "unsupported content type `{}`", content_type)
+            }
+            CodecError::Encode {
+                content_type,
+                reason,
+            } => write!(f, "cannot encode as {}: {}", content_type, reason),
+            CodecError::Decode {
+                content_type,
+                reason,
+            } => write!(f, "cannot decode {}: {}", content_type, reason),
+            CodecError::Compression(error) => error.fmt(f),
+        }
+    }
+}
+
+impl CodecError {
+    fn encode(codec: &impl Codec, reason: impl fmt::Display) -> Self {
+        CodecError::Encode {
+            content_type: codec.content_type(),
+            reason: reason.to_string(),
+        }
+    }
+
+    fn decode(codec: &impl Codec, reason: impl fmt::Display) -> Self {
+        CodecError::Decode {
+            content_type: codec.content_type(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<CompressionError> for CodecError {
+    fn from(error: CompressionError) -> Self {
+        CodecError::Compression(error)
+    }
+}
+
+impl From<CodecError> for lapin::Error {
+    fn from(error: CodecError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error.to_string()).into()
+    }
+}
+
+/// Encodes values into message bodies and decodes them back.
+pub trait Codec {
+    /// MIME type published as `content_type`.
+    fn content_type(&self) -> &'static str;
+
+    /// Published as `content_encoding`: `utf-8` for text formats, `binary` otherwise.
+    fn content_encoding(&self) -> &'static str;
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError>;
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, CodecError>;
+
+    /// `properties` tagged with this codec's content type and encoding.
+    fn properties(&self, properties: BasicProperties) -> BasicProperties {
+        properties
+            .with_content_type(self.content_type().into())
+            .with_content_encoding(self.content_encoding().into())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    fn content_encoding(&self) -> &'static str {
+        "utf-8"
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        serde_json::to_vec(value).map_err(|error| CodecError::encode(self, error))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, CodecError> {
+        serde_json::from_slice(data).map_err(|error| CodecError::decode(self, error))
+    }
+}
+
+/// MessagePack, with structs encoded as maps so other languages see field names.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePack;
+
+impl Codec for MessagePack {
+    fn content_type(&self) -> &'static str {
+        "application/msgpack"
+    }
+
+    fn content_encoding(&self) -> &'static str {
+        "binary"
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        rmp_serde::to_vec_named(value).map_err(|error| CodecError::encode(self, error))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, CodecError> {
+        rmp_serde::from_slice(data).map_err(|error| CodecError::decode(self, error))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cbor;
+
+impl Codec for Cbor {
+    fn content_type(&self) -> &'static str {
+        "application/cbor"
+    }
+
+    fn content_encoding(&self) -> &'static str {
+        "binary"
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        serde_cbor::to_vec(value).map_err(|error| CodecError::encode(self, error))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, CodecError> {
+        serde_cbor::from_slice(data).map_err(|error| CodecError::decode(self, error))
+    }
+}
+
+/// The body as is: only strings and byte sequences can be encoded, and decoded
+/// into types accepting bytes (`String`, `Vec<u8>`, ...).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Raw;
+
+impl Codec for Raw {
+    fn content_type(&self) -> &'static str {
+        "application/octet-stream"
+    }
+
+    fn content_encoding(&self) -> &'static str {
+        "binary"
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        match serde_json::to_value(value).map_err(|error| CodecError::encode(self, error))? {
+            serde_json::Value::String(text) => Ok(text.into_bytes()),
+            serde_json::Value::Array(items) => items
+                .iter()
+                .map(|item| item.as_u64().and_then(|byte| u8::try_from(byte).ok()))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| CodecError::encode(self, "expected a sequence of bytes")),
+            _ => Err(CodecError::encode(self, "expected a string or bytes")),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, CodecError> {
+        // `String` and byte buffers accept bytes, `Vec<u8>` only a sequence
+        T::deserialize(BytesDeserializer::<ValueError>::new(data)).or_else(|error| {
+            T::deserialize(SeqDeserializer::<_, ValueError>::new(data.iter().copied()))
+                .map_err(|_| CodecError::decode(self, error))
+        })
+    }
+}
+
+/// Every supported codec, chosen at runtime from a flag or a delivery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContentType {
+    Json,
+    MessagePack,
+    Cbor,
+    #[default]
+    Raw,
+}
+
+impl ContentType {
+    /// Recognizes the usual spellings of each MIME type, ignoring parameters such
+    /// as `charset` and accepting structured suffixes like `+json`.
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        let mime = mime.split(';').next()?.trim().to_ascii_lowercase();
+        match mime.as_str() {
+            "application/json" | "text/json" => Some(ContentType::Json),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(ContentType::MessagePack)
+            }
+            "application/cbor" => Some(ContentType::Cbor),
+            "application/octet-stream" | "text/plain" => Some(ContentType::Raw),
+            mime if mime.ends_with("+json") => Some(ContentType::Json),
+            mime if mime.ends_with("+msgpack") => Some(ContentType::MessagePack),
+            mime if mime.ends_with("+cbor") => Some(ContentType::Cbor),
+            _ => None,
+        }
+    }
+
+    /// The codec for a message, [`Raw`] when it has no content type.
+    pub fn of(properties: &BasicProperties) -> Result<Self, CodecError> {
+        match properties.content_type() {
+            Some(mime) => Self::from_mime(mime.as_str())
+                .ok_or_else(|| CodecError::Unsupported(mime.to_string())),
+            None => Ok(ContentType::Raw),
+        }
+    }
+
+    /// Decodes a message body with the codec its properties ask for, decompressing
+    /// it first, to at most `max_decompressed` bytes, when it has a `content_encoding`.
+    pub fn decode_message<T: DeserializeOwned>(
+        properties: &BasicProperties,
+        data: &[u8],
+        max_decompressed: usize,
+    ) -> Result<T, CodecError> {
+        let codec = Self::of(properties)?;
+        codec.decode(&compression::decompress(
+            properties,
+            data,
+            max_decompressed,
+        )?)
+    }
+}
+
+impl Codec for ContentType {
+    fn content_type(&self) -> &'static str {
+        match self {
+            ContentType::Json => Json.content_type(),
+            ContentType::MessagePack => MessagePack.content_type(),
+            ContentType::Cbor => Cbor.content_type(),
+            ContentType::Raw => Raw.content_type(),
+        }
+    }
+
+    fn content_encoding(&self) -> &'static str {
+        match self {
+            ContentType::Json => Json.content_encoding(),
+            ContentType::MessagePack => MessagePack.content_encoding(),
+            ContentType::Cbor => Cbor.content_encoding(),
+            ContentType::Raw => Raw.content_encoding(),
+        }
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        match self {
+            ContentType::Json => Json.encode(value),
+            ContentType::MessagePack => MessagePack.encode(value),
+            ContentType::Cbor => Cbor.encode(value),
+            ContentType::Raw => Raw.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, CodecError> {
+        match self {
+            ContentType::Json => Json.decode(data),
+            ContentType::MessagePack => MessagePack.decode(data),
+            ContentType::Cbor => Cbor.decode(data),
+            ContentType::Raw => Raw.decode(data),
+        }
+    }
+}
+
+impl FromStr for ContentType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ContentType::Json),
+            "msgpack" => Ok(ContentType::MessagePack),
+            "cbor" => Ok(ContentType::Cbor),
+            "raw" => Ok(ContentType::Raw),
+            s => Self::from_mime(s).ok_or_else(|| format!("Invalid content type: {}", s)),
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Body compression, announced through `content_encoding`.
+//!
+//! A publisher with a [`Compression`] compresses bodies of at least its threshold
+//! and replaces the message's `content_encoding` with the algorithm's name; smaller
+//! bodies are sent as they are, since compressing them rarely pays off. Consumers
+//! call [`decompress`], which undoes whatever the property names and refuses
+//! encodings it doesn't know rather than handing compressed bytes to a codec. It
+//! also stops at a limit, so that a small body can't expand to exhaust memory.
+//!
+//! `gzip` is RFC 1952, `zstd` a Zstandard frame and `lz4` the LZ4 frame format, so
+//! producers written in other languages can use their standard libraries.
+
+use clap::Clap;
+use lapin::BasicProperties;
+use std::{
+    borrow::Cow,
+    fmt,
+    io::{self, Read, Write},
+    str::FromStr,
+};
+
+/// Encodings that leave the body untouched, as set by the codecs.
+const IDENTITY: &[&str] = &["identity", "utf-8", "binary"];
+
+/// Default for the largest body a message may decompress to, 64 MiB.
+pub const MAX_DECOMPRESSED: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Gzip,
+    Zstd,
+    Lz4,
+}
+
+impl Algorithm {
+    /// Name published as `content_encoding`.
+    pub fn content_encoding(self) -> &'static str {
+        match self {
+            Algorithm::Gzip => "gzip",
+            Algorithm::Zstd => "zstd",
+            Algorithm::Lz4 => "lz4",
+        }
+    }
+
+    pub fn from_content_encoding(encoding: &str) -> Option<Self> {
+        match encoding.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Algorithm::Gzip),
+            "zstd" => Some(Algorithm::Zstd),
+            "lz4" => Some(Algorithm::Lz4),
+            _ => None,
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Algorithm::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Algorithm::Zstd => zstd::encode_all(data, 0),
+            Algorithm::Lz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+                encoder.write_all(data)?;
+                encoder.finish().map_err(io::Error::other)
+            }
+        }
+    }
+
+    /// Decompresses `data`, failing once the output grows past `limit` bytes.
+    pub fn decompress(self, data: &[u8], limit: usize) -> Result<Vec<u8>, CompressionError> {
+        let corrupt = |error: io::Error| CompressionError::Corrupt {
+            algorithm: self,
+            reason: error.to_string(),
+        };
+        let reader: Box<dyn Read + '_> = match self {
+            Algorithm::Gzip => Box::new(flate2::read::GzDecoder::new(data)),
+            Algorithm::Zstd => Box::new(zstd::stream::read::Decoder::new(data).map_err(corrupt)?),
+            Algorithm::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(data)),
+        };
+        // One byte past the limit tells a body of exactly `limit` bytes from a larger one
+        let mut decompressed = Vec::new();
+        reader
+            .take(limit as u64 + 1)
+            .read_to_end(&mut decompressed)
+            .map_err(corrupt)?;
+        if decompressed.len() > limit {
+            return Err(CompressionError::TooLarge {
+                algorithm: self,
+                limit,
+            });
+        }
+        Ok(decompressed)
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_content_encoding(s).ok_or_else(|| format!("Invalid compression: {}", s))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompressionError {
+    /// `content_encoding` names no algorithm we know.
+    UnknownEncoding(String),
+    /// The body is not valid for the algorithm it claims.
+    Corrupt {
+        algorithm: Algorithm,
+        reason: String,
+    },
+    /// The body decompresses to more than `limit` bytes.
+    TooLarge { algorithm: Algorithm, limit: usize },
+}
+
+impl fmt::Display for CompressionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompressionError::UnknownEncoding(encoding) => {
+                write!(f, "unknown content encoding `{}`", encoding)
+            }
+            CompressionError::Corrupt { algorithm, reason } => write!(
+                f,
+                "corrupt {} body: {}",
+                algorithm.content_encoding(),
+                reason
+            ),
+            CompressionError::TooLarge { algorithm, limit } => write!(
+                f,
+                "{} body decompresses to more than {} bytes",
+                algorithm.content_encoding(),
+                limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CompressionError {}
+
+/// Compresses bodies of at least `threshold` bytes with `algorithm`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    pub algorithm: Algorithm,
+    pub threshold: usize,
+}
+
+impl Compression {
+    pub fn new(algorithm: Algorithm, threshold: usize) -> Self {
+        Self {
+            algorithm,
+            threshold,
+        }
+    }
+
+    /// Compresses `payload` when it is large enough, setting `content_encoding` on
+    /// `properties` accordingly.
+    pub fn apply(
+        &self,
+        payload: Vec<u8>,
+        properties: BasicProperties,
+    ) -> io::Result<(Vec<u8>, BasicProperties)> {
+        if payload.len() < self.threshold {
+            return Ok((payload, properties));
+        }
+        let compressed = self.algorithm.compress(&payload)?;
+        let properties = properties.with_content_encoding(self.algorithm.content_encoding().into());
+        Ok((compressed, properties))
+    }
+}
+
+/// The body of a message as its codec expects it, decompressed according to its
+/// `content_encoding` to at most `limit` bytes.
+pub fn decompress<'a>(
+    properties: &BasicProperties,
+    data: &'a [u8],
+    limit: usize,
+) -> Result<Cow<'a, [u8]>, CompressionError> {
+    let encoding = match properties.content_encoding() {
+        Some(encoding) => encoding.as_str(),
+        None => return Ok(Cow::Borrowed(data)),
+    };
+    if encoding.is_empty() || IDENTITY.contains(&encoding.to_ascii_lowercase().as_str()) {
+        return Ok(Cow::Borrowed(data));
+    }
+    let algorithm = Algorithm::from_content_encoding(encoding)
+        .ok_or_else(|| CompressionError::UnknownEncoding(encoding.to_string()))?;
+    algorithm.decompress(data, limit).map(Cow::Owned)
+}
+
+#[derive(Debug, Clone, Clap)]
+pub struct CompressionOpts {
+    /// Compress message bodies with `gzip`, `zstd` or `lz4`
+    #[clap(long)]
+    pub compress: Option<Algorithm>,
+    /// Smallest body, in bytes, worth compressing
+    #[clap(long, default_value = "1024")]
+    pub compress_threshold: usize,
+    /// Largest body, in bytes, a received message may decompress to
+    #[clap(long, default_value = "67108864")]
+    pub max_decompressed: usize,
+}
+
+impl CompressionOpts {
+    pub fn compression(&self) -> Option<Compression> {
+        self.compress
+            .map(|algorithm| Compression::new(algorithm, self.compress_threshold))
+    }
+}
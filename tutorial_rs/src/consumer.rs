@@ -0,0 +1,492 @@
+//! Async, `Stream` based consumer.
+//!
+//! Wraps `lapin::Consumer` so binaries can `.next().await` deliveries (and therefore
+//! use them inside `tokio::select!`) instead of blocking a runtime thread with
+//! `into_iter()`, and can process several deliveries at once up to the prefetch
+//! count set on the channel.
+//!
+//! With [`Consumer::with_metrics`], deliveries are counted as they arrive and as
+//! they are acked, nacked or rejected, and handlers are timed.
+//!
+//! With [`Consumer::with_health`], its tag, prefetch and latest delivery are
+//! reported by the health endpoints.
+//!
+//! With [`Consumer::with_dedup`], deliveries seen before are acked without running
+//! the handler (see [`dedup`](crate::dedup)).
+//!
+//! With [`Consumer::with_reports`], the jobs of the deliveries it settles without
+//! the handler get a final event (see [`status`](crate::status)).
+//!
+//! Every delivery is handled in a span continuing the trace it was published in
+//! (see [`telemetry`](crate::telemetry)).
+
+use crate::{
+    codec::{CodecError, ContentType},
+    compression::MAX_DECOMPRESSED,
+    dedup::Dedup,
+    envelope::{EnvelopeError, Keyring},
+    health::Health,
+    metrics::{Metrics, QueueMetrics},
+    shutdown::{DrainPolicy, Shutdown},
+    status::{JobReports, JobStatus},
+    telemetry,
+};
+use futures::{future, stream::FuturesUnordered, Stream, StreamExt};
+use lapin::{
+    message,
+    options::{
+        BasicAckOptions, BasicCancelOptions, BasicConsumeOptions, BasicNackOptions,
+        BasicQosOptions, BasicRejectOptions,
+    },
+    types::{FieldTable, ShortString},
+    Channel, DeliveryTag, Result,
+};
+use serde::de::DeserializeOwned;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    marker::PhantomData,
+    ops::Deref,
+    pin::Pin,
+    string::FromUtf8Error,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+use tracing::{info, warn, Instrument};
+
+/// Payload types deliveries can be decoded into.
+pub trait Payload: Sized {
+    type Error: std::error::Error;
+
+    fn decode(data: &[u8]) -> std::result::Result<Self, Self::Error>;
+}
+
+impl Payload for Vec<u8> {
+    type Error = Infallible;
+
+    fn decode(data: &[u8]) -> std::result::Result<Self, Self::Error> {
+        Ok(data.to_vec())
+    }
+}
+
+impl Payload for String {
+    type Error = FromUtf8Error;
+
+    fn decode(data: &[u8]) -> std::result::Result<Self, Self::Error> {
+        String::from_utf8(data.to_vec())
+    }
+}
+
+/// A delivery whose payload decodes into `T`.
+///
+/// Derefs to the underlying `lapin` delivery to reach its properties, and acks,
+/// nacks or rejects it through it while counting it in the consumer's metrics.
+/// Once opened, its `data` stays sealed so it can be
+/// republished as is, and the payload is read from the opened body.
+/// Acking it remembers its key in the consumer's dedup store, if any.
+#[derive(Debug)]
+pub struct Delivery<T> {
+    pub channel: Channel,
+    pub delivery: message::Delivery,
+    opened: Option<Vec<u8>>,
+    metrics: Option<QueueMetrics>,
+    dedup: Option<(Dedup, String)>,
+    /// Set once acked, nacked or rejected, so a shutdown doesn't settle it twice.
+    settled: Arc<AtomicBool>,
+    max_decompressed: usize,
+    _payload: PhantomData<fn() -> T>,
+}
+
+impl<T: Payload> Delivery<T> {
+    pub fn payload(&self) -> std::result::Result<T, T::Error> {
+        T::decode(self.body())
+    }
+}
+
+impl<T> Delivery<T> {
+    /// The body the publisher sealed, or the data as delivered when not opened.
+    pub fn body(&self) -> &[u8] {
+        self.opened.as_deref().unwrap_or(&self.delivery.data)
+    }
+
+    /// Verifies and decrypts the delivery with `keyring`, under its routing key.
+    pub fn open(&mut self, keyring: &Keyring) -> std::result::Result<(), EnvelopeError> {
+        self.opened = Some(keyring.open(
+            self.delivery.routing_key.as_str(),
+            &self.delivery.data,
+            &self.delivery.properties,
+        )?);
+        Ok(())
+    }
+
+    /// Decodes the payload with the codec its `content_type` asks for, after undoing
+    /// the compression its `content_encoding` names.
+    pub fn decode<V: DeserializeOwned>(&self) -> std::result::Result<V, CodecError> {
+        ContentType::decode_message(
+            &self.delivery.properties,
+            self.body(),
+            self.max_decompressed,
+        )
+    }
+
+    pub async fn ack(&self, options: BasicAckOptions) -> Result<()> {
+        // Remembered first: a crash before the ack then skips the redelivery
+        if let Some((dedup, key)) = &self.dedup {
+            if let Err(error) = dedup.remember(key) {
+                warn!("Failed to remember delivery {}: {}", key, error);
+            }
+        }
+        self.delivery.ack(options).await?;
+        self.settled.store(true, Ordering::SeqCst);
+        if let Some(metrics) = &self.metrics {
+            metrics.acked();
+        }
+        Ok(())
+    }
+
+    pub async fn nack(&self, options: BasicNackOptions) -> Result<()> {
+        self.delivery.nack(options).await?;
+        self.settled.store(true, Ordering::SeqCst);
+        if let Some(metrics) = &self.metrics {
+            metrics.rejected();
+        }
+        Ok(())
+    }
+
+    pub async fn reject(&self, options: BasicRejectOptions) -> Result<()> {
+        self.delivery.reject(options).await?;
+        self.settled.store(true, Ordering::SeqCst);
+        if let Some(metrics) = &self.metrics {
+            metrics.rejected();
+        }
+        Ok(())
+    }
+}
+
+impl<T> Deref for Delivery<T> {
+    type Target = message::Delivery;
+
+    fn deref(&self) -> &Self::Target {
+        &self.delivery
+    }
+}
+
+pub struct Consumer<T> {
+    channel: Channel,
+    inner: lapin::Consumer,
+    queue: String,
+    prefetch: u16,
+    shutdown: Option<Shutdown>,
+    keyring: Option<Arc<Keyring>>,
+    metrics: Option<(Metrics, QueueMetrics)>,
+    health: Option<Health>,
+    dedup: Option<Dedup>,
+    reports: Option<JobReports>,
+    max_decompressed: usize,
+    _payload: PhantomData<fn() -> T>,
+}
+
+impl<T: Payload> Consumer<T> {
+    /// Starts consuming `queue`, setting the channel prefetch to `prefetch` first
+    /// unless it is 0 (unlimited).
+    pub async fn start(channel: &Channel, queue: &str, prefetch: u16) -> Result<Self> {
+        Self::start_with_options(channel, queue, prefetch, BasicConsumeOptions::default()).await
+    }
+
+    /// Like [`Consumer::start`], with explicit consume options (e.g. `no_ack`).
+    pub async fn start_with_options(
+        channel: &Channel,
+        queue: &str,
+        prefetch: u16,
+        options: BasicConsumeOptions,
+    ) -> Result<Self> {
+        if prefetch > 0 {
+            channel
+                .basic_qos(prefetch, BasicQosOptions::default())
+                .await?;
+        }
+
+        let inner = channel
+            .basic_consume(queue, "", options, FieldTable::default())
+            .await?;
+
+        Ok(Self {
+            channel: channel.clone(),
+            inner,
+            queue: queue.to_string(),
+            prefetch,
+            shutdown: None,
+            keyring: None,
+            metrics: None,
+            health: None,
+            dedup: None,
+            reports: None,
+            max_decompressed: MAX_DECOMPRESSED,
+            _payload: PhantomData,
+        })
+    }
+
+    /// Makes [`Consumer::for_each_concurrent`] stop gracefully once `shutdown` fires.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Makes [`Consumer::for_each_concurrent`] open deliveries with `keyring`, and
+    /// reject without requeueing the ones that fail to.
+    pub fn with_keyring(mut self, keyring: Option<Arc<Keyring>>) -> Self {
+        self.keyring = keyring;
+        self
+    }
+
+    pub fn keyring(&self) -> Option<&Arc<Keyring>> {
+        self.keyring.as_ref()
+    }
+
+    /// Records the deliveries of this consumer in `metrics`, under its queue.
+    pub fn with_metrics(mut self, metrics: Option<Metrics>) -> Self {
+        self.metrics = metrics.map(|metrics| {
+            let queue = metrics.queue(&self.queue);
+            (metrics, queue)
+        });
+        self
+    }
+
+    /// Reports this consumer as registered in `health` until it is dropped, and its
+    /// deliveries as they arrive.
+    pub fn with_health(mut self, health: Option<Health>) -> Self {
+        if let Some(health) = &health {
+            health.consuming(self.tag().as_str(), self.prefetch);
+        }
+        self.health = health;
+        self
+    }
+
+    /// Makes [`Consumer::for_each_concurrent`] ack the deliveries `dedup` has seen
+    /// before instead of handling them, counting them in the metrics.
+    pub fn with_dedup(mut self, dedup: Option<Dedup>) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Makes [`Consumer::for_each_concurrent`] report the jobs of the deliveries it
+    /// rejects as failed, and of the duplicates it skips as skipped, to `reports`.
+    pub fn with_reports(mut self, reports: Option<JobReports>) -> Self {
+        self.reports = reports;
+        self
+    }
+
+    /// Makes [`Delivery::decode`] refuse bodies that decompress to more than
+    /// `max_decompressed` bytes, instead of [`MAX_DECOMPRESSED`].
+    pub fn with_max_decompressed(mut self, max_decompressed: usize) -> Self {
+        self.max_decompressed = max_decompressed;
+        self
+    }
+
+    pub fn channel(&self) -> &Channel {
+        &self.channel
+    }
+
+    pub fn dedup(&self) -> Option<&Dedup> {
+        self.dedup.as_ref()
+    }
+
+    pub fn metrics(&self) -> Option<&Metrics> {
+        self.metrics.as_ref().map(|(metrics, _)| metrics)
+    }
+
+    pub fn queue(&self) -> &str {
+        &self.queue
+    }
+
+    pub fn tag(&self) -> ShortString {
+        self.inner.tag()
+    }
+
+    pub fn prefetch(&self) -> u16 {
+        self.prefetch
+    }
+
+    /// Runs `handler` on every delivery, with at most `prefetch` of them (one when
+    /// prefetch is unlimited) in flight at any time. Stops on the first consumer or
+    /// handler error.
+    ///
+    /// When a [`Shutdown`] is attached and fires, the consumer is cancelled and the
+    /// in-flight deliveries are drained according to its policy before returning.
+    pub async fn for_each_concurrent<F, Fut>(mut self, mut handler: F) -> Result<()>
+    where
+        F: FnMut(Delivery<T>) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let limit = self.prefetch.max(1) as usize;
+        let mut in_flight = FuturesUnordered::new();
+        // Deliveries handed to the handler, until it returns
+        let mut pending = HashMap::<DeliveryTag, Arc<AtomicBool>>::new();
+        let shutdown = self.shutdown.take();
+        let watched = shutdown.clone();
+        let stopped = async move {
+            match watched {
+                Some(watched) => watched.wait().await,
+                None => future::pending().await,
+            }
+        };
+        tokio::pin!(stopped);
+
+        loop {
+            tokio::select! {
+                _ = &mut stopped => break,
+                Some((tag, result)) = in_flight.next(), if !in_flight.is_empty() => {
+                    pending.remove(&tag);
+                    result?;
+                }
+                delivery = self.next(), if in_flight.len() < limit => match delivery {
+                    Some(delivery) => {
+                        let mut delivery = delivery?;
+                        let tag = delivery.delivery_tag;
+                        let (span, context) = telemetry::delivery_span(&self.queue, &delivery);
+                        if let Some(keyring) = &self.keyring {
+                            if let Err(error) = delivery.open(keyring) {
+                                span.in_scope(|| warn!("Rejecting message: {}", error));
+                                delivery
+                                    .reject(BasicRejectOptions { requeue: false })
+                                    .await?;
+                                if let Some(reports) = &self.reports {
+                                    let error = error.to_string();
+                                    let failed = JobStatus::Failed { error, retrying: false };
+                                    reports.reporter(&delivery.properties).report(failed).await;
+                                }
+                                continue;
+                            }
+                        }
+                        if let Some(dedup) = &self.dedup {
+                            if let Some(key) = dedup.key(&delivery.properties, delivery.body()) {
+                                if dedup.seen(&key)? {
+                                    span.in_scope(|| info!("Skipping duplicate {}", key));
+                                    delivery.ack(BasicAckOptions::default()).await?;
+                                    if let Some((_, queue_metrics)) = &self.metrics {
+                                        queue_metrics.duplicate();
+                                    }
+                                    if let Some(reports) = &self.reports {
+                                        let reason = format!("duplicate {}", key);
+                                        let skipped = JobStatus::Skipped { reason };
+                                        reports.reporter(&delivery.properties).report(skipped).await;
+                                    }
+                                    continue;
+                                }
+                                delivery.dedup = Some((dedup.clone(), key));
+                            }
+                        }
+                        pending.insert(tag, delivery.settled.clone());
+                        let queue_metrics = self.metrics.as_ref().map(|(_, queue)| queue.clone());
+                        let handled = handler(delivery);
+                        in_flight.push(async move {
+                            let _handling = queue_metrics.as_ref().map(QueueMetrics::handling);
+                            let started = Instant::now();
+                            let result = context.scope(handled).instrument(span).await;
+                            if let Some(queue_metrics) = &queue_metrics {
+                                queue_metrics.handled(started.elapsed());
+                            }
+                            (tag, result)
+                        });
+                    }
+                    None => {
+                        while let Some((_tag, result)) = in_flight.next().await {
+                            result?;
+                        }
+                        return Ok(());
+                    }
+                },
+            }
+        }
+
+        let shutdown = match shutdown {
+            Some(shutdown) => shutdown,
+            None => return Ok(()),
+        };
+        self.channel
+            .basic_cancel(self.tag().as_str(), BasicCancelOptions::default())
+            .await?;
+
+        if shutdown.policy == DrainPolicy::Finish {
+            let drain = async {
+                while let Some((tag, result)) = in_flight.next().await {
+                    pending.remove(&tag);
+                    result?;
+                }
+                Ok(())
+            };
+            if let Ok(drained) = tokio::time::timeout(shutdown.drain_timeout, drain).await {
+                return drained;
+            }
+            warn!("Drain timeout reached, requeueing in-flight deliveries");
+        }
+
+        drop(in_flight);
+        // A handler may have settled its delivery before being stopped, and settling
+        // a tag twice closes the channel
+        let unsettled = pending
+            .into_iter()
+            .filter(|(_, settled)| !settled.load(Ordering::SeqCst))
+            .map(|(tag, _)| tag);
+        for tag in unsettled {
+            self.channel
+                .basic_nack(
+                    tag,
+                    BasicNackOptions {
+                        requeue: true,
+                        ..Default::default()
+                    },
+                )
+                .await?;
+            if let Some((_, queue_metrics)) = &self.metrics {
+                queue_metrics.rejected();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        if let Some(health) = &self.health {
+            health.stopped_consuming();
+        }
+    }
+}
+
+impl<T> Stream for Consumer<T> {
+    type Item = Result<Delivery<T>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let metrics = self.metrics.as_ref().map(|(_, queue)| queue.clone());
+        let health = self.health.clone();
+        let max_decompressed = self.max_decompressed;
+        self.inner.poll_next_unpin(cx).map(|item| {
+            item.map(|delivery| {
+                delivery.map(|(channel, delivery)| {
+                    if let Some(metrics) = &metrics {
+                        metrics.delivered(delivery.redelivered);
+                    }
+                    if let Some(health) = &health {
+                        health.delivered();
+                    }
+                    Delivery {
+                        channel,
+                        delivery,
+                        opened: None,
+                        metrics,
+                        dedup: None,
+                        settled: Arc::new(AtomicBool::new(false)),
+                        max_decompressed,
+                        _payload: PhantomData,
+                    }
+                })
+            })
+        })
+    }
+}
@@ -0,0 +1,423 @@
+//! Skipping deliveries that were already handled.
+//!
+//! Consumers ack after handling a delivery, so a crash in between gets it
+//! redelivered and handled twice. A consumer with a [`Dedup`] looks every delivery
+//! up by its key, its `message_id` or a hash of its body, before handing it over:
+//! deliveries seen before are acked and skipped, the others are remembered once
+//! acked. Deliveries without a `message_id` are never skipped by `message-id`.
+//!
+//! Keys are remembered in a [`DedupStore`] for a TTL: in memory, as an LRU of a
+//! bounded number of keys, or on disk in SQLite or sled so they survive restarts.
+//!
+//! Retries republished by a [`RetryPolicy`] keep the `message_id` of the attempt
+//! that failed, which is acked once republished. Their key carries the retry count
+//! so they aren't mistaken for duplicates of it.
+
+use crate::retry::RetryPolicy;
+use clap::Clap;
+use lapin::BasicProperties;
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    fmt, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Debug)]
+pub enum DedupError {
+    Sqlite(rusqlite::Error),
+    Sled(sled::Error),
+    /// `--dedup` names a store kept on disk without a `--dedup-path`.
+    MissingPath(Backend),
+}
+
+impl fmt::Display for DedupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DedupError::Sqlite(error) => write!(f, "dedup store: {}", error),
+            DedupError::Sled(error) => write!(f, "dedup store: {}", error),
+            DedupError::MissingPath(backend) => {
+                write!(f, "--dedup {} requires --dedup-path", backend)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DedupError {}
+
+impl From<rusqlite::Error> for DedupError {
+    fn from(error: rusqlite::Error) -> Self {
+        DedupError::Sqlite(error)
+    }
+}
+
+impl From<sled::Error> for DedupError {
+    fn from(error: sled::Error) -> Self {
+        DedupError::Sled(error)
+    }
+}
+
+impl From<DedupError> for lapin::Error {
+    fn from(error: DedupError) -> Self {
+        let kind = match &error {
+            DedupError::MissingPath(_) => io::ErrorKind::InvalidInput,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, error.to_string()).into()
+    }
+}
+
+/// Remembers keys for a while.
+pub trait DedupStore: Send + Sync {
+    /// Whether `key` was inserted less than the store's TTL ago.
+    fn contains(&self, key: &str) -> std::result::Result<bool, DedupError>;
+
+    fn insert(&self, key: &str) -> std::result::Result<(), DedupError>;
+}
+
+/// Keys kept in memory, forgetting the least recently used beyond `capacity`.
+#[derive(Debug)]
+pub struct MemoryStore {
+    capacity: usize,
+    ttl: Duration,
+    lru: Mutex<Lru>,
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    /// Insertion time and last use of each key.
+    keys: HashMap<String, (Instant, u64)>,
+    /// Keys by last use.
+    uses: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, key: &str, inserted: Instant) {
+        self.clock += 1;
+        if let Some((_, used)) = self.keys.insert(key.to_string(), (inserted, self.clock)) {
+            self.uses.remove(&used);
+        }
+        self.uses.insert(self.clock, key.to_string());
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((_, used)) = self.keys.remove(key) {
+            self.uses.remove(&used);
+        }
+    }
+}
+
+impl MemoryStore {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            ttl,
+            lru: Mutex::new(Lru::default()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lru.lock().unwrap().keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl DedupStore for MemoryStore {
+    fn contains(&self, key: &str) -> std::result::Result<bool, DedupError> {
+        let mut lru = self.lru.lock().unwrap();
+        let inserted = match lru.keys.get(key) {
+            Some((inserted, _)) => *inserted,
+            None => return Ok(false),
+        };
+        if inserted.elapsed() >= self.ttl {
+            lru.remove(key);
+            return Ok(false);
+        }
+        lru.touch(key, inserted);
+        Ok(true)
+    }
+
+    fn insert(&self, key: &str) -> std::result::Result<(), DedupError> {
+        let mut lru = self.lru.lock().unwrap();
+        lru.touch(key, Instant::now());
+        while lru.keys.len() > self.capacity {
+            let oldest = match lru.uses.iter().next() {
+                Some((_, key)) => key.clone(),
+                None => break,
+            };
+            lru.remove(&oldest);
+        }
+        Ok(())
+    }
+}
+
+/// Keys kept in a SQLite database.
+#[derive(Debug)]
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+    ttl: Duration,
+}
+
+impl SqliteStore {
+    /// Opens the database at `path`, creating it if needed.
+    pub fn open(path: &Path, ttl: Duration) -> std::result::Result<Self, DedupError> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS seen (
+                key TEXT PRIMARY KEY,
+                inserted_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS seen_inserted_at ON seen (inserted_at)",
+        )?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+            ttl,
+        })
+    }
+}
+
+impl DedupStore for SqliteStore {
+    fn contains(&self, key: &str) -> std::result::Result<bool, DedupError> {
+        let inserted_at: Option<i64> = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT inserted_at FROM seen WHERE key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(inserted_at.is_some_and(|at| !expired(at as u64, self.ttl)))
+    }
+
+    fn insert(&self, key: &str) -> std::result::Result<(), DedupError> {
+        let now = unix_millis();
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT OR REPLACE INTO seen (key, inserted_at) VALUES (?1, ?2)",
+            params![key, now as i64],
+        )?;
+        connection.execute(
+            "DELETE FROM seen WHERE inserted_at <= ?1",
+            params![now.saturating_sub(self.ttl.as_millis() as u64) as i64],
+        )?;
+        Ok(())
+    }
+}
+
+/// Keys kept in a sled tree, with their insertion time as big endian millis. Values
+/// of any other shape are treated as absent.
+#[derive(Debug)]
+pub struct SledStore {
+    tree: sled::Db,
+    ttl: Duration,
+}
+
+impl SledStore {
+    /// Opens the database in the `path` directory, creating it if needed.
+    pub fn open(path: &Path, ttl: Duration) -> std::result::Result<Self, DedupError> {
+        Ok(Self {
+            tree: sled::open(path)?,
+            ttl,
+        })
+    }
+}
+
+impl DedupStore for SledStore {
+    fn contains(&self, key: &str) -> std::result::Result<bool, DedupError> {
+        let inserted_at = match self.tree.get(key)? {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        let fresh = <[u8; 8]>::try_from(inserted_at.as_ref())
+            .is_ok_and(|millis| !expired(u64::from_be_bytes(millis), self.ttl));
+        if !fresh {
+            self.tree.remove(key)?;
+        }
+        Ok(fresh)
+    }
+
+    fn insert(&self, key: &str) -> std::result::Result<(), DedupError> {
+        self.tree.insert(key, &unix_millis().to_be_bytes())?;
+        self.tree.flush()?;
+        Ok(())
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn expired(inserted_at: u64, ttl: Duration) -> bool {
+    unix_millis().saturating_sub(inserted_at) >= ttl.as_millis() as u64
+}
+
+/// What identifies a delivery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupKey {
+    MessageId,
+    /// SHA-256 of the body, as the publisher sealed it.
+    ContentHash,
+}
+
+impl FromStr for DedupKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "message-id" => Ok(DedupKey::MessageId),
+            "content-hash" => Ok(DedupKey::ContentHash),
+            _ => Err(format!("Invalid dedup key: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Memory,
+    Sqlite,
+    Sled,
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Backend::Memory => "memory",
+            Backend::Sqlite => "sqlite",
+            Backend::Sled => "sled",
+        })
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(Backend::Memory),
+            "sqlite" => Ok(Backend::Sqlite),
+            "sled" => Ok(Backend::Sled),
+            _ => Err(format!("Invalid dedup store: {}", s)),
+        }
+    }
+}
+
+/// A store and the key deliveries are looked up by, counting the duplicates found.
+#[derive(Clone)]
+pub struct Dedup {
+    store: Arc<dyn DedupStore>,
+    key: DedupKey,
+    hits: Arc<AtomicU64>,
+}
+
+impl Dedup {
+    pub fn new(store: impl DedupStore + 'static, key: DedupKey) -> Self {
+        Self {
+            store: Arc::new(store),
+            key,
+            hits: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// The key of a delivery with `properties` and `body`, or `None` when it has
+    /// none to be looked up by.
+    pub fn key(&self, properties: &BasicProperties, body: &[u8]) -> Option<String> {
+        let key = match self.key {
+            DedupKey::MessageId => properties.message_id().as_ref()?.to_string(),
+            DedupKey::ContentHash => Sha256::digest(body)
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+        };
+        match RetryPolicy::retry_count(properties) {
+            0 => Some(key),
+            retries => Some(format!("{}#{}", key, retries)),
+        }
+    }
+
+    /// Whether `key` was seen before, counting it as a hit if so.
+    pub fn seen(&self, key: &str) -> std::result::Result<bool, DedupError> {
+        let seen = self.store.contains(key)?;
+        if seen {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(seen)
+    }
+
+    pub fn remember(&self, key: &str) -> std::result::Result<(), DedupError> {
+        self.store.insert(key)
+    }
+
+    /// Duplicates found so far.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Debug for Dedup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dedup")
+            .field("key", &self.key)
+            .field("hits", &self.hits())
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Clap)]
+pub struct DedupOpts {
+    /// Skip deliveries seen before, remembering them in `memory`, `sqlite` or `sled`
+    #[clap(long)]
+    pub dedup: Option<Backend>,
+    /// Identify deliveries by `message-id` or `content-hash`
+    #[clap(long, default_value = "message-id")]
+    pub dedup_key: DedupKey,
+    /// SQLite file or sled directory of the dedup store
+    #[clap(long)]
+    pub dedup_path: Option<PathBuf>,
+    /// Number of keys the `memory` dedup store holds at most
+    #[clap(long, default_value = "10000")]
+    pub dedup_capacity: usize,
+    /// Seconds a delivery is remembered for
+    #[clap(long, default_value = "86400")]
+    pub dedup_ttl: u64,
+}
+
+impl DedupOpts {
+    /// Opens the store `--dedup` names, or returns `None` when it isn't set.
+    pub fn open(&self) -> std::result::Result<Option<Dedup>, DedupError> {
+        let backend = match self.dedup {
+            Some(backend) => backend,
+            None => return Ok(None),
+        };
+        let ttl = Duration::from_secs(self.dedup_ttl);
+        let path = || {
+            self.dedup_path
+                .as_deref()
+                .ok_or(DedupError::MissingPath(backend))
+        };
+        let dedup = match backend {
+            Backend::Memory => {
+                Dedup::new(MemoryStore::new(self.dedup_capacity, ttl), self.dedup_key)
+            }
+            Backend::Sqlite => Dedup::new(SqliteStore::open(path()?, ttl)?, self.dedup_key),
+            Backend::Sled => Dedup::new(SledStore::open(path()?, ttl)?, self.dedup_key),
+        };
+        Ok(Some(dedup))
+    }
+}
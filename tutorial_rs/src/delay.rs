@@ -0,0 +1,86 @@
+//! Delayed delivery into a work queue, without the delayed message plugin.
+//!
+//! A delayed message is published to a delay queue with its delay as per-message
+//! TTL (`expiration`). Once it expires, the delay queue dead-letters it into the
+//! work queue through the default exchange, which drops the `expiration` on the way.
+//!
+//! RabbitMQ only expires messages at the head of a queue, so a message never leaves
+//! a delay queue before the ones published ahead of it. Delays are therefore spread
+//! over buckets: for a work queue `q`, `q.delay.<ms>` holds the delays longer than
+//! `<ms> / 2` and up to `<ms>`, a power of two seconds. A message ends up at most
+//! half its bucket late, when a longer delay from the same bucket is ahead of it.
+//! Delays are capped at [`MAX_DELAY`], which is also the largest bucket.
+
+use crate::headers::arguments;
+use lapin::{
+    options::QueueDeclareOptions,
+    types::{AMQPValue, FieldTable},
+    BasicProperties, Channel, Result,
+};
+use std::time::Duration;
+
+/// Longest delay a message can be held back for, 2^22 seconds or about 48.5 days.
+pub const MAX_DELAY: Duration = Duration::from_secs(1 << 22);
+
+#[derive(Debug, Clone)]
+pub struct DelayQueues {
+    pub queue: String,
+}
+
+impl DelayQueues {
+    /// The delay queues dead-lettering into `queue`.
+    pub fn new(queue: &str) -> Self {
+        Self {
+            queue: queue.to_string(),
+        }
+    }
+
+    /// The bucket holding `delay`: the shortest power of two seconds, from one second
+    /// up to [`MAX_DELAY`], that isn't shorter than it.
+    pub fn bucket(delay: Duration) -> Duration {
+        let mut bucket = Duration::from_secs(1);
+        while bucket < delay {
+            match bucket.checked_mul(2) {
+                Some(next) if next <= MAX_DELAY => bucket = next,
+                _ => break,
+            }
+        }
+        bucket
+    }
+
+    pub fn delay_queue(&self, delay: Duration) -> String {
+        format!("{}.delay.{}", self.queue, Self::bucket(delay).as_millis())
+    }
+
+    /// Arguments every delay queue is declared with.
+    pub fn queue_arguments(&self) -> FieldTable {
+        arguments(vec![
+            ("x-dead-letter-exchange", AMQPValue::LongString("".into())),
+            (
+                "x-dead-letter-routing-key",
+                AMQPValue::LongString(self.queue.clone().into()),
+            ),
+        ])
+    }
+
+    /// Declares the delay queue `delay` goes through, and returns its name.
+    pub async fn declare(&self, channel: &Channel, delay: Duration) -> Result<String> {
+        let name = self.delay_queue(delay);
+        channel
+            .queue_declare(
+                &name,
+                QueueDeclareOptions {
+                    durable: true,
+                    ..Default::default()
+                },
+                self.queue_arguments(),
+            )
+            .await?;
+        Ok(name)
+    }
+
+    /// `properties` with an expiration of `delay`, rounded down to the millisecond.
+    pub fn properties(properties: BasicProperties, delay: Duration) -> BasicProperties {
+        properties.with_expiration(delay.as_millis().to_string().into())
+    }
+}
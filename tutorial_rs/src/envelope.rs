@@ -0,0 +1,481 @@
+//! Signed or encrypted message bodies.
+//!
+//! A publisher with a [`Keyring`] seals every body with the keyring's active key:
+//! `aes-256-gcm` and `chacha20-poly1305` keys encrypt it, `hmac-sha256` keys only
+//! sign it. The scheme and key id travel in the [`SCHEME_HEADER`] and
+//! [`KEY_ID_HEADER`] headers. They are authenticated along with the body, the
+//! routing key the message is delivered with, the properties consumers act on
+//! (content type and encoding, type, reply to, correlation and message ids,
+//! timestamp) and the retry count and RPC deadline headers, so without the key a
+//! message can be neither forged nor altered, nor sent somewhere else. Consumers
+//! with a keyring open every delivery before handling it and reject the ones that
+//! aren't sealed, were tampered with or name a key they don't have, which
+//! dead-letters them when the queue has a dead letter exchange.
+//!
+//! A sealed message can still be replayed as is. Its message id being covered too,
+//! consumers deduplicating by message id (see [`dedup`](crate::dedup)) skip the
+//! copies for as long as they remember it.
+//!
+//! Keyring files are TOML or YAML, told apart by their extension:
+//!
+//! ```toml
+//! active = "2024-06"
+//!
+//! [[keys]]
+//! id = "2024-06"
+//! scheme = "chacha20-poly1305"
+//! secret = "..." # 32 random bytes in base64, e.g. from `openssl rand -base64 32`
+//! ```
+//!
+//! To rotate, add the new key to the consumers' keyring and restart them, then make
+//! it `active` for the publishers. Drop the old key once no queue holds messages
+//! sealed with it.
+
+use crate::{
+    headers::{header_int, header_str, with_header},
+    retry::RETRY_COUNT_HEADER,
+    rpc::{DEADLINE_HEADER, DIRECT_REPLY_TO},
+    topology::Format,
+};
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chacha20poly1305::ChaCha20Poly1305;
+use clap::Clap;
+use hmac::{Hmac, Mac};
+use lapin::{
+    types::{AMQPValue, ShortString},
+    BasicProperties,
+};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    fmt, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+/// Header naming the scheme a message is sealed with.
+pub const SCHEME_HEADER: &str = "x-envelope";
+/// Header naming the key a message is sealed with.
+pub const KEY_ID_HEADER: &str = "x-key-id";
+/// Header carrying the base64 HMAC of signed messages.
+pub const SIGNATURE_HEADER: &str = "x-signature";
+
+const SECRET_LEN: usize = 32;
+/// Encrypted bodies start with their nonce.
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scheme {
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    #[serde(rename = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+    #[serde(rename = "hmac-sha256")]
+    HmacSha256,
+}
+
+impl Scheme {
+    pub fn name(self) -> &'static str {
+        match self {
+            Scheme::Aes256Gcm => "aes-256-gcm",
+            Scheme::ChaCha20Poly1305 => "chacha20-poly1305",
+            Scheme::HmacSha256 => "hmac-sha256",
+        }
+    }
+
+    /// Whether the body is encrypted rather than only signed.
+    pub fn encrypts(self) -> bool {
+        self != Scheme::HmacSha256
+    }
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Scheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            Scheme::Aes256Gcm,
+            Scheme::ChaCha20Poly1305,
+            Scheme::HmacSha256,
+        ]
+        .iter()
+        .copied()
+        .find(|scheme| scheme.name() == s)
+        .ok_or_else(|| format!("Invalid scheme: {}", s))
+    }
+}
+
+#[derive(Debug)]
+pub enum KeyringError {
+    Io(PathBuf, io::Error),
+    UnknownFormat(PathBuf),
+    Invalid(String),
+}
+
+impl fmt::Display for KeyringError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyringError::Io(path, error) => write!(f, "{}: {}", path.display(), error),
+            KeyringError::UnknownFormat(path) => write!(
+                f,
+                "{}: unknown keyring format, expected .toml, .yaml or .yml",
+                path.display()
+            ),
+            KeyringError::Invalid(reason) => write!(f, "invalid keyring: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for KeyringError {}
+
+impl From<KeyringError> for lapin::Error {
+    fn from(error: KeyringError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, error.to_string()).into()
+    }
+}
+
+/// Why a message could not be sealed or opened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvelopeError {
+    /// The message carries no envelope headers.
+    Unsealed,
+    UnknownScheme(String),
+    /// The message names a key missing from the keyring.
+    UnknownKey(String),
+    /// The body or its headers were altered, or sealed with another secret.
+    Forged {
+        key_id: String,
+    },
+    /// The body is too large for the scheme.
+    TooLarge,
+}
+
+impl fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvelopeError::Unsealed => write!(f, "message is not sealed"),
+            EnvelopeError::UnknownScheme(scheme) => write!(f, "unknown envelope `{}`", scheme),
+            EnvelopeError::UnknownKey(key_id) => write!(f, "unknown key `{}`", key_id),
+            EnvelopeError::Forged { key_id } => {
+                write!(f, "message fails verification with key `{}`", key_id)
+            }
+            EnvelopeError::TooLarge => write!(f, "body too large to seal"),
+        }
+    }
+}
+
+impl std::error::Error for EnvelopeError {}
+
+impl From<EnvelopeError> for lapin::Error {
+    fn from(error: EnvelopeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error.to_string()).into()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyringFile {
+    active: String,
+    keys: Vec<KeyEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyEntry {
+    id: String,
+    scheme: Scheme,
+    secret: String,
+}
+
+#[derive(Clone)]
+struct Key {
+    scheme: Scheme,
+    secret: Vec<u8>,
+}
+
+/// Keys messages are sealed and opened with. Only the active key seals, any key
+/// opens.
+#[derive(Clone)]
+pub struct Keyring {
+    active: String,
+    keys: HashMap<String, Key>,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ids = self.keys.keys().collect::<Vec<_>>();
+        ids.sort();
+        f.debug_struct("Keyring")
+            .field("active", &self.active)
+            .field("keys", &ids)
+            .finish()
+    }
+}
+
+impl Keyring {
+    /// A keyring holding a single key, used for both sealing and opening.
+    pub fn single(id: &str, scheme: Scheme, secret: &[u8]) -> Result<Self, KeyringError> {
+        let mut keyring = Self {
+            active: id.to_string(),
+            keys: HashMap::new(),
+        };
+        keyring.insert(id, scheme, secret.to_vec())?;
+        Ok(keyring)
+    }
+
+    pub fn parse(source: &str, format: Format) -> Result<Self, KeyringError> {
+        let file: KeyringFile = match format {
+            Format::Toml => {
+                toml::from_str(source).map_err(|e| KeyringError::Invalid(e.to_string()))?
+            }
+            Format::Yaml => {
+                serde_yaml::from_str(source).map_err(|e| KeyringError::Invalid(e.to_string()))?
+            }
+        };
+        let mut keyring = Self {
+            active: file.active,
+            keys: HashMap::new(),
+        };
+        for entry in file.keys {
+            let secret = BASE64.decode(entry.secret.trim()).map_err(|error| {
+                KeyringError::Invalid(format!("secret of `{}`: {}", entry.id, error))
+            })?;
+            keyring.insert(&entry.id, entry.scheme, secret)?;
+        }
+        if !keyring.keys.contains_key(&keyring.active) {
+            return Err(KeyringError::Invalid(format!(
+                "active key `{}` is not in the keyring",
+                keyring.active
+            )));
+        }
+        Ok(keyring)
+    }
+
+    /// Reads a keyring file, its format given by the extension.
+    pub fn load(path: &Path) -> Result<Self, KeyringError> {
+        let format =
+            Format::from_path(path).ok_or_else(|| KeyringError::UnknownFormat(path.into()))?;
+        let source =
+            std::fs::read_to_string(path).map_err(|error| KeyringError::Io(path.into(), error))?;
+        Self::parse(&source, format)
+    }
+
+    fn insert(&mut self, id: &str, scheme: Scheme, secret: Vec<u8>) -> Result<(), KeyringError> {
+        if secret.len() != SECRET_LEN {
+            return Err(KeyringError::Invalid(format!(
+                "secret of `{}` is {} bytes long, expected {}",
+                id,
+                secret.len(),
+                SECRET_LEN
+            )));
+        }
+        if self
+            .keys
+            .insert(id.to_string(), Key { scheme, secret })
+            .is_some()
+        {
+            return Err(KeyringError::Invalid(format!("duplicate key `{}`", id)));
+        }
+        Ok(())
+    }
+
+    /// Id of the key new messages are sealed with.
+    pub fn active(&self) -> &str {
+        &self.active
+    }
+
+    /// Seals `payload` for delivery under `routing_key` with the active key,
+    /// returning the body to publish and `properties` with the envelope headers set.
+    pub fn seal(
+        &self,
+        routing_key: &str,
+        payload: Vec<u8>,
+        properties: BasicProperties,
+    ) -> Result<(Vec<u8>, BasicProperties), EnvelopeError> {
+        let key = &self.keys[&self.active];
+        let aad = authenticated_data(key.scheme, &self.active, routing_key, &properties);
+        let properties = with_header(
+            with_header(
+                properties,
+                SCHEME_HEADER,
+                AMQPValue::LongString(key.scheme.name().into()),
+            ),
+            KEY_ID_HEADER,
+            AMQPValue::LongString(self.active.as_str().into()),
+        );
+
+        if key.scheme == Scheme::HmacSha256 {
+            let signature = BASE64.encode(mac(&key.secret, &aad, &payload).finalize().into_bytes());
+            let properties = with_header(
+                properties,
+                SIGNATURE_HEADER,
+                AMQPValue::LongString(signature.into()),
+            );
+            return Ok((payload, properties));
+        }
+
+        let nonce = rand::random::<[u8; NONCE_LEN]>();
+        let message = Payload {
+            msg: &payload,
+            aad: &aad,
+        };
+        let ciphertext = match key.scheme {
+            Scheme::Aes256Gcm => Aes256Gcm::new_from_slice(&key.secret)
+                .expect("secret length checked on load")
+                .encrypt(&nonce.into(), message),
+            _ => ChaCha20Poly1305::new_from_slice(&key.secret)
+                .expect("secret length checked on load")
+                .encrypt(&nonce.into(), message),
+        }
+        .map_err(|_| EnvelopeError::TooLarge)?;
+        let mut body = nonce.to_vec();
+        body.extend(ciphertext);
+        Ok((body, properties))
+    }
+
+    /// Verifies a sealed body, delivered under `routing_key`, and returns it decrypted.
+    pub fn open(
+        &self,
+        routing_key: &str,
+        data: &[u8],
+        properties: &BasicProperties,
+    ) -> Result<Vec<u8>, EnvelopeError> {
+        let scheme = header_str(properties, SCHEME_HEADER).ok_or(EnvelopeError::Unsealed)?;
+        let scheme = scheme
+            .parse::<Scheme>()
+            .map_err(|_| EnvelopeError::UnknownScheme(scheme))?;
+        let key_id = header_str(properties, KEY_ID_HEADER).ok_or(EnvelopeError::Unsealed)?;
+        let key = self
+            .keys
+            .get(&key_id)
+            .ok_or_else(|| EnvelopeError::UnknownKey(key_id.clone()))?;
+        let forged = || EnvelopeError::Forged {
+            key_id: key_id.clone(),
+        };
+        if key.scheme != scheme {
+            return Err(forged());
+        }
+
+        // The envelope headers were added after the authenticated data was taken
+        let aad = authenticated_data(scheme, &key_id, routing_key, properties);
+        if scheme == Scheme::HmacSha256 {
+            let expected = header_str(properties, SIGNATURE_HEADER)
+                .and_then(|signature| BASE64.decode(signature).ok())
+                .ok_or_else(forged)?;
+            mac(&key.secret, &aad, data)
+                .verify_slice(&expected)
+                .map_err(|_| forged())?;
+            return Ok(data.to_vec());
+        }
+
+        if data.len() < NONCE_LEN {
+            return Err(forged());
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let message = Payload {
+            msg: ciphertext,
+            aad: &aad,
+        };
+        match scheme {
+            Scheme::Aes256Gcm => Aes256Gcm::new_from_slice(&key.secret)
+                .expect("secret length checked on load")
+                .decrypt(nonce.into(), message),
+            _ => ChaCha20Poly1305::new_from_slice(&key.secret)
+                .expect("secret length checked on load")
+                .decrypt(nonce.into(), message),
+        }
+        .map_err(|_| forged())
+    }
+}
+
+/// What a seal covers besides the body: the scheme, the key id, the routing key and
+/// the properties and headers consumers rely on. Every field is length-prefixed, and
+/// absent ones told apart from empty ones, so no two messages share the same data.
+fn authenticated_data(
+    scheme: Scheme,
+    key_id: &str,
+    routing_key: &str,
+    properties: &BasicProperties,
+) -> Vec<u8> {
+    let string = |value: &Option<ShortString>| value.as_ref().map(|value| value.to_string());
+    let int = |name| header_int(properties, name).map(|value| value.to_string());
+    // The broker turns direct reply-to into an address of the caller's own on delivery
+    let reply_to = string(properties.reply_to()).map(|reply_to| {
+        if reply_to.starts_with(DIRECT_REPLY_TO) {
+            DIRECT_REPLY_TO.to_string()
+        } else {
+            reply_to
+        }
+    });
+    let fields = [
+        Some(scheme.name().to_string()),
+        Some(key_id.to_string()),
+        Some(routing_key.to_string()),
+        string(properties.content_type()),
+        string(properties.content_encoding()),
+        string(properties.kind()),
+        reply_to,
+        string(properties.correlation_id()),
+        string(properties.message_id()),
+        properties
+            .timestamp()
+            .map(|timestamp| timestamp.to_string()),
+        int(RETRY_COUNT_HEADER),
+        int(DEADLINE_HEADER),
+    ];
+    let mut data = Vec::new();
+    for field in &fields {
+        match field {
+            Some(value) => {
+                data.push(1);
+                length_prefixed(&mut data, value.as_bytes());
+            }
+            None => data.push(0),
+        }
+    }
+    data
+}
+
+fn length_prefixed(data: &mut Vec<u8>, bytes: &[u8]) {
+    data.extend((bytes.len() as u64).to_be_bytes());
+    data.extend(bytes);
+}
+
+fn mac(secret: &[u8], aad: &[u8], payload: &[u8]) -> Hmac<Sha256> {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(secret).expect("HMAC accepts any key length");
+    for bytes in [aad, payload] {
+        mac.update(&(bytes.len() as u64).to_be_bytes());
+        mac.update(bytes);
+    }
+    mac
+}
+
+#[derive(Debug, Clone, Clap)]
+pub struct EnvelopeOpts {
+    /// Seal published messages with the active key of this keyring file, and reject
+    /// deliveries that don't open with one of its keys
+    #[clap(long)]
+    pub keyring: Option<PathBuf>,
+}
+
+impl EnvelopeOpts {
+    pub fn load(&self) -> Result<Option<Arc<Keyring>>, KeyringError> {
+        self.keyring
+            .as_deref()
+            .map(|path| Keyring::load(path).map(Arc::new))
+            .transpose()
+    }
+}
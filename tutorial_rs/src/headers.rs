@@ -0,0 +1,56 @@
+//! Helpers to read and write entries of the AMQP `headers` table.
+
+use lapin::{
+    types::{AMQPValue, FieldTable},
+    BasicProperties,
+};
+
+/// Returns a copy of `properties` with `name` set to `value` in its headers.
+pub fn with_header(properties: BasicProperties, name: &str, value: AMQPValue) -> BasicProperties {
+    let mut headers = properties.headers().clone().unwrap_or_default();
+    headers.insert(name.into(), value);
+    properties.with_headers(headers)
+}
+
+pub fn header<'a>(properties: &'a BasicProperties, name: &str) -> Option<&'a AMQPValue> {
+    properties
+        .headers()
+        .as_ref()
+        .and_then(|headers| headers.inner().get(name))
+}
+
+/// Reads an integer header, whatever width the publisher encoded it with.
+pub fn header_int(properties: &BasicProperties, name: &str) -> Option<i64> {
+    match header(properties, name)? {
+        AMQPValue::ShortShortInt(value) => Some(*value as i64),
+        AMQPValue::ShortShortUInt(value) => Some(*value as i64),
+        AMQPValue::ShortInt(value) => Some(*value as i64),
+        AMQPValue::ShortUInt(value) => Some(*value as i64),
+        AMQPValue::LongInt(value) => Some(*value as i64),
+        AMQPValue::LongUInt(value) => Some(*value as i64),
+        AMQPValue::LongLongInt(value) => Some(*value),
+        AMQPValue::Timestamp(value) => Some(*value as i64),
+        _ => None,
+    }
+}
+
+pub fn header_str(properties: &BasicProperties, name: &str) -> Option<String> {
+    match header(properties, name)? {
+        AMQPValue::ShortString(value) => Some(value.to_string()),
+        AMQPValue::LongString(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+pub fn header_bool(properties: &BasicProperties, name: &str) -> bool {
+    matches!(header(properties, name), Some(AMQPValue::Boolean(true)))
+}
+
+/// Builds a queue arguments table from `(name, value)` pairs.
+pub fn arguments<'a>(entries: impl IntoIterator<Item = (&'a str, AMQPValue)>) -> FieldTable {
+    let mut table = FieldTable::default();
+    for (name, value) in entries {
+        table.insert(name.into(), value);
+    }
+    table
+}
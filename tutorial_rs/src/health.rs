@@ -0,0 +1,156 @@
+//! Liveness and readiness endpoints for long-running consumers.
+//!
+//! [`HealthOpts::start`] serves two endpoints on `--health-addr`, both answering
+//! with a JSON [`Report`]:
+//!
+//! - `/healthz` returns 200 while the connection and channel are open, 503 otherwise;
+//! - `/readyz` returns 200 once, on top of that, the topology is declared and the
+//!   consumer is registered, 503 otherwise.
+//!
+//! The [`Supervisor`](crate::Supervisor) records every new connection in the shared
+//! [`Health`], and the [`Consumer`](crate::Consumer) its tag, prefetch and deliveries.
+
+use crate::http;
+use clap::Clap;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use lapin::{Channel, Connection, ConnectionStatus, Result};
+use serde::Serialize;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use tracing::info;
+
+#[derive(Debug, Clone, Clap)]
+pub struct HealthOpts {
+    /// Serve `/healthz` and `/readyz` on `http://<addr>` while consuming
+    #[clap(long)]
+    pub health_addr: Option<SocketAddr>,
+}
+
+impl HealthOpts {
+    /// Serves the health checks on `--health-addr`, or returns `None` when it isn't
+    /// set.
+    pub fn start(&self) -> Result<Option<Health>> {
+        let addr = match self.health_addr {
+            Some(addr) => addr,
+            None => return Ok(None),
+        };
+        let health = Health::default();
+        let served = health.clone();
+        let addr = http::serve("Health", addr, move |request| served.respond(request))?;
+        info!("Serving health checks on http://{}", addr);
+        Ok(Some(health))
+    }
+}
+
+/// State of a consumer process, shared with the health endpoints.
+#[derive(Debug, Clone, Default)]
+pub struct Health {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    connection: Option<ConnectionStatus>,
+    channel: Option<Channel>,
+    consumer_tag: Option<String>,
+    prefetch: Option<u16>,
+    last_delivery: Option<SystemTime>,
+}
+
+/// What the health endpoints answer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Report {
+    /// Whether the connection and channel are open.
+    pub healthy: bool,
+    /// Whether the topology is declared and the consumer registered as well.
+    pub ready: bool,
+    pub connection: String,
+    pub channel: String,
+    pub topology_declared: bool,
+    pub consumer_tag: Option<String>,
+    pub prefetch: Option<u16>,
+    /// RFC 3339 time of the last delivery received.
+    pub last_delivery: Option<String>,
+}
+
+impl Health {
+    /// Records a new connection and channel, once the topology has been declared on
+    /// it. The consumer started on the previous one, if any, is forgotten.
+    pub fn connected(&self, connection: &Connection, channel: &Channel) {
+        let mut state = self.state.lock().unwrap();
+        state.connection = Some(connection.status().clone());
+        state.channel = Some(channel.clone());
+        state.consumer_tag = None;
+        state.prefetch = None;
+    }
+
+    pub fn consuming(&self, consumer_tag: &str, prefetch: u16) {
+        let mut state = self.state.lock().unwrap();
+        state.consumer_tag = Some(consumer_tag.to_string());
+        state.prefetch = Some(prefetch);
+    }
+
+    pub fn stopped_consuming(&self) {
+        self.state.lock().unwrap().consumer_tag = None;
+    }
+
+    pub fn delivered(&self) {
+        self.state.lock().unwrap().last_delivery = Some(SystemTime::now());
+    }
+
+    pub fn report(&self) -> Report {
+        let state = self.state.lock().unwrap();
+        let connection_open = state
+            .connection
+            .as_ref()
+            .is_some_and(ConnectionStatus::connected);
+        let channel_open = state
+            .channel
+            .as_ref()
+            .is_some_and(|channel| channel.status().connected());
+        let healthy = connection_open && channel_open;
+        let topology_declared = state.connection.is_some();
+
+        Report {
+            healthy,
+            ready: healthy && topology_declared && state.consumer_tag.is_some(),
+            connection: state
+                .connection
+                .as_ref()
+                .map(|connection| format!("{:?}", connection.state()))
+                .unwrap_or_else(|| "Disconnected".to_string())
+                .to_lowercase(),
+            channel: state
+                .channel
+                .as_ref()
+                .map(|channel| format!("{:?}", channel.status().state()))
+                .unwrap_or_else(|| "Closed".to_string())
+                .to_lowercase(),
+            topology_declared,
+            consumer_tag: state.consumer_tag.clone(),
+            prefetch: state.prefetch,
+            last_delivery: state
+                .last_delivery
+                .map(|time| humantime::format_rfc3339_millis(time).to_string()),
+        }
+    }
+
+    fn respond(&self, request: &Request<Body>) -> Response<Body> {
+        let report = self.report();
+        let passed = match (request.method(), request.uri().path()) {
+            (&Method::GET, "/healthz") => report.healthy,
+            (&Method::GET, "/readyz") => report.ready,
+            _ => return http::not_found(),
+        };
+        let status = if passed {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        let body = serde_json::to_string(&report).expect("reports serialize");
+        http::response(status, "application/json", body)
+    }
+}
@@ -0,0 +1,58 @@
+//! Tiny HTTP server the metrics and health endpoints are served from.
+
+use hyper::{
+    header::{HeaderValue, CONTENT_TYPE},
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, StatusCode,
+};
+use lapin::Result;
+use std::{convert::Infallible, io, net::SocketAddr};
+use tracing::error;
+
+/// Serves every request with `respond` from a background task, returning the
+/// address bound (which tells the port when `addr` asks for any). `name` tells
+/// the server apart in logs.
+pub(crate) fn serve<F>(name: &'static str, addr: SocketAddr, respond: F) -> Result<SocketAddr>
+where
+    F: Fn(&Request<Body>) -> Response<Body> + Clone + Send + Sync + 'static,
+{
+    let make_service = make_service_fn(move |_| {
+        let respond = respond.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = respond(&request);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+    let server = hyper::Server::try_bind(&addr)
+        .map_err(io::Error::other)?
+        .serve(make_service);
+    let bound = server.local_addr();
+    tokio::spawn(async move {
+        if let Err(error) = server.await {
+            error!("{} server stopped: {}", name, error);
+        }
+    });
+    Ok(bound)
+}
+
+/// A response with `body` of the given content type.
+pub(crate) fn response(
+    status: StatusCode,
+    content_type: &'static str,
+    body: String,
+) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+pub(crate) fn not_found() -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::NOT_FOUND;
+    response
+}
@@ -0,0 +1,47 @@
+pub mod codec;
+pub mod compression;
+pub mod connection;
+pub mod consumer;
+pub mod dedup;
+pub mod delay;
+pub mod envelope;
+pub mod headers;
+pub mod health;
+mod http;
+pub mod metrics;
+pub mod pool;
+pub mod publisher;
+pub mod results;
+pub mod retry;
+pub mod rpc;
+pub mod schedule;
+pub mod shutdown;
+pub mod status;
+pub mod supervisor;
+pub mod tasks;
+pub mod telemetry;
+pub mod topic;
+pub mod topology;
+
+pub use codec::{Codec, CodecError, ContentType};
+pub use compression::{Algorithm, Compression, CompressionError, CompressionOpts};
+pub use connection::ConnectionOpts;
+pub use consumer::{Consumer, Delivery, Payload};
+pub use dedup::{Dedup, DedupError, DedupKey, DedupOpts, DedupStore};
+pub use delay::DelayQueues;
+pub use envelope::{EnvelopeError, EnvelopeOpts, Keyring, KeyringError};
+pub use health::{Health, HealthOpts};
+pub use metrics::{Metrics, MetricsOpts};
+pub use pool::{Pool, Utilisation};
+pub use publisher::{BatchReport, Message, PublishOutcome, Publisher};
+pub use results::{JobRecord, ResultStore, ResultsError};
+pub use retry::{Failure, FailureAction, RetryPolicy};
+pub use rpc::{RemoteError, ReplyMode, RpcClient, RpcError, RpcServer};
+pub use schedule::{LeaderLock, Schedule, ScheduleError};
+pub use shutdown::{DrainPolicy, Shutdown, ShutdownOpts};
+pub use status::{JobEvent, JobReporter, JobReports, JobStatus, JobWatch};
+pub use supervisor::{Backoff, Supervisor};
+pub use tasks::{TaskHandler, TaskRegistry};
+pub use telemetry::{LogFormat, TelemetryOpts, TraceContext};
+pub use topic::{BindingKey, TopicIndex};
+pub use topology::{Topology, TopologyOpts};
@@ -0,0 +1,307 @@
+//! Prometheus metrics for publishers and consumers.
+//!
+//! A [`Metrics`] registry is labelled with the binary it runs in. [`Publisher`]s
+//! attached to it count what they publish and how the broker confirmed it, by
+//! exchange, and [`Consumer`]s count what they receive and how it was settled, by
+//! queue, along with the time their handler took, how many deliveries it has in
+//! flight and how many it skipped as duplicates. [`Pool`]s count the time each of their slots spends busy.
+//! [`MetricsOpts::start`] serves the registry on `--metrics-addr` for
+//! Prometheus to scrape.
+//!
+//! [`Publisher`]: crate::Publisher
+//! [`Consumer`]: crate::Consumer
+//! [`Pool`]: crate::Pool
+
+use crate::{http, publisher::PublishOutcome};
+use clap::Clap;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use lapin::Result;
+use prometheus::{
+    CounterVec, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use tracing::info;
+
+const NAMESPACE: &str = "amqp";
+const DEFAULT_EXCHANGE: &str = "amq.default";
+
+#[derive(Debug, Clone, Clap)]
+pub struct MetricsOpts {
+    /// Serve Prometheus metrics on `http://<addr>/metrics` while running
+    #[clap(long)]
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+impl MetricsOpts {
+    /// Creates the metrics of `binary` and serves them on `--metrics-addr`, or
+    /// returns `None` when it isn't set.
+    pub fn start(&self, binary: &str) -> Result<Option<Metrics>> {
+        let addr = match self.metrics_addr {
+            Some(addr) => addr,
+            None => return Ok(None),
+        };
+        let metrics = Metrics::new(binary);
+        let served = metrics.clone();
+        let addr = http::serve("Metrics", addr, move |request| served.respond(request))?;
+        info!("Serving metrics on http://{}/metrics", addr);
+        Ok(Some(metrics))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    registry: Registry,
+    published: IntCounterVec,
+    confirmed: IntCounterVec,
+    nacked: IntCounterVec,
+    returned: IntCounterVec,
+    confirm_duration: HistogramVec,
+    delivered: IntCounterVec,
+    redelivered: IntCounterVec,
+    acked: IntCounterVec,
+    rejected: IntCounterVec,
+    dedup_hits: IntCounterVec,
+    handler_duration: HistogramVec,
+    in_flight: IntGaugeVec,
+    slot_busy: CounterVec,
+}
+
+impl Metrics {
+    /// A registry whose metrics all carry a `binary` label set to `binary`.
+    pub fn new(binary: &str) -> Self {
+        let mut labels = HashMap::new();
+        labels.insert("binary".to_string(), binary.to_string());
+        let registry = Registry::new_custom(Some(NAMESPACE.to_string()), Some(labels))
+            .expect("valid registry labels");
+
+        let counter = |name: &str, help: &str, label: &str| {
+            let counter =
+                IntCounterVec::new(Opts::new(name, help), &[label]).expect("valid counter options");
+            registry
+                .register(Box::new(counter.clone()))
+                .expect("counter registered once");
+            counter
+        };
+        let histogram = |name: &str, help: &str, label: &str| {
+            let histogram = HistogramVec::new(HistogramOpts::new(name, help), &[label])
+                .expect("valid histogram options");
+            registry
+                .register(Box::new(histogram.clone()))
+                .expect("histogram registered once");
+            histogram
+        };
+
+        let published = counter("published_total", "Messages published", "exchange");
+        let confirmed = counter(
+            "confirmed_total",
+            "Messages acked by the broker",
+            "exchange",
+        );
+        let nacked = counter("nacked_total", "Messages nacked by the broker", "exchange");
+        let returned = counter(
+            "returned_total",
+            "Messages returned as unroutable",
+            "exchange",
+        );
+        let confirm_duration = histogram(
+            "confirm_duration_seconds",
+            "Time from publishing a message until the broker confirmed it",
+            "exchange",
+        );
+        let delivered = counter("delivered_total", "Messages delivered", "queue");
+        let redelivered = counter(
+            "redelivered_total",
+            "Messages delivered again after an earlier delivery wasn't acked",
+            "queue",
+        );
+        let acked = counter("acked_total", "Deliveries acked", "queue");
+        let rejected = counter("rejected_total", "Deliveries rejected or nacked", "queue");
+        let dedup_hits = counter(
+            "dedup_hits_total",
+            "Deliveries acked without handling them, as duplicates of earlier ones",
+            "queue",
+        );
+        let handler_duration = histogram(
+            "handler_duration_seconds",
+            "Time spent handling a delivery",
+            "queue",
+        );
+        let in_flight = IntGaugeVec::new(
+            Opts::new("in_flight", "Deliveries being handled"),
+            &["queue"],
+        )
+        .expect("valid gauge options");
+        registry
+            .register(Box::new(in_flight.clone()))
+            .expect("gauge registered once");
+        let slot_busy = CounterVec::new(
+            Opts::new(
+                "slot_busy_seconds_total",
+                "Time each worker slot spent busy",
+            ),
+            &["slot"],
+        )
+        .expect("valid counter options");
+        registry
+            .register(Box::new(slot_busy.clone()))
+            .expect("counter registered once");
+
+        Self {
+            inner: Arc::new(Inner {
+                registry,
+                published,
+                confirmed,
+                nacked,
+                returned,
+                confirm_duration,
+                delivered,
+                redelivered,
+                acked,
+                rejected,
+                dedup_hits,
+                handler_duration,
+                in_flight,
+                slot_busy,
+            }),
+        }
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.inner.registry
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.inner.registry.gather(), &mut buffer)
+            .expect("metrics encode as text");
+        String::from_utf8(buffer).expect("text format is UTF-8")
+    }
+
+    pub fn published(&self, exchange: &str) {
+        self.inner
+            .published
+            .with_label_values(&[exchange_label(exchange)])
+            .inc();
+    }
+
+    /// Records how the broker confirmed a message published to `exchange` after
+    /// `elapsed`.
+    pub fn confirmed(&self, exchange: &str, outcome: &PublishOutcome, elapsed: Duration) {
+        let labels = &[exchange_label(exchange)];
+        let counter = match outcome {
+            PublishOutcome::Ack => &self.inner.confirmed,
+            PublishOutcome::Nack => &self.inner.nacked,
+            PublishOutcome::Returned { .. } => &self.inner.returned,
+            PublishOutcome::NotRequested => return,
+        };
+        counter.with_label_values(labels).inc();
+        self.inner
+            .confirm_duration
+            .with_label_values(labels)
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// The consumer side metrics of `queue`.
+    pub fn queue(&self, queue: &str) -> QueueMetrics {
+        let labels = &[queue];
+        QueueMetrics {
+            delivered: self.inner.delivered.with_label_values(labels),
+            redelivered: self.inner.redelivered.with_label_values(labels),
+            acked: self.inner.acked.with_label_values(labels),
+            rejected: self.inner.rejected.with_label_values(labels),
+            dedup_hits: self.inner.dedup_hits.with_label_values(labels),
+            handler_duration: self.inner.handler_duration.with_label_values(labels),
+            in_flight: self.inner.in_flight.with_label_values(labels),
+        }
+    }
+
+    /// Records that worker `slot` of a [`Pool`](crate::Pool) was busy for `elapsed`.
+    pub fn slot_busy(&self, slot: usize, elapsed: Duration) {
+        self.inner
+            .slot_busy
+            .with_label_values(&[&slot.to_string()])
+            .inc_by(elapsed.as_secs_f64());
+    }
+
+    fn respond(&self, request: &Request<Body>) -> Response<Body> {
+        match (request.method(), request.uri().path()) {
+            (&Method::GET, "/metrics") => {
+                http::response(StatusCode::OK, "text/plain; version=0.0.4", self.render())
+            }
+            _ => http::not_found(),
+        }
+    }
+}
+
+/// The metrics of a single queue, resolved once per consumer.
+#[derive(Debug, Clone)]
+pub struct QueueMetrics {
+    delivered: IntCounter,
+    redelivered: IntCounter,
+    acked: IntCounter,
+    rejected: IntCounter,
+    dedup_hits: IntCounter,
+    handler_duration: Histogram,
+    in_flight: IntGauge,
+}
+
+impl QueueMetrics {
+    pub fn delivered(&self, redelivered: bool) {
+        self.delivered.inc();
+        if redelivered {
+            self.redelivered.inc();
+        }
+    }
+
+    pub fn acked(&self) {
+        self.acked.inc();
+    }
+
+    pub fn rejected(&self) {
+        self.rejected.inc();
+    }
+
+    pub fn duplicate(&self) {
+        self.dedup_hits.inc();
+    }
+
+    /// Counts a delivery as in flight until the returned guard is dropped.
+    pub fn handling(&self) -> Handling {
+        self.in_flight.inc();
+        Handling {
+            in_flight: self.in_flight.clone(),
+        }
+    }
+
+    pub fn handled(&self, elapsed: Duration) {
+        self.handler_duration.observe(elapsed.as_secs_f64());
+    }
+}
+
+/// Guard returned by [`QueueMetrics::handling`].
+#[derive(Debug)]
+pub struct Handling {
+    in_flight: IntGauge,
+}
+
+impl Drop for Handling {
+    fn drop(&mut self) {
+        self.in_flight.dec();
+    }
+}
+
+fn exchange_label(exchange: &str) -> &str {
+    if exchange.is_empty() {
+        DEFAULT_EXCHANGE
+    } else {
+        exchange
+    }
+}
@@ -0,0 +1,216 @@
+//! Bounded pool of tokio tasks for handling deliveries in parallel.
+//!
+//! A [`Pool`] has a fixed number of slots. [`Pool::run`] waits for a free slot and
+//! runs a handler on a task of its own there, in the span and trace context it was
+//! called from, so a consumer with prefetch `N` and a pool of `N` slots behaves like
+//! `N` workers with prefetch 1 sharing a channel. Each delivery is still acked on the
+//! channel it came from, with its own delivery tag.
+//!
+//! Slots record how long they are busy: [`Pool::utilisation`] reports the share of
+//! time each one spent handling deliveries, and with [`Pool::with_metrics`] the busy
+//! time is also exported as `amqp_slot_busy_seconds_total`.
+
+use crate::{metrics::Metrics, telemetry::TraceContext};
+use lapin::Result;
+use std::{
+    fmt,
+    future::Future,
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+};
+use tracing::{Instrument, Span};
+
+#[derive(Debug, Clone)]
+pub struct Pool {
+    inner: Arc<Inner>,
+    metrics: Option<Metrics>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    permits: Arc<Semaphore>,
+    slots: Mutex<Slots>,
+}
+
+#[derive(Debug)]
+struct Slots {
+    free: Vec<usize>,
+    busy_since: Vec<Option<Instant>>,
+    busy: Vec<Duration>,
+    window_start: Instant,
+}
+
+impl Pool {
+    /// A pool of `size` slots, at least one.
+    pub fn new(size: usize) -> Self {
+        let size = size.max(1);
+        Self {
+            inner: Arc::new(Inner {
+                permits: Arc::new(Semaphore::new(size)),
+                slots: Mutex::new(Slots {
+                    free: (0..size).rev().collect(),
+                    busy_since: vec![None; size],
+                    busy: vec![Duration::ZERO; size],
+                    window_start: Instant::now(),
+                }),
+            }),
+            metrics: None,
+        }
+    }
+
+    /// Records the busy time of each slot in `metrics`.
+    pub fn with_metrics(mut self, metrics: Option<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn size(&self) -> usize {
+        self.inner.slots.lock().unwrap().busy.len()
+    }
+
+    /// Runs `handler` on its own task once a slot is free, and returns its result.
+    ///
+    /// Dropping the returned future aborts the task, so a consumer requeueing its
+    /// in-flight deliveries on shutdown doesn't have them acked behind its back.
+    pub async fn run<F>(&self, handler: F) -> Result<()>
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        let permit = self
+            .inner
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("pool semaphore is never closed");
+        let slot = self.inner.slots.lock().unwrap().acquire();
+        let _busy = Busy {
+            inner: self.inner.clone(),
+            slot,
+            metrics: self.metrics.clone(),
+            _permit: permit,
+        };
+
+        let context = TraceContext::current();
+        let handler = async move {
+            match context {
+                Some(context) => context.scope(handler).await,
+                None => handler.await,
+            }
+        };
+        let mut task = Task(tokio::spawn(handler.instrument(Span::current())));
+        match (&mut task.0).await {
+            Ok(result) => result,
+            Err(error) => {
+                Err(io::Error::other(format!("slot {} panicked: {}", slot, error)).into())
+            }
+        }
+    }
+
+    /// The share of time each slot was busy since the previous call, or since the
+    /// pool was created.
+    pub fn utilisation(&self) -> Utilisation {
+        let mut slots = self.inner.slots.lock().unwrap();
+        let now = Instant::now();
+        let window = now.duration_since(slots.window_start);
+        slots.window_start = now;
+        let Slots {
+            busy_since, busy, ..
+        } = &mut *slots;
+        let shares = busy
+            .iter_mut()
+            .zip(busy_since.iter_mut())
+            .map(|(busy, since)| {
+                let mut elapsed = std::mem::take(busy);
+                if let Some(since) = since {
+                    elapsed += now.duration_since(*since);
+                    *since = now;
+                }
+                if window.is_zero() {
+                    0.0
+                } else {
+                    (elapsed.as_secs_f64() / window.as_secs_f64()).min(1.0)
+                }
+            })
+            .collect();
+        Utilisation { shares }
+    }
+}
+
+impl Slots {
+    fn acquire(&mut self) -> usize {
+        let slot = self.free.pop().expect("a permit guarantees a free slot");
+        self.busy_since[slot] = Some(Instant::now());
+        slot
+    }
+
+    fn release(&mut self, slot: usize) -> Duration {
+        let elapsed = self.busy_since[slot]
+            .take()
+            .map_or(Duration::ZERO, |since| since.elapsed());
+        self.busy[slot] += elapsed;
+        self.free.push(slot);
+        elapsed
+    }
+}
+
+/// Frees its slot, and the permit that goes with it, when the handler is done.
+struct Busy {
+    inner: Arc<Inner>,
+    slot: usize,
+    metrics: Option<Metrics>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        let elapsed = self.inner.slots.lock().unwrap().release(self.slot);
+        if let Some(metrics) = &self.metrics {
+            metrics.slot_busy(self.slot, elapsed);
+        }
+    }
+}
+
+/// Aborts its task when dropped.
+struct Task(JoinHandle<Result<()>>);
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Per-slot utilisation returned by [`Pool::utilisation`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Utilisation {
+    /// Fraction of the window each slot was busy, between 0 and 1.
+    pub shares: Vec<f64>,
+}
+
+impl Utilisation {
+    /// The average share over all slots.
+    pub fn mean(&self) -> f64 {
+        if self.shares.is_empty() {
+            return 0.0;
+        }
+        self.shares.iter().sum::<f64>() / self.shares.len() as f64
+    }
+}
+
+impl fmt::Display for Utilisation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.0}% [", self.mean() * 100.0)?;
+        for (slot, share) in self.shares.iter().enumerate() {
+            if slot > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{:.0}%", share * 100.0)?;
+        }
+        write!(f, "]")
+    }
+}
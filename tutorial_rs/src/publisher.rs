@@ -0,0 +1,389 @@
+//! Publisher with optional publisher confirms.
+//!
+//! Messages are published with `mandatory` set unless [`Publisher::with_mandatory`]
+//! turns it off, so when confirms are enabled a message that no queue is bound to
+//! receive comes back as [`PublishOutcome::Returned`] instead of being dropped
+//! silently.
+//!
+//! [`Publisher::publish_batch`] pipelines many messages, keeping up to a window of
+//! confirms outstanding instead of waiting a full round trip per message.
+//!
+//! With [`Publisher::with_compression`] every message sent, batched or not, goes
+//! through a [`Compression`] stage on its way out, and with
+//! [`Publisher::with_keyring`] it is then sealed with the keyring's active key, for
+//! its routing key or the one set with [`Publisher::with_delivery_routing_key`].
+//!
+//! With [`Publisher::with_metrics`] every message sent is counted by exchange, along
+//! with how and how quickly the broker confirmed it.
+//!
+//! Every message is published in a span of its own, and carries its trace context
+//! in its headers (see [`telemetry`](crate::telemetry)).
+//!
+//! Messages without a `message_id` are given a random UUID, so consumers can tell
+//! redeliveries apart (see [`dedup`](crate::dedup)). A batched message keeps its
+//! `message_id` when it is republished after a nack.
+
+use crate::{
+    codec::{Codec, CodecError},
+    compression::Compression,
+    envelope::Keyring,
+    metrics::Metrics,
+    telemetry,
+};
+use futures::{stream::FuturesUnordered, StreamExt};
+use lapin::{
+    options::{BasicPublishOptions, ConfirmSelectOptions},
+    publisher_confirm::{Confirmation, PublisherConfirm},
+    BasicProperties, Channel, Result,
+};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::Instrument;
+use uuid::Uuid;
+
+/// What the broker did with a published message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublishOutcome {
+    /// The broker took responsibility for the message.
+    Ack,
+    /// The broker could not take responsibility for the message.
+    Nack,
+    /// The message could not be routed to any queue and was sent back.
+    Returned { reply_code: u16, reply_text: String },
+    /// Confirms are disabled on the channel, nothing is known about the message.
+    NotRequested,
+}
+
+impl PublishOutcome {
+    pub fn is_ack(&self) -> bool {
+        matches!(self, PublishOutcome::Ack)
+    }
+}
+
+impl From<Confirmation> for PublishOutcome {
+    fn from(confirmation: Confirmation) -> Self {
+        match confirmation {
+            Confirmation::Ack(Some(returned)) | Confirmation::Nack(Some(returned)) => {
+                PublishOutcome::Returned {
+                    reply_code: returned.reply_code,
+                    reply_text: returned.reply_text.to_string(),
+                }
+            }
+            Confirmation::Ack(None) => PublishOutcome::Ack,
+            Confirmation::Nack(None) => PublishOutcome::Nack,
+            Confirmation::NotRequested => PublishOutcome::NotRequested,
+        }
+    }
+}
+
+impl fmt::Display for PublishOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PublishOutcome::Ack => write!(f, "ack"),
+            PublishOutcome::Nack => write!(f, "nack"),
+            PublishOutcome::Returned {
+                reply_code,
+                reply_text,
+            } => write!(f, "returned ({} {})", reply_code, reply_text),
+            PublishOutcome::NotRequested => write!(f, "not requested"),
+        }
+    }
+}
+
+/// A message waiting to be published as part of a batch.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub exchange: String,
+    pub routing_key: String,
+    pub payload: Vec<u8>,
+    pub properties: BasicProperties,
+}
+
+impl Message {
+    /// A message carrying `value` encoded with `codec`, tagged with its content type.
+    pub fn encode<C: Codec, T: Serialize>(
+        exchange: &str,
+        routing_key: &str,
+        codec: &C,
+        value: &T,
+        properties: BasicProperties,
+    ) -> std::result::Result<Self, CodecError> {
+        Ok(Self {
+            exchange: exchange.to_string(),
+            routing_key: routing_key.to_string(),
+            payload: codec.encode(value)?,
+            properties: codec.properties(properties),
+        })
+    }
+}
+
+/// Summary of a [`Publisher::publish_batch`] call.
+#[derive(Debug, Clone, Default)]
+pub struct BatchReport {
+    /// Messages sent, retries included.
+    pub published: usize,
+    /// Messages acked, or merely sent when confirms are disabled.
+    pub acked: usize,
+    /// Indices, in the batch given, of the messages still nacked after every retry.
+    pub nacked: Vec<usize>,
+    /// Indices, in the batch given, of the messages returned as unroutable.
+    pub returned: Vec<usize>,
+    pub retried: usize,
+    /// Time from the first publish until the last confirm arrived.
+    pub elapsed: Duration,
+    /// Slowest publish to confirm round trip seen in the batch.
+    pub max_latency: Duration,
+}
+
+impl fmt::Display for BatchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} published, {} acked, {} nacked, {} returned, {} retried in {:?} (max latency {:?})",
+            self.published,
+            self.acked,
+            self.nacked.len(),
+            self.returned.len(),
+            self.retried,
+            self.elapsed,
+            self.max_latency
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Publisher {
+    channel: Channel,
+    confirm: bool,
+    mandatory: bool,
+    compression: Option<Compression>,
+    keyring: Option<Arc<Keyring>>,
+    delivery_routing_key: Option<String>,
+    metrics: Option<Metrics>,
+}
+
+impl Publisher {
+    /// Wraps `channel`, putting it in confirm mode when `confirm` is set.
+    pub async fn new(channel: Channel, confirm: bool) -> Result<Self> {
+        if confirm {
+            channel
+                .confirm_select(ConfirmSelectOptions::default())
+                .await?;
+        }
+        Ok(Self {
+            channel,
+            confirm,
+            mandatory: true,
+            compression: None,
+            keyring: None,
+            delivery_routing_key: None,
+            metrics: None,
+        })
+    }
+
+    /// Whether the broker returns the messages no queue takes, the default, or
+    /// drops them.
+    pub fn with_mandatory(mut self, mandatory: bool) -> Self {
+        self.mandatory = mandatory;
+        self
+    }
+
+    /// Compresses the messages published from now on, `None` sends them as they are.
+    pub fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Seals the messages published from now on, `None` sends them unsealed.
+    pub fn with_keyring(mut self, keyring: Option<Arc<Keyring>>) -> Self {
+        self.keyring = keyring;
+        self
+    }
+
+    /// Seals the messages published from now on for delivery under `routing_key`
+    /// rather than the one they are published with, as when a delay or retry queue
+    /// dead-letters them into their work queue. `None` seals them for their own.
+    pub fn with_delivery_routing_key(mut self, routing_key: Option<String>) -> Self {
+        self.delivery_routing_key = routing_key;
+        self
+    }
+
+    /// Records the messages published from now on in `metrics`.
+    pub fn with_metrics(mut self, metrics: Option<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn channel(&self) -> &Channel {
+        &self.channel
+    }
+
+    pub fn confirm(&self) -> bool {
+        self.confirm
+    }
+
+    /// Publishes a message and waits for the broker's confirmation when enabled.
+    pub async fn publish(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        payload: Vec<u8>,
+        properties: BasicProperties,
+    ) -> Result<PublishOutcome> {
+        let sent_at = Instant::now();
+        let confirm = self
+            .send(exchange, routing_key, payload, with_message_id(properties))
+            .await?;
+        let outcome = confirm.await?.into();
+        self.confirmed(exchange, &outcome, sent_at.elapsed());
+        Ok(outcome)
+    }
+
+    /// Encodes `value` with `codec` and publishes it like [`Publisher::publish`].
+    pub async fn publish_encoded<C: Codec, T: Serialize>(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        codec: &C,
+        value: &T,
+        properties: BasicProperties,
+    ) -> Result<PublishOutcome> {
+        let message = Message::encode(exchange, routing_key, codec, value, properties)?;
+        self.publish(
+            &message.exchange,
+            &message.routing_key,
+            message.payload,
+            message.properties,
+        )
+        .await
+    }
+
+    /// Sends a message without waiting for its confirmation.
+    async fn send(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        payload: Vec<u8>,
+        properties: BasicProperties,
+    ) -> Result<PublisherConfirm> {
+        let (span, context) = telemetry::publish_span(exchange, routing_key);
+        let properties = context.inject(properties);
+        let (payload, properties) = match &self.compression {
+            Some(compression) => compression.apply(payload, properties)?,
+            None => (payload, properties),
+        };
+        let (payload, properties) = match &self.keyring {
+            Some(keyring) => {
+                let delivered_as = self.delivery_routing_key.as_deref();
+                keyring.seal(delivered_as.unwrap_or(routing_key), payload, properties)?
+            }
+            None => (payload, properties),
+        };
+        let confirm = self
+            .channel
+            .basic_publish(
+                exchange,
+                routing_key,
+                BasicPublishOptions {
+                    mandatory: self.mandatory,
+                    ..Default::default()
+                },
+                payload,
+                properties,
+            )
+            .instrument(span)
+            .await?;
+        if let Some(metrics) = &self.metrics {
+            metrics.published(exchange);
+        }
+        Ok(confirm)
+    }
+
+    fn confirmed(&self, exchange: &str, outcome: &PublishOutcome, elapsed: Duration) {
+        if let Some(metrics) = &self.metrics {
+            metrics.confirmed(exchange, outcome, elapsed);
+        }
+    }
+
+    /// Publishes every message keeping at most `window` confirms outstanding, and
+    /// republishes nacked messages up to `max_retries` times.
+    pub async fn publish_batch(
+        &self,
+        messages: Vec<Message>,
+        window: usize,
+        max_retries: u32,
+    ) -> Result<BatchReport> {
+        let window = window.max(1);
+        let start = Instant::now();
+        let mut report = BatchReport::default();
+        let mut pending = messages
+            .into_iter()
+            .enumerate()
+            .map(|(index, message)| {
+                let properties = with_message_id(message.properties);
+                (
+                    index,
+                    Message {
+                        properties,
+                        ..message
+                    },
+                    0,
+                )
+            })
+            .collect::<VecDeque<_>>();
+        let mut in_flight = FuturesUnordered::new();
+
+        loop {
+            while in_flight.len() < window {
+                let (index, message, attempts) = match pending.pop_front() {
+                    Some(next) => next,
+                    None => break,
+                };
+                let sent_at = Instant::now();
+                let confirm = self
+                    .send(
+                        &message.exchange,
+                        &message.routing_key,
+                        message.payload.clone(),
+                        message.properties.clone(),
+                    )
+                    .await?;
+                report.published += 1;
+                in_flight.push(async move { (index, message, attempts, sent_at, confirm.await) });
+            }
+
+            let (index, message, attempts, sent_at, confirmation) = match in_flight.next().await {
+                Some(confirmed) => confirmed,
+                None => break,
+            };
+            let outcome = PublishOutcome::from(confirmation?);
+            report.max_latency = report.max_latency.max(sent_at.elapsed());
+            self.confirmed(&message.exchange, &outcome, sent_at.elapsed());
+            match outcome {
+                PublishOutcome::Ack | PublishOutcome::NotRequested => report.acked += 1,
+                PublishOutcome::Nack if attempts < max_retries => {
+                    report.retried += 1;
+                    pending.push_back((index, message, attempts + 1));
+                }
+                PublishOutcome::Nack => report.nacked.push(index),
+                PublishOutcome::Returned { .. } => report.returned.push(index),
+            }
+        }
+
+        report.elapsed = start.elapsed();
+        Ok(report)
+    }
+}
+
+/// `properties`, with a random UUID as `message_id` unless they already have one.
+fn with_message_id(properties: BasicProperties) -> BasicProperties {
+    match properties.message_id() {
+        Some(_) => properties,
+        None => properties.with_message_id(Uuid::new_v4().to_string().into()),
+    }
+}
@@ -0,0 +1,199 @@
+//! SQLite store of the latest status and result of each job.
+//!
+//! Workers given a [`ResultStore`] record every [`JobEvent`] they publish in it, so
+//! the outcome of a job can be looked up once nobody is watching its events anymore.
+//! SQLite calls block, so records are read and written on tokio's blocking threads.
+
+use crate::status::{JobEvent, JobStatus};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    fmt, io, panic,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tokio::task;
+
+#[derive(Debug)]
+pub struct ResultsError(rusqlite::Error);
+
+impl fmt::Display for ResultsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "results store: {}", self.0)
+    }
+}
+
+impl std::error::Error for ResultsError {}
+
+impl From<rusqlite::Error> for ResultsError {
+    fn from(error: rusqlite::Error) -> Self {
+        ResultsError(error)
+    }
+}
+
+impl From<ResultsError> for lapin::Error {
+    fn from(error: ResultsError) -> Self {
+        io::Error::other(error.to_string()).into()
+    }
+}
+
+/// Where a job stands, as of its latest event.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct JobRecord {
+    pub job_id: String,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    /// State of the latest event: `started`, `progress`, `succeeded`, `failed` or
+    /// `skipped`.
+    pub state: String,
+    pub attempt: u32,
+    pub progress: Option<u8>,
+    pub result: Option<Value>,
+    pub error: Option<String>,
+    /// Whether a failed job will be attempted again.
+    pub retrying: bool,
+    pub updated_at: String,
+}
+
+impl JobRecord {
+    /// Updates the record with a newer event of its job.
+    pub fn apply(&mut self, event: &JobEvent) {
+        self.job_id = event.job_id.clone();
+        if event.kind.is_some() {
+            self.kind = event.kind.clone();
+        }
+        self.state = event.status.state().to_string();
+        self.updated_at = event.at.clone();
+        match &event.status {
+            JobStatus::Started { attempt } => {
+                self.attempt = *attempt;
+                self.progress = None;
+                self.error = None;
+                self.retrying = false;
+            }
+            JobStatus::Progress { percent } => self.progress = Some(*percent),
+            JobStatus::Succeeded { result } => {
+                self.progress = Some(100);
+                self.result = Some(result.clone());
+            }
+            JobStatus::Failed { error, retrying } => {
+                self.error = Some(error.clone());
+                self.retrying = *retrying;
+            }
+            JobStatus::Skipped { .. } => self.retrying = false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ResultStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl ResultStore {
+    /// Opens the database at `path`, creating it if needed.
+    pub fn open(path: &Path) -> std::result::Result<Self, ResultsError> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    /// A store that lives as long as the process, for tests.
+    pub fn in_memory() -> std::result::Result<Self, ResultsError> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(connection: Connection) -> std::result::Result<Self, ResultsError> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS jobs (
+                job_id TEXT PRIMARY KEY,
+                type TEXT,
+                state TEXT NOT NULL,
+                attempt INTEGER NOT NULL,
+                progress INTEGER,
+                result TEXT,
+                error TEXT,
+                retrying INTEGER NOT NULL,
+                updated_at TEXT NOT NULL
+            )",
+        )?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Applies `event` to the record of its job.
+    pub async fn record(&self, event: &JobEvent) -> std::result::Result<(), ResultsError> {
+        let event = event.clone();
+        self.blocking(move |connection| write(connection, &event))
+            .await
+    }
+
+    pub async fn get(&self, job_id: &str) -> std::result::Result<Option<JobRecord>, ResultsError> {
+        let job_id = job_id.to_string();
+        self.blocking(move |connection| read(connection, &job_id))
+            .await
+    }
+
+    /// Runs `query` on a blocking thread, passing on its panics.
+    async fn blocking<T, F>(&self, query: F) -> std::result::Result<T, ResultsError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> std::result::Result<T, ResultsError> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        task::spawn_blocking(move || query(&connection.lock().unwrap()))
+            .await
+            .unwrap_or_else(|error| panic::resume_unwind(error.into_panic()))
+    }
+}
+
+fn write(connection: &Connection, event: &JobEvent) -> std::result::Result<(), ResultsError> {
+    let mut record = read(connection, &event.job_id)?.unwrap_or_default();
+    record.apply(event);
+    connection.execute(
+        "INSERT OR REPLACE INTO jobs
+                (job_id, type, state, attempt, progress, result, error, retrying, updated_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            record.job_id,
+            record.kind,
+            record.state,
+            record.attempt,
+            record.progress,
+            record.result.as_ref().map(Value::to_string),
+            record.error,
+            record.retrying,
+            record.updated_at,
+        ],
+    )?;
+    Ok(())
+}
+
+fn read(
+    connection: &Connection,
+    job_id: &str,
+) -> std::result::Result<Option<JobRecord>, ResultsError> {
+    let record = connection
+        .query_row(
+            "SELECT job_id, type, state, attempt, progress, result, error, retrying, updated_at
+                FROM jobs WHERE job_id = ?1",
+            params![job_id],
+            |row| {
+                let result: Option<String> = row.get(5)?;
+                Ok(JobRecord {
+                    job_id: row.get(0)?,
+                    kind: row.get(1)?,
+                    state: row.get(2)?,
+                    attempt: row.get(3)?,
+                    progress: row.get(4)?,
+                    // Written by `record`, so always valid JSON
+                    result: result.and_then(|result| serde_json::from_str(&result).ok()),
+                    error: row.get(6)?,
+                    retrying: row.get(7)?,
+                    updated_at: row.get(8)?,
+                })
+            },
+        )
+        .optional()?;
+    Ok(record)
+}
@@ -0,0 +1,245 @@
+//! Dead-lettering, delayed retries and a parking lot for failed deliveries.
+//!
+//! For a work queue `q` the policy declares:
+//!
+//! - `q` itself, dead-lettering to the `q.dlx` exchange;
+//! - `q.parking-lot`, bound to `q.dlx`, where messages end up once they are rejected;
+//! - one `q.retry.<ms>` queue per delay tier, whose messages expire after `<ms>` and
+//!   are dead-lettered back into `q` through the default exchange.
+//!
+//! A failed delivery is republished to the next retry tier with its `x-retry-count`
+//! header incremented, and rejected into the parking lot once the tiers run out or
+//! the failure is permanent. The original is only acked once the broker confirmed
+//! its retry, and requeued otherwise.
+
+use crate::{
+    headers::{arguments, header_int, with_header},
+    Delivery, PublishOutcome, Publisher,
+};
+use lapin::{
+    options::{
+        BasicAckOptions, BasicNackOptions, BasicRejectOptions, ExchangeDeclareOptions,
+        QueueBindOptions, QueueDeclareOptions,
+    },
+    types::{AMQPValue, FieldTable},
+    BasicProperties, Channel, ExchangeKind, Result,
+};
+use std::{fmt, time::Duration};
+
+pub const RETRY_COUNT_HEADER: &str = "x-retry-count";
+
+/// Why a handler could not process a delivery.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Failure {
+    /// Worth trying again later.
+    Transient(String),
+    /// Will never succeed, e.g. the payload is malformed.
+    Permanent(String),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Transient(reason) => write!(f, "transient failure: {}", reason),
+            Failure::Permanent(reason) => write!(f, "permanent failure: {}", reason),
+        }
+    }
+}
+
+/// What [`RetryPolicy::fail`] did with a delivery.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FailureAction {
+    /// Republished to a retry queue, to come back after `delay`.
+    Retried { attempt: u32, delay: Duration },
+    /// Requeued as is, as the broker didn't take its retry.
+    Requeued { outcome: PublishOutcome },
+    /// Rejected into the parking lot.
+    Parked,
+}
+
+impl fmt::Display for FailureAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailureAction::Retried { attempt, delay } => {
+                write!(f, "retry #{} in {:?}", attempt, delay)
+            }
+            FailureAction::Requeued { outcome } => write!(f, "requeued, retry {}", outcome),
+            FailureAction::Parked => write!(f, "parked"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub queue: String,
+    pub delays: Vec<Duration>,
+}
+
+impl RetryPolicy {
+    pub fn new(queue: &str, delays: Vec<Duration>) -> Self {
+        Self {
+            queue: queue.to_string(),
+            delays,
+        }
+    }
+
+    pub fn dead_letter_exchange(&self) -> String {
+        format!("{}.dlx", self.queue)
+    }
+
+    pub fn parking_lot(&self) -> String {
+        format!("{}.parking-lot", self.queue)
+    }
+
+    pub fn retry_queue(&self, delay: Duration) -> String {
+        format!("{}.retry.{}", self.queue, delay.as_millis())
+    }
+
+    /// Total number of times a message is handled before it is parked.
+    pub fn max_attempts(&self) -> u32 {
+        self.delays.len() as u32 + 1
+    }
+
+    /// Arguments the work queue must be declared with.
+    pub fn queue_arguments(&self) -> FieldTable {
+        arguments(vec![(
+            "x-dead-letter-exchange",
+            AMQPValue::LongString(self.dead_letter_exchange().into()),
+        )])
+    }
+
+    /// Declares the work queue with `options`, along with the dead-letter exchange,
+    /// the parking lot and the retry queues.
+    pub async fn declare(&self, channel: &Channel, options: QueueDeclareOptions) -> Result<()> {
+        self.declare_with_arguments(channel, options, FieldTable::default())
+            .await
+    }
+
+    /// Like [`declare`](Self::declare), with extra work queue arguments such as a
+    /// max length. The policy's own arguments take precedence.
+    pub async fn declare_with_arguments(
+        &self,
+        channel: &Channel,
+        options: QueueDeclareOptions,
+        mut queue_arguments: FieldTable,
+    ) -> Result<()> {
+        let durable = QueueDeclareOptions {
+            durable: true,
+            ..Default::default()
+        };
+
+        channel
+            .exchange_declare(
+                &self.dead_letter_exchange(),
+                ExchangeKind::Fanout,
+                ExchangeDeclareOptions {
+                    durable: true,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        channel
+            .queue_declare(&self.parking_lot(), durable, FieldTable::default())
+            .await?;
+        channel
+            .queue_bind(
+                &self.parking_lot(),
+                &self.dead_letter_exchange(),
+                "",
+                QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+
+        for delay in &self.delays {
+            let retry_arguments = arguments(vec![
+                (
+                    "x-message-ttl",
+                    AMQPValue::LongLongInt(delay.as_millis() as i64),
+                ),
+                ("x-dead-letter-exchange", AMQPValue::LongString("".into())),
+                (
+                    "x-dead-letter-routing-key",
+                    AMQPValue::LongString(self.queue.clone().into()),
+                ),
+            ]);
+            channel
+                .queue_declare(&self.retry_queue(*delay), durable, retry_arguments)
+                .await?;
+        }
+
+        for (name, value) in self.queue_arguments().inner() {
+            queue_arguments.insert(name.clone(), value.clone());
+        }
+        channel
+            .queue_declare(&self.queue, options, queue_arguments)
+            .await?;
+        Ok(())
+    }
+
+    /// Number of times the delivery has already been retried.
+    pub fn retry_count(properties: &BasicProperties) -> u32 {
+        header_int(properties, RETRY_COUNT_HEADER)
+            .unwrap_or(0)
+            .max(0) as u32
+    }
+
+    /// Settles a delivery whose handler failed: schedules a retry through `publisher`
+    /// when the failure is transient and tiers are left, parks it otherwise.
+    ///
+    /// `publisher` needs confirms on, and `mandatory` left on: only a retry the broker
+    /// acked lets the original go. The retry is the opened body, so a publisher with a
+    /// keyring reseals it, with [`Publisher::with_delivery_routing_key`] set to the
+    /// work queue it comes back to.
+    pub async fn fail<T>(
+        &self,
+        publisher: &Publisher,
+        delivery: &Delivery<T>,
+        failure: &Failure,
+    ) -> Result<FailureAction> {
+        let retries = Self::retry_count(&delivery.properties);
+        let delay = match failure {
+            Failure::Transient(_) => self.delays.get(retries as usize).copied(),
+            Failure::Permanent(_) => None,
+        };
+
+        match delay {
+            Some(delay) => {
+                let properties = with_header(
+                    delivery.properties.clone(),
+                    RETRY_COUNT_HEADER,
+                    AMQPValue::LongLongInt(retries as i64 + 1),
+                );
+                let outcome = publisher
+                    .publish(
+                        "",
+                        &self.retry_queue(delay),
+                        delivery.body().to_vec(),
+                        properties,
+                    )
+                    .await?;
+                if outcome != PublishOutcome::Ack {
+                    delivery
+                        .nack(BasicNackOptions {
+                            requeue: true,
+                            ..Default::default()
+                        })
+                        .await?;
+                    return Ok(FailureAction::Requeued { outcome });
+                }
+                delivery.ack(BasicAckOptions::default()).await?;
+                Ok(FailureAction::Retried {
+                    attempt: retries + 1,
+                    delay,
+                })
+            }
+            None => {
+                delivery
+                    .reject(BasicRejectOptions { requeue: false })
+                    .await?;
+                Ok(FailureAction::Parked)
+            }
+        }
+    }
+}
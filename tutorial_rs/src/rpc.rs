@@ -0,0 +1,526 @@
+//! Typed request/reply over AMQP.
+//!
+//! Requests are encoded with the client's [`ContentType`], JSON by default, and the
+//! server replies in the same one (JSON for raw requests). An [`RpcClient`] receives
+//! every reply on a single consumer, either through RabbitMQ's direct reply-to or
+//! an exclusive reply queue (see [`ReplyMode`]), and keeps a map of pending calls
+//! keyed by correlation id, so any number of concurrent calls can share its channel. The
+//! server side implements [`RpcServer`] and hands a consumer to [`serve`].
+//!
+//! Calls made with a timeout carry it to the server twice: as the message
+//! `expiration`, so the broker drops requests that waited too long in the queue,
+//! and as an absolute [`DEADLINE_HEADER`], so the server skips requests whose caller
+//! has given up already. Failures on the server side come back as a [`RemoteError`]
+//! reply marked with the [`ERROR_HEADER`].
+//!
+//! Each call runs in a span whose trace context goes along with the request, so the
+//! server handles it in the same trace (see [`telemetry`](crate::telemetry)).
+//!
+//! Requests and replies both go out through a [`Publisher`], so requests get a
+//! `message_id` too. With a [`Keyring`] on both sides, requests and replies are
+//! sealed, and anything that doesn't open is rejected by the server or fails the
+//! call on the client.
+
+use crate::{
+    codec::{Codec, CodecError, ContentType},
+    compression::MAX_DECOMPRESSED,
+    envelope::{EnvelopeError, Keyring},
+    headers::{header_bool, header_int, with_header},
+    telemetry::TraceContext,
+    Consumer, Publisher,
+};
+use futures::{future::BoxFuture, StreamExt};
+use lapin::{
+    message,
+    options::{BasicAckOptions, BasicConsumeOptions, BasicRejectOptions, QueueDeclareOptions},
+    types::{AMQPValue, FieldTable},
+    BasicProperties, Channel, Result,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    fmt,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::oneshot;
+use tracing::{field, info, info_span, warn, Instrument};
+use uuid::Uuid;
+
+/// Milliseconds since the Unix epoch after which the caller stops waiting.
+pub const DEADLINE_HEADER: &str = "x-deadline";
+/// Set on replies whose payload is a [`RemoteError`].
+pub const ERROR_HEADER: &str = "x-rpc-error";
+
+/// Pseudo-queue the broker routes replies through straight to the consumer.
+pub const DIRECT_REPLY_TO: &str = "amq.rabbitmq.reply-to";
+
+type Pending = Arc<Mutex<HashMap<String, oneshot::Sender<message::Delivery>>>>;
+
+/// Where an [`RpcClient`] receives its replies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReplyMode {
+    /// RabbitMQ's direct reply-to: no queue is declared at all.
+    #[default]
+    Direct,
+    /// An exclusive, server-named queue declared for the client.
+    Queue,
+}
+
+/// Error returned by the server instead of a response.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteError {
+    /// Machine readable kind, e.g. `bad_request`.
+    pub code: String,
+    pub message: String,
+}
+
+impl RemoteError {
+    pub fn new(code: &str, message: impl fmt::Display) -> Self {
+        Self {
+            code: code.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for RemoteError {}
+
+/// Why an RPC call failed.
+#[derive(Debug)]
+pub enum RpcError {
+    Amqp(lapin::Error),
+    /// The request could not be encoded or the reply could not be decoded.
+    Codec(CodecError),
+    /// The request could not be sealed or the reply could not be opened.
+    Envelope(EnvelopeError),
+    /// The reply consumer stopped before the reply arrived.
+    Disconnected,
+    /// No reply arrived before the deadline.
+    Timeout(Duration),
+    /// The server replied with an error.
+    Remote(RemoteError),
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Amqp(error) => write!(f, "AMQP error: {}", error),
+            RpcError::Codec(error) => write!(f, "codec error: {}", error),
+            RpcError::Envelope(error) => write!(f, "envelope error: {}", error),
+            RpcError::Disconnected => write!(f, "reply consumer disconnected"),
+            RpcError::Timeout(timeout) => write!(f, "no reply after {:?}", timeout),
+            RpcError::Remote(error) => write!(f, "server error: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+impl From<lapin::Error> for RpcError {
+    fn from(error: lapin::Error) -> Self {
+        RpcError::Amqp(error)
+    }
+}
+
+impl From<CodecError> for RpcError {
+    fn from(error: CodecError) -> Self {
+        RpcError::Codec(error)
+    }
+}
+
+impl From<EnvelopeError> for RpcError {
+    fn from(error: EnvelopeError) -> Self {
+        RpcError::Envelope(error)
+    }
+}
+
+/// Client for a procedure served on `routing_key` through the default exchange.
+pub struct RpcClient<Req, Resp> {
+    publisher: Publisher,
+    routing_key: String,
+    reply_to: String,
+    timeout: Option<Duration>,
+    content_type: ContentType,
+    keyring: Option<Arc<Keyring>>,
+    pending: Pending,
+    _types: PhantomData<fn(Req) -> Resp>,
+}
+
+impl<Req: Serialize, Resp: DeserializeOwned> RpcClient<Req, Resp> {
+    /// Sets up where replies come back according to `reply_mode` and starts routing
+    /// them to pending calls.
+    pub async fn new(channel: Channel, routing_key: &str, reply_mode: ReplyMode) -> Result<Self> {
+        let reply_to = match reply_mode {
+            ReplyMode::Direct => DIRECT_REPLY_TO.to_string(),
+            ReplyMode::Queue => channel
+                .queue_declare(
+                    "",
+                    QueueDeclareOptions {
+                        exclusive: true,
+                        ..Default::default()
+                    },
+                    FieldTable::default(),
+                )
+                .await?
+                .name()
+                .to_string(),
+        };
+        // Direct reply-to requires consuming before the first publish, with no-ack
+        let consumer = Consumer::<Vec<u8>>::start_with_options(
+            &channel,
+            &reply_to,
+            0,
+            BasicConsumeOptions {
+                no_ack: true,
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        let pending = Pending::default();
+        tokio::spawn(dispatch(consumer, pending.clone()));
+        // An unroutable request times out like one nobody answers
+        let publisher = Publisher::new(channel, false).await?.with_mandatory(false);
+
+        Ok(Self {
+            publisher,
+            routing_key: routing_key.to_string(),
+            reply_to,
+            timeout: None,
+            content_type: ContentType::Json,
+            keyring: None,
+            pending,
+            _types: PhantomData,
+        })
+    }
+
+    /// Makes every call give up after `timeout`, and tells the server so.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Encodes requests with `content_type` instead of JSON.
+    pub fn with_content_type(mut self, content_type: ContentType) -> Self {
+        self.content_type = content_type;
+        self
+    }
+
+    /// Seals requests with `keyring` and only accepts replies that open with it.
+    pub fn with_keyring(mut self, keyring: Option<Arc<Keyring>>) -> Self {
+        self.publisher = self.publisher.with_keyring(keyring.clone());
+        self.keyring = keyring;
+        self
+    }
+
+    pub fn reply_to(&self) -> &str {
+        &self.reply_to
+    }
+
+    /// Sends `request` and waits for its reply, or until the timeout elapses.
+    ///
+    /// Dropping the returned future cancels the call: a late reply is discarded.
+    pub async fn call(&self, request: &Req) -> std::result::Result<Resp, RpcError> {
+        let payload = self.content_type.encode(request)?;
+        let correlation_id = Uuid::new_v4().to_string();
+        let span = info_span!(
+            "rpc_call",
+            routing_key = self.routing_key.as_str(),
+            correlation_id = correlation_id.as_str(),
+            trace_id = field::Empty,
+            span_id = field::Empty,
+        );
+        let context = TraceContext::start(&span, TraceContext::current().as_ref());
+        context
+            .scope(self.call_in_span(payload, correlation_id))
+            .instrument(span)
+            .await
+    }
+
+    async fn call_in_span(
+        &self,
+        payload: Vec<u8>,
+        correlation_id: String,
+    ) -> std::result::Result<Resp, RpcError> {
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(correlation_id.clone(), sender);
+        let _guard = PendingGuard {
+            pending: &self.pending,
+            correlation_id: &correlation_id,
+        };
+
+        self.publish(payload, &correlation_id).await?;
+
+        let reply = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, receiver)
+                .await
+                .map_err(|_| RpcError::Timeout(timeout))?,
+            None => receiver.await,
+        };
+        let reply = reply.map_err(|_| RpcError::Disconnected)?;
+        let body = match &self.keyring {
+            Some(keyring) => Cow::Owned(keyring.open(
+                reply.routing_key.as_str(),
+                &reply.data,
+                &reply.properties,
+            )?),
+            None => Cow::Borrowed(&reply.data),
+        };
+
+        if header_bool(&reply.properties, ERROR_HEADER) {
+            return Err(RpcError::Remote(ContentType::decode_message(
+                &reply.properties,
+                &body,
+                MAX_DECOMPRESSED,
+            )?));
+        }
+        Ok(ContentType::decode_message(
+            &reply.properties,
+            &body,
+            MAX_DECOMPRESSED,
+        )?)
+    }
+
+    async fn publish(&self, payload: Vec<u8>, correlation_id: &str) -> Result<()> {
+        let mut properties = self
+            .content_type
+            .properties(BasicProperties::default())
+            .with_reply_to(self.reply_to.as_str().into())
+            .with_correlation_id(correlation_id.into());
+        if let Some(timeout) = self.timeout {
+            let deadline = now_millis() + timeout.as_millis() as i64;
+            properties = with_header(
+                properties.with_expiration(timeout.as_millis().to_string().into()),
+                DEADLINE_HEADER,
+                AMQPValue::LongLongInt(deadline),
+            );
+        }
+        self.publisher
+            .publish("", &self.routing_key, payload, properties)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Forgets a pending call once it returns, times out or is dropped.
+struct PendingGuard<'a> {
+    pending: &'a Pending,
+    correlation_id: &'a str,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(self.correlation_id);
+    }
+}
+
+/// Hands every reply to the call waiting on its correlation id. Once the consumer
+/// stops, the remaining calls fail with [`RpcError::Disconnected`].
+async fn dispatch(mut consumer: Consumer<Vec<u8>>, pending: Pending) {
+    while let Some(delivery) = consumer.next().await {
+        let delivery = match delivery {
+            Ok(delivery) => delivery,
+            Err(error) => {
+                warn!("Reply consumer failed: {}", error);
+                break;
+            }
+        };
+        let waiting = delivery
+            .properties
+            .correlation_id()
+            .as_ref()
+            .and_then(|id| pending.lock().unwrap().remove(id.as_str()));
+        match waiting {
+            // The caller may have given up already
+            Some(sender) => drop(sender.send(delivery.delivery)),
+            None => warn!("Dropping reply with unknown correlation id"),
+        }
+    }
+    pending.lock().unwrap().clear();
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// A procedure served over AMQP.
+///
+/// Services exposing several procedures typically use an enum as their request type.
+pub trait RpcServer: Send + Sync {
+    type Request: DeserializeOwned;
+    type Response: Serialize;
+
+    fn handle(
+        &self,
+        request: Self::Request,
+    ) -> BoxFuture<'_, std::result::Result<Self::Response, RemoteError>>;
+}
+
+/// Most replies [`serve`] keeps around to answer duplicates with.
+const CACHED_REPLIES: usize = 1024;
+
+/// A reply's payload and properties, short of the correlation id.
+type Reply = (Vec<u8>, BasicProperties);
+
+/// Replies by the dedup key of their request, forgetting the oldest beyond
+/// [`CACHED_REPLIES`].
+#[derive(Default)]
+struct ReplyCache {
+    replies: Mutex<(HashMap<String, Reply>, VecDeque<String>)>,
+}
+
+impl ReplyCache {
+    fn get(&self, key: &str) -> Option<Reply> {
+        self.replies.lock().unwrap().0.get(key).cloned()
+    }
+
+    fn insert(&self, key: String, reply: Reply) {
+        let (replies, order) = &mut *self.replies.lock().unwrap();
+        if replies.insert(key.clone(), reply).is_none() {
+            order.push_back(key);
+        }
+        while order.len() > CACHED_REPLIES {
+            if let Some(oldest) = order.pop_front() {
+                replies.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// The codec replies to a request with `properties` are encoded with: the request's,
+/// unless it is raw or unknown, as neither can carry a response or an error.
+fn reply_codec(properties: &BasicProperties) -> ContentType {
+    match ContentType::of(properties) {
+        Ok(ContentType::Raw) | Err(_) => ContentType::Json,
+        Ok(codec) => codec,
+    }
+}
+
+/// Encodes `response` with `codec`, marking errors with the [`ERROR_HEADER`].
+fn reply<T: Serialize>(
+    codec: ContentType,
+    response: std::result::Result<T, RemoteError>,
+) -> Result<Reply> {
+    let properties = codec.properties(BasicProperties::default());
+    match response {
+        Ok(response) => Ok((codec.encode(&response)?, properties)),
+        Err(error) => {
+            warn!("Replying with {}", error);
+            let properties = with_header(properties, ERROR_HEADER, AMQPValue::Boolean(true));
+            Ok((codec.encode(&error)?, properties))
+        }
+    }
+}
+
+/// Answers every request coming from `consumer` with `server`, replying to the
+/// request's `reply_to` queue with the same correlation id.
+///
+/// Requests past their deadline are acked without a reply. Requests that can't be
+/// decoded get a `bad_request` [`RemoteError`] back. Replies are sealed with the
+/// consumer's keyring, if it has one, and counted in its metrics.
+///
+/// With a [`Dedup`](crate::Dedup) on the consumer, a duplicate request isn't handled
+/// again but answered with the reply to the first one, or with a `duplicate`
+/// [`RemoteError`] once that reply is no longer cached.
+pub async fn serve<S: RpcServer>(server: &S, consumer: Consumer<Vec<u8>>) -> Result<()> {
+    // Looked up here rather than by the consumer, which would skip duplicates silently
+    let dedup = &consumer.dedup().cloned();
+    let consumer = consumer.with_dedup(None);
+    let duplicates = &consumer
+        .metrics()
+        .map(|metrics| metrics.queue(consumer.queue()));
+    let cache = &ReplyCache::default();
+    // Replies whose client went away are dropped rather than returned
+    let publisher = &Publisher::new(consumer.channel().clone(), false)
+        .await?
+        .with_mandatory(false)
+        .with_keyring(consumer.keyring().cloned())
+        .with_metrics(consumer.metrics().cloned());
+    consumer
+        .for_each_concurrent(|delivery| async move {
+            let reply_to = match delivery.properties.reply_to() {
+                Some(reply_to) => reply_to.to_string(),
+                None => {
+                    warn!("Request without `reply_to`, rejecting it");
+                    return delivery.reject(BasicRejectOptions { requeue: false }).await;
+                }
+            };
+            if header_int(&delivery.properties, DEADLINE_HEADER)
+                .is_some_and(|deadline| deadline < now_millis())
+            {
+                warn!("Skipping expired request");
+                return delivery.ack(BasicAckOptions::default()).await;
+            }
+
+            let codec = reply_codec(&delivery.properties);
+            let key = dedup
+                .as_ref()
+                .and_then(|dedup| dedup.key(&delivery.properties, delivery.body()));
+            let duplicate = match (dedup, &key) {
+                (Some(dedup), Some(key)) => dedup.seen(key)?,
+                _ => false,
+            };
+            let (payload, mut properties) = match &key {
+                Some(key) if duplicate => {
+                    info!("Answering duplicate {}", key);
+                    if let Some(duplicates) = duplicates {
+                        duplicates.duplicate();
+                    }
+                    match cache.get(key) {
+                        Some(cached) => cached,
+                        None => reply::<()>(
+                            codec,
+                            Err(RemoteError::new(
+                                "duplicate",
+                                format!("request {} was answered already", key),
+                            )),
+                        )?,
+                    }
+                }
+                _ => {
+                    let response = match delivery.decode() {
+                        Ok(request) => server.handle(request).await,
+                        Err(error) => Err(RemoteError::new("bad_request", error)),
+                    };
+                    let answer = reply(codec, response)?;
+                    if let Some(key) = &key {
+                        cache.insert(key.clone(), answer.clone());
+                    }
+                    answer
+                }
+            };
+            if let Some(correlation_id) = delivery.properties.correlation_id() {
+                properties = properties.with_correlation_id(correlation_id.clone());
+            }
+            publisher
+                .publish("", &reply_to, payload, properties)
+                .await?;
+
+            // Remembered before the ack, like the consumer does, so that a redelivery
+            // isn't handled twice
+            if let (Some(dedup), Some(key)) = (dedup, &key) {
+                if !duplicate {
+                    if let Err(error) = dedup.remember(key) {
+                        warn!("Failed to remember request {}: {}", key, error);
+                    }
+                }
+            }
+            delivery.ack(BasicAckOptions::default()).await
+        })
+        .await
+}
@@ -0,0 +1,217 @@
+//! Recurring tasks on cron schedules, fired by a single leader.
+//!
+//! A [`Schedule`] is read from a TOML or YAML file listing jobs:
+//!
+//! ```toml
+//! [[jobs]]
+//! name = "nightly-report"
+//! cron = "0 2 * * *"
+//! task = "report..."
+//! type = "sleep"
+//! ```
+//!
+//! Cron expressions have the five usual fields, from minute to day of week, or six
+//! with seconds first, and are evaluated in UTC.
+//!
+//! Any number of schedulers can run for availability. Each one declares the same
+//! exclusive queue as a [`LeaderLock`]: the broker lets a single connection own it,
+//! and deletes it when that connection goes away, so exactly one scheduler fires the
+//! jobs while the others stand by to take over.
+
+use crate::topology::Format;
+use chrono::{DateTime, Utc};
+use lapin::{
+    options::QueueDeclareOptions, protocol::AMQPSoftError, types::FieldTable, Channel, Connection,
+    Error, Result,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    fmt, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+#[derive(Debug)]
+pub enum ScheduleError {
+    Io(PathBuf, io::Error),
+    /// The file extension is neither `.toml`, `.yaml` nor `.yml`.
+    UnknownFormat(PathBuf),
+    Invalid(String),
+    InvalidCron {
+        job: String,
+        reason: String,
+    },
+    DuplicateJob(String),
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleError::Io(path, error) => write!(f, "{}: {}", path.display(), error),
+            ScheduleError::UnknownFormat(path) => write!(
+                f,
+                "{}: expected a .toml, .yaml or .yml file",
+                path.display()
+            ),
+            ScheduleError::Invalid(reason) => write!(f, "invalid schedule: {}", reason),
+            ScheduleError::InvalidCron { job, reason } => {
+                write!(f, "invalid cron expression for `{}`: {}", job, reason)
+            }
+            ScheduleError::DuplicateJob(job) => write!(f, "job `{}` is listed twice", job),
+        }
+    }
+}
+
+impl std::error::Error for ScheduleError {}
+
+impl From<ScheduleError> for lapin::Error {
+    fn from(error: ScheduleError) -> Self {
+        let kind = match &error {
+            ScheduleError::Io(_, error) => error.kind(),
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, error.to_string()).into()
+    }
+}
+
+/// A job as written in the schedule file.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct JobSpec {
+    pub name: String,
+    pub cron: String,
+    /// Body of the task to enqueue.
+    pub task: String,
+    /// Type of the task, picking the handler workers run it with.
+    #[serde(rename = "type", default)]
+    pub kind: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScheduleFile {
+    #[serde(default)]
+    jobs: Vec<JobSpec>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub spec: JobSpec,
+    schedule: cron::Schedule,
+}
+
+impl Job {
+    pub fn new(spec: JobSpec) -> std::result::Result<Self, ScheduleError> {
+        // The cron crate wants seconds first
+        let expression = match spec.cron.split_whitespace().count() {
+            5 => format!("0 {}", spec.cron),
+            _ => spec.cron.clone(),
+        };
+        let schedule =
+            cron::Schedule::from_str(&expression).map_err(|error| ScheduleError::InvalidCron {
+                job: spec.name.clone(),
+                reason: error.to_string(),
+            })?;
+        Ok(Self { spec, schedule })
+    }
+
+    /// The first time the job is due strictly after `after`.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.schedule.after(&after).next()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub jobs: Vec<Job>,
+}
+
+impl Schedule {
+    pub fn parse(source: &str, format: Format) -> std::result::Result<Self, ScheduleError> {
+        let file: ScheduleFile = match format {
+            Format::Toml => {
+                toml::from_str(source).map_err(|e| ScheduleError::Invalid(e.to_string()))?
+            }
+            Format::Yaml => {
+                serde_yaml::from_str(source).map_err(|e| ScheduleError::Invalid(e.to_string()))?
+            }
+        };
+        let mut names = HashSet::new();
+        let jobs = file
+            .jobs
+            .into_iter()
+            .map(|spec| {
+                if !names.insert(spec.name.clone()) {
+                    return Err(ScheduleError::DuplicateJob(spec.name));
+                }
+                Job::new(spec)
+            })
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(Self { jobs })
+    }
+
+    /// Reads a schedule file, its format given by the extension.
+    pub fn load(path: &Path) -> std::result::Result<Self, ScheduleError> {
+        let format =
+            Format::from_path(path).ok_or_else(|| ScheduleError::UnknownFormat(path.into()))?;
+        let source =
+            std::fs::read_to_string(path).map_err(|error| ScheduleError::Io(path.into(), error))?;
+        Self::parse(&source, format)
+    }
+
+    /// The next time any job is due strictly after `after`, with every job due then.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<(DateTime<Utc>, Vec<&Job>)> {
+        let due = self
+            .jobs
+            .iter()
+            .filter_map(|job| Some((job.next_after(after)?, job)));
+        let mut next: Option<(DateTime<Utc>, Vec<&Job>)> = None;
+        for (at, job) in due {
+            match &mut next {
+                Some((first, jobs)) if *first == at => jobs.push(job),
+                Some((first, _)) if *first < at => {}
+                _ => next = Some((at, vec![job])),
+            }
+        }
+        next
+    }
+}
+
+/// An exclusive queue held by at most one connection at a time.
+#[derive(Debug, Clone)]
+pub struct LeaderLock {
+    pub queue: String,
+}
+
+impl LeaderLock {
+    pub fn new(queue: &str) -> Self {
+        Self {
+            queue: queue.to_string(),
+        }
+    }
+
+    /// Declares the lock queue on a channel of its own, as a lock held elsewhere
+    /// closes the channel. Returns that channel, which leads for as long as its
+    /// connection stays open, or `None` while another connection holds the lock.
+    pub async fn try_acquire(&self, connection: &Connection) -> Result<Option<Channel>> {
+        let channel = connection.create_channel().await?;
+        let declared = channel
+            .queue_declare(
+                &self.queue,
+                QueueDeclareOptions {
+                    exclusive: true,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await;
+        match declared {
+            Ok(_) => Ok(Some(channel)),
+            Err(Error::ProtocolError(error))
+                if error.get_id() == AMQPSoftError::RESOURCELOCKED.get_id() =>
+            {
+                Ok(None)
+            }
+            Err(error) => Err(error),
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Graceful shutdown on SIGINT/SIGTERM.
+//!
+//! [`ShutdownOpts::listen`] returns a [`Shutdown`] handle that fires on the first
+//! signal. Consumers attached to it stop taking new deliveries, cancel their
+//! consumer tag and drain what is in flight according to the [`DrainPolicy`], and
+//! the [`Supervisor`](crate::Supervisor) then closes the channel and connection.
+
+use clap::Clap;
+use std::{str::FromStr, sync::Arc, time::Duration};
+use tokio::sync::watch;
+use tracing::info;
+
+/// What to do with deliveries still being handled when shutdown starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DrainPolicy {
+    /// Let handlers finish (and ack) until the drain timeout, requeue the rest.
+    Finish,
+    /// Stop handlers right away and requeue their deliveries.
+    Requeue,
+}
+
+impl FromStr for DrainPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "finish" => Ok(DrainPolicy::Finish),
+            "requeue" => Ok(DrainPolicy::Requeue),
+            s => Err(format!("Invalid drain policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Clap)]
+pub struct ShutdownOpts {
+    /// Seconds to wait for in-flight deliveries once a shutdown signal arrives
+    #[clap(long, default_value = "30")]
+    pub drain_timeout: u64,
+    /// What to do with in-flight deliveries on shutdown: `finish` or `requeue`
+    #[clap(long, default_value = "finish")]
+    pub drain_policy: DrainPolicy,
+}
+
+impl ShutdownOpts {
+    /// Creates a [`Shutdown`] that fires on SIGINT or SIGTERM.
+    pub fn listen(&self) -> Shutdown {
+        let shutdown = Shutdown::new(self.drain_policy, Duration::from_secs(self.drain_timeout));
+        let trigger = shutdown.clone();
+        tokio::spawn(async move {
+            signal().await;
+            info!("Shutting down");
+            trigger.trigger();
+        });
+        shutdown
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+    pub policy: DrainPolicy,
+    pub drain_timeout: Duration,
+}
+
+impl Shutdown {
+    pub fn new(policy: DrainPolicy, drain_timeout: Duration) -> Self {
+        let (sender, receiver) = watch::channel(false);
+        Self {
+            sender: Arc::new(sender),
+            receiver,
+            policy,
+            drain_timeout,
+        }
+    }
+
+    pub fn trigger(&self) {
+        let _ = self.sender.send(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Resolves once shutdown has been triggered.
+    pub async fn wait(&self) {
+        let mut receiver = self.receiver.clone();
+        while !*receiver.borrow() {
+            // The sender lives as long as `self`, so this can't fail
+            let _ = receiver.changed().await;
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
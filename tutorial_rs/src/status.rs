@@ -0,0 +1,288 @@
+//! Job status events published by workers as they handle tasks.
+//!
+//! A task is a job identified by its `message_id`. While handling it, workers
+//! publish [`JobEvent`]s to the status exchange, a topic exchange, with the routing
+//! key `<job id>.<state>`: when the job starts, as its handler reports progress, and
+//! once it succeeded or failed. A consumer given [`JobReports`] also reports the
+//! deliveries it settles without a handler: those it can't open fail, and
+//! duplicates are skipped. [`JobWatch`] follows the events of a single job, and a
+//! [`ResultStore`](crate::ResultStore) attached to the [`JobReporter`] keeps them for
+//! later queries.
+
+use crate::{
+    codec::ContentType,
+    consumer::Consumer,
+    publisher::{Message, Publisher},
+    results::ResultStore,
+};
+use futures::StreamExt;
+use lapin::{
+    options::{BasicConsumeOptions, QueueBindOptions, QueueDeclareOptions},
+    types::FieldTable,
+    BasicProperties, Channel, Result,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{fmt, time::SystemTime};
+use tracing::warn;
+
+/// What happened to a job.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum JobStatus {
+    Started {
+        attempt: u32,
+    },
+    Progress {
+        percent: u8,
+    },
+    Succeeded {
+        #[serde(default)]
+        result: Value,
+    },
+    Failed {
+        error: String,
+        /// Whether the job will be attempted again.
+        retrying: bool,
+    },
+    /// Settled without running, e.g. as a duplicate.
+    Skipped {
+        reason: String,
+    },
+}
+
+impl JobStatus {
+    /// The state in routing keys and the results store.
+    pub fn state(&self) -> &'static str {
+        match self {
+            JobStatus::Started { .. } => "started",
+            JobStatus::Progress { .. } => "progress",
+            JobStatus::Succeeded { .. } => "succeeded",
+            JobStatus::Failed { .. } => "failed",
+            JobStatus::Skipped { .. } => "skipped",
+        }
+    }
+
+    /// Whether no event follows this one.
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            JobStatus::Succeeded { .. }
+                | JobStatus::Failed {
+                    retrying: false,
+                    ..
+                }
+                | JobStatus::Skipped { .. }
+        )
+    }
+}
+
+impl fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobStatus::Started { attempt } => write!(f, "started (attempt {})", attempt),
+            JobStatus::Progress { percent } => write!(f, "{}% done", percent),
+            JobStatus::Succeeded {
+                result: Value::Null,
+            } => write!(f, "succeeded"),
+            JobStatus::Succeeded { result } => write!(f, "succeeded: {}", result),
+            JobStatus::Failed {
+                error,
+                retrying: true,
+            } => write!(f, "failed: {}, retrying", error),
+            JobStatus::Failed { error, .. } => write!(f, "failed: {}", error),
+            JobStatus::Skipped { reason } => write!(f, "skipped: {}", reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobEvent {
+    pub job_id: String,
+    /// Type of the task.
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(flatten)]
+    pub status: JobStatus,
+    /// RFC 3339 time the event happened at.
+    pub at: String,
+}
+
+impl JobEvent {
+    /// An event that happens now.
+    pub fn new(job_id: &str, kind: Option<&str>, status: JobStatus) -> Self {
+        Self {
+            job_id: job_id.to_string(),
+            kind: kind.map(String::from),
+            status,
+            at: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        }
+    }
+
+    pub fn routing_key(&self) -> String {
+        format!("{}.{}", self.job_id, self.status.state())
+    }
+}
+
+/// Where the jobs of a consumer are reported: a status exchange, and a results store
+/// if there is one.
+#[derive(Debug, Clone)]
+pub struct JobReports {
+    publisher: Publisher,
+    exchange: String,
+    store: Option<ResultStore>,
+}
+
+impl JobReports {
+    /// Reports through `publisher` to `exchange`.
+    pub fn new(publisher: Publisher, exchange: &str) -> Self {
+        Self {
+            publisher,
+            exchange: exchange.to_string(),
+            store: None,
+        }
+    }
+
+    /// Records every event in `store` as well.
+    pub fn with_store(mut self, store: Option<ResultStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    /// The reporter of the job a delivery with `properties` carries.
+    pub fn reporter(&self, properties: &BasicProperties) -> JobReporter {
+        JobReporter::new(&self.publisher, &self.exchange, properties).with_store(self.store.clone())
+    }
+}
+
+/// Reports on the job a delivery carries.
+#[derive(Debug, Clone)]
+pub struct JobReporter {
+    publisher: Publisher,
+    exchange: String,
+    job_id: Option<String>,
+    kind: Option<String>,
+    store: Option<ResultStore>,
+}
+
+impl JobReporter {
+    /// Reports through `publisher` to `exchange` on the job of a delivery with
+    /// `properties`, or does nothing if it has no `message_id`.
+    pub fn new(publisher: &Publisher, exchange: &str, properties: &BasicProperties) -> Self {
+        Self {
+            publisher: publisher.clone(),
+            exchange: exchange.to_string(),
+            job_id: properties.message_id().as_ref().map(|id| id.to_string()),
+            kind: properties.kind().as_ref().map(|kind| kind.to_string()),
+            store: None,
+        }
+    }
+
+    /// Records every event in `store` as well.
+    pub fn with_store(mut self, store: Option<ResultStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    pub fn job_id(&self) -> Option<&str> {
+        self.job_id.as_deref()
+    }
+
+    /// Publishes `status` and records it. Failures are only logged: reports are
+    /// best effort and never fail the job itself.
+    pub async fn report(&self, status: JobStatus) {
+        let job_id = match &self.job_id {
+            Some(job_id) => job_id,
+            None => return,
+        };
+        let event = JobEvent::new(job_id, self.kind.as_deref(), status);
+        if let Some(store) = &self.store {
+            if let Err(error) = store.record(&event).await {
+                warn!("Failed to record job {}: {}", job_id, error);
+            }
+        }
+        if let Err(error) = self.publish(&event).await {
+            warn!("Failed to report job {}: {}", job_id, error);
+        }
+    }
+
+    pub async fn progress(&self, percent: u8) {
+        self.report(JobStatus::Progress {
+            percent: percent.min(100),
+        })
+        .await
+    }
+
+    async fn publish(&self, event: &JobEvent) -> Result<()> {
+        let message = Message::encode(
+            &self.exchange,
+            &event.routing_key(),
+            &ContentType::Json,
+            event,
+            BasicProperties::default(),
+        )?;
+        self.publisher
+            .publish(
+                &message.exchange,
+                &message.routing_key,
+                message.payload,
+                message.properties,
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+/// The events of a single job, received on an exclusive queue.
+pub struct JobWatch {
+    consumer: Consumer<Vec<u8>>,
+}
+
+impl JobWatch {
+    /// Starts following the events `exchange` receives for `job_id`. Watch before
+    /// publishing the task so no event is missed.
+    pub async fn start(channel: &Channel, exchange: &str, job_id: &str) -> Result<Self> {
+        let queue = channel
+            .queue_declare(
+                "",
+                QueueDeclareOptions {
+                    exclusive: true,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        channel
+            .queue_bind(
+                queue.name().as_str(),
+                exchange,
+                &format!("{}.*", job_id),
+                QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+        let consumer = Consumer::start_with_options(
+            channel,
+            queue.name().as_str(),
+            0,
+            BasicConsumeOptions {
+                no_ack: true,
+                ..Default::default()
+            },
+        )
+        .await?;
+        Ok(Self { consumer })
+    }
+
+    /// The next event of the job, or `None` once the channel is closed. Events that
+    /// don't decode are skipped.
+    pub async fn next(&mut self) -> Result<Option<JobEvent>> {
+        while let Some(delivery) = self.consumer.next().await {
+            match delivery?.decode::<JobEvent>() {
+                Ok(event) => return Ok(Some(event)),
+                Err(error) => warn!("Skipping status event: {}", error),
+            }
+        }
+        Ok(None)
+    }
+}
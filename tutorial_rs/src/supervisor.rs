@@ -0,0 +1,214 @@
+//! Supervised connection that survives broker restarts and network loss.
+//!
+//! Binaries register the declarations they depend on (exchanges, queues, bindings)
+//! and hand their consumer loop to [`Supervisor::run`]. Whenever the loop fails
+//! because the connection went away, the supervisor reconnects with exponential
+//! backoff, re-runs every declaration on the new channel and starts the loop again.
+//!
+//! Errors that reconnecting cannot fix are returned instead (see [`is_fatal`]): the
+//! broker refusing access, or a declaration naming something that doesn't exist or
+//! conflicting with what does, such as a queue declared with other arguments.
+//!
+//! With a [`Shutdown`] attached, a signal stops the reconnection attempts and the
+//! channel and connection are closed cleanly once the loop returns.
+//!
+//! With a [`Health`] attached, every connection whose declarations succeeded is
+//! recorded in it, for the health endpoints to report on.
+
+use crate::{ConnectionOpts, Health, Shutdown};
+use lapin::{protocol::AMQPSoftError, Channel, Connection, Error, Result};
+use rand::Rng;
+use std::{future::Future, pin::Pin, time::Duration};
+use tokio::time::sleep;
+use tracing::warn;
+
+type Declaration =
+    Box<dyn Fn(Channel) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> + Send + Sync>;
+
+/// Exponential backoff with jitter used between reconnection attempts.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+            multiplier: 2.0,
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay before the given (zero based) attempt, randomized between half and the
+    /// full exponential value so that many clients don't reconnect in lockstep.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exp = self.initial.as_secs_f64() * self.multiplier.powi(attempt as i32);
+        let capped = exp.min(self.max.as_secs_f64());
+        let jitter = rand::thread_rng().gen_range(0.5..=1.0);
+        Duration::from_secs_f64(capped * jitter)
+    }
+}
+
+/// Whether `error` would happen again on every reconnection: the broker refused
+/// access, or a declaration failed with `NOT_FOUND` or `PRECONDITION_FAILED`.
+/// Anything else, I/O errors and lost connections included, is transient.
+pub fn is_fatal(error: &Error) -> bool {
+    match error {
+        Error::ProtocolError(error) => [
+            AMQPSoftError::ACCESSREFUSED,
+            AMQPSoftError::NOTFOUND,
+            AMQPSoftError::PRECONDITIONFAILED,
+        ]
+        .iter()
+        .any(|fatal| error.get_id() == fatal.get_id()),
+        _ => false,
+    }
+}
+
+pub struct Supervisor {
+    opts: ConnectionOpts,
+    backoff: Backoff,
+    declarations: Vec<Declaration>,
+    shutdown: Option<Shutdown>,
+    health: Option<Health>,
+}
+
+impl Supervisor {
+    pub fn new(opts: ConnectionOpts) -> Self {
+        Self {
+            opts,
+            backoff: Backoff::default(),
+            declarations: Vec::new(),
+            shutdown: None,
+            health: None,
+        }
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    pub fn with_health(mut self, health: Option<Health>) -> Self {
+        self.health = health;
+        self
+    }
+
+    /// Registers a declaration to run, in registration order, on every new channel.
+    pub fn declare<F, Fut>(mut self, declaration: F) -> Self
+    where
+        F: Fn(Channel) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.declarations
+            .push(Box::new(move |channel| Box::pin(declaration(channel))));
+        self
+    }
+
+    /// Connects, opens a channel and applies the registered declarations, retrying
+    /// transient errors until it succeeds. Returns `None` if shutdown fires first,
+    /// and fatal errors as soon as they happen.
+    pub async fn connect(&self) -> Result<Option<(Connection, Channel)>> {
+        let mut attempt = 0;
+        loop {
+            let connected = match self.until_shutdown(self.try_connect()).await {
+                Some(connected) => connected,
+                None => return Ok(None),
+            };
+            match connected {
+                Ok(connected) => return Ok(Some(connected)),
+                Err(error) if is_fatal(&error) => return Err(error),
+                Err(error) => {
+                    let delay = self.backoff.delay(attempt);
+                    warn!("Connection failed: {}, retrying in {:?}", error, delay);
+                    if self.until_shutdown(sleep(delay)).await.is_none() {
+                        return Ok(None);
+                    }
+                    attempt = attempt.saturating_add(1);
+                }
+            }
+        }
+    }
+
+    /// Runs `future` to completion unless shutdown fires first.
+    async fn until_shutdown<Fut: Future>(&self, future: Fut) -> Option<Fut::Output> {
+        match &self.shutdown {
+            Some(shutdown) => tokio::select! {
+                output = future => Some(output),
+                _ = shutdown.wait() => None,
+            },
+            None => Some(future.await),
+        }
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        self.shutdown.as_ref().is_some_and(Shutdown::is_triggered)
+    }
+
+    async fn try_connect(&self) -> Result<(Connection, Channel)> {
+        let conn = self.opts.connect().await?;
+        let channel = conn.create_channel().await?;
+        for declaration in &self.declarations {
+            declaration(channel.clone()).await?;
+        }
+        if let Some(health) = &self.health {
+            health.connected(&conn, &channel);
+        }
+        Ok((conn, channel))
+    }
+
+    /// Runs `consume` on a supervised channel, starting it again on a fresh connection
+    /// whenever it fails or returns after the connection was lost.
+    ///
+    /// Returns once `consume` finishes while the connection is still up or shutdown
+    /// has been triggered, or with the first fatal error.
+    pub async fn run<F, Fut>(&self, mut consume: F) -> Result<()>
+    where
+        F: FnMut(Channel) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        loop {
+            let (conn, channel) = match self.connect().await? {
+                Some(connected) => connected,
+                None => return Ok(()),
+            };
+            match consume(channel.clone()).await {
+                Ok(()) if conn.status().connected() || self.is_shutting_down() => {
+                    if conn.status().connected() {
+                        channel.close(200, "Shutting down").await?;
+                        conn.close(200, "Shutting down").await?;
+                    }
+                    return Ok(());
+                }
+                Ok(()) => warn!("Connection lost, reconnecting"),
+                Err(error) if is_fatal(&error) => {
+                    if conn.status().connected() {
+                        let _ = conn.close(200, "Shutting down").await;
+                    }
+                    return Err(error);
+                }
+                Err(error) => warn!("Consumer failed: {}, reconnecting", error),
+            }
+            if conn.status().connected() {
+                let _ = conn.close(200, "Reconnecting").await;
+            }
+            if self
+                .until_shutdown(sleep(self.backoff.delay(0)))
+                .await
+                .is_none()
+            {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Dispatching work queue tasks to handlers by kind.
+//!
+//! A task's kind is read from the AMQP `type` property, or from a `type` header for
+//! publishers that can't set properties. A [`TaskRegistry`] maps kinds to
+//! [`TaskHandler`]s, with an optional default for tasks that carry no kind.
+//!
+//! Handlers report a [`Failure`] the same way any other handler does, so workers
+//! settle tasks with their [`RetryPolicy`](crate::RetryPolicy): they ack on success,
+//! retry transient failures after a delay and park permanent ones, tasks of an
+//! unknown kind included. On success they return the job's result, and they can
+//! report progress along the way through the [`JobReporter`] they are given.
+
+use crate::{consumer::Delivery, headers::header_str, retry::Failure, status::JobReporter};
+use futures::future::BoxFuture;
+use lapin::BasicProperties;
+use serde_json::Value;
+use std::{collections::BTreeMap, fmt, sync::Arc};
+
+pub const TASK_TYPE_HEADER: &str = "type";
+
+/// Handles the tasks of one kind.
+pub trait TaskHandler: Send + Sync {
+    fn handle<'a>(
+        &'a self,
+        delivery: &'a Delivery<Vec<u8>>,
+        status: &'a JobReporter,
+    ) -> BoxFuture<'a, std::result::Result<Value, Failure>>;
+}
+
+#[derive(Clone, Default)]
+pub struct TaskRegistry {
+    handlers: BTreeMap<String, Arc<dyn TaskHandler>>,
+    default: Option<String>,
+}
+
+impl TaskRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handles the tasks of kind `kind` with `handler`, replacing any earlier one.
+    pub fn register(mut self, kind: &str, handler: impl TaskHandler + 'static) -> Self {
+        self.handlers.insert(kind.to_string(), Arc::new(handler));
+        self
+    }
+
+    /// Handles the tasks without a kind as if they were of kind `kind`.
+    pub fn with_default(mut self, kind: &str) -> Self {
+        self.default = Some(kind.to_string());
+        self
+    }
+
+    /// The registered kinds, in order.
+    pub fn kinds(&self) -> impl Iterator<Item = &str> {
+        self.handlers.keys().map(String::as_str)
+    }
+
+    /// The kind a task was published with, if any.
+    pub fn task_type(properties: &BasicProperties) -> Option<String> {
+        properties
+            .kind()
+            .as_ref()
+            .map(|kind| kind.to_string())
+            .or_else(|| header_str(properties, TASK_TYPE_HEADER))
+    }
+
+    /// Runs the handler of the delivery's kind. Tasks of an unknown kind, or
+    /// without one when there is no default, fail permanently.
+    pub async fn handle(
+        &self,
+        delivery: &Delivery<Vec<u8>>,
+        status: &JobReporter,
+    ) -> std::result::Result<Value, Failure> {
+        let kind = match Self::task_type(&delivery.properties).or_else(|| self.default.clone()) {
+            Some(kind) => kind,
+            None => return Err(Failure::Permanent("task has no type".to_string())),
+        };
+        match self.handlers.get(&kind) {
+            Some(handler) => handler.handle(delivery, status).await,
+            None => Err(Failure::Permanent(format!(
+                "no handler for task type `{}`",
+                kind
+            ))),
+        }
+    }
+}
+
+impl fmt::Debug for TaskRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaskRegistry")
+            .field("kinds", &self.kinds().collect::<Vec<_>>())
+            .field("default", &self.default)
+            .finish()
+    }
+}
@@ -0,0 +1,391 @@
+//! Structured logging and trace context propagation.
+//!
+//! [`TelemetryOpts::init`] sends `tracing` events to stdout as text, pretty or JSON
+//! lines, filtered with `RUST_LOG` (`info` by default). Every publish and every
+//! delivery handled by a [`Consumer`] runs in a span of its own, carrying the W3C
+//! trace context: publishers inject it into the message `headers` as
+//! [`TRACEPARENT_HEADER`] and [`TRACESTATE_HEADER`], and consumers extract it, so a
+//! message and whatever its handler publishes share the trace id of the publisher.
+//!
+//! Built with the `otlp` feature, `--otlp-endpoint` also exports the spans over
+//! OTLP/gRPC, with the ids propagated in the headers taken from the exported spans.
+//!
+//! [`Consumer`]: crate::Consumer
+
+use crate::headers::{header_str, with_header};
+use clap::Clap;
+use lapin::{message, types::AMQPValue, BasicProperties, Result};
+use std::{
+    fmt,
+    future::Future,
+    io::{self, IsTerminal},
+    str::FromStr,
+};
+use tracing::{field, info_span, Span, Subscriber};
+use tracing_subscriber::{
+    layer::SubscriberExt, registry::LookupSpan, util::SubscriberInitExt, EnvFilter, Layer,
+};
+
+/// Header carrying the W3C `traceparent` of the span that published the message.
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+/// Header carrying the W3C `tracestate` vendors attach to the trace.
+pub const TRACESTATE_HEADER: &str = "tracestate";
+
+tokio::task_local! {
+    static CURRENT: TraceContext;
+}
+
+/// How events are written to stdout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One line per event.
+    Text,
+    /// Several indented lines per event, with the source location.
+    Pretty,
+    /// One JSON object per event, with the fields of its span.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            s => Err(format!("Invalid log format: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Clap)]
+pub struct TelemetryOpts {
+    /// Log output: `text`, `pretty` or `json`, filtered with `RUST_LOG`
+    #[clap(long, default_value = "text")]
+    pub log_format: LogFormat,
+    /// Export spans over OTLP/gRPC to this endpoint, e.g. `http://localhost:4317`
+    /// (requires the `otlp` feature)
+    #[clap(long)]
+    pub otlp_endpoint: Option<String>,
+}
+
+impl TelemetryOpts {
+    /// Installs the global subscriber, naming exported spans after `service`.
+    ///
+    /// The returned guard flushes the spans still waiting to be exported when dropped.
+    pub fn init(&self, service: &str) -> Result<Telemetry> {
+        let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+        let subscriber = tracing_subscriber::registry()
+            .with(filter)
+            .with(self.fmt_layer());
+
+        #[cfg(feature = "otlp")]
+        {
+            let (layer, provider) = match &self.otlp_endpoint {
+                Some(endpoint) => {
+                    let (layer, provider) = otlp::layer(endpoint, service)?;
+                    (Some(layer), Some(provider))
+                }
+                None => (None, None),
+            };
+            subscriber
+                .with(layer)
+                .try_init()
+                .map_err(io::Error::other)?;
+            Ok(Telemetry { provider })
+        }
+
+        #[cfg(not(feature = "otlp"))]
+        {
+            let _ = service;
+            if self.otlp_endpoint.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "--otlp-endpoint requires building with the `otlp` feature",
+                )
+                .into());
+            }
+            subscriber.try_init().map_err(io::Error::other)?;
+            Ok(Telemetry {})
+        }
+    }
+
+    fn fmt_layer<S>(&self) -> Box<dyn Layer<S> + Send + Sync>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let layer = tracing_subscriber::fmt::layer()
+            .with_writer(io::stdout)
+            .with_ansi(io::stdout().is_terminal());
+        match self.log_format {
+            LogFormat::Text => layer.boxed(),
+            LogFormat::Pretty => layer.pretty().boxed(),
+            LogFormat::Json => layer.json().with_span_list(false).boxed(),
+        }
+    }
+}
+
+/// Guard returned by [`TelemetryOpts::init`].
+#[derive(Debug)]
+#[must_use]
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+#[cfg(feature = "otlp")]
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            if let Err(error) = provider.shutdown() {
+                eprintln!("failed to flush spans: {}", error);
+            }
+        }
+    }
+}
+
+/// W3C trace context of a span: the trace it belongs to and its own id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub sampled: bool,
+    /// Opaque `tracestate`, passed along untouched.
+    pub state: Option<String>,
+}
+
+impl TraceContext {
+    /// The first span of a new trace.
+    pub fn root() -> Self {
+        Self {
+            trace_id: rand::random::<u128>().max(1),
+            span_id: rand::random::<u64>().max(1),
+            sampled: true,
+            state: None,
+        }
+    }
+
+    /// A new span in the same trace.
+    pub fn child(&self) -> Self {
+        Self {
+            span_id: rand::random::<u64>().max(1),
+            ..self.clone()
+        }
+    }
+
+    /// Parses a `traceparent` header value, and the `tracestate` that came with it.
+    pub fn parse(traceparent: &str, tracestate: Option<&str>) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let version = hex(parts.next()?, 2)?;
+        let trace_id = hex(parts.next()?, 32)?;
+        let span_id = hex(parts.next()?, 16)?;
+        let flags = hex(parts.next()?, 2)?;
+        // Later versions may append fields, version 00 may not
+        if version == "ff" || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+
+        Some(Self {
+            trace_id: u128::from_str_radix(trace_id, 16)
+                .ok()
+                .filter(|id| *id != 0)?,
+            span_id: u64::from_str_radix(span_id, 16)
+                .ok()
+                .filter(|id| *id != 0)?,
+            sampled: u8::from_str_radix(flags, 16).ok()? & 1 == 1,
+            state: tracestate
+                .map(str::trim)
+                .filter(|state| !state.is_empty())
+                .map(String::from),
+        })
+    }
+
+    pub fn trace_id_hex(&self) -> String {
+        format!("{:032x}", self.trace_id)
+    }
+
+    pub fn span_id_hex(&self) -> String {
+        format!("{:016x}", self.span_id)
+    }
+
+    /// The context a message was published with, if it carries a valid one.
+    pub fn extract(properties: &BasicProperties) -> Option<Self> {
+        let traceparent = header_str(properties, TRACEPARENT_HEADER)?;
+        let tracestate = header_str(properties, TRACESTATE_HEADER);
+        Self::parse(&traceparent, tracestate.as_deref())
+    }
+
+    /// Adds the headers propagating this context to `properties`.
+    pub fn inject(&self, properties: BasicProperties) -> BasicProperties {
+        let properties = with_header(
+            properties,
+            TRACEPARENT_HEADER,
+            AMQPValue::LongString(self.to_string().into()),
+        );
+        match &self.state {
+            Some(state) => with_header(
+                properties,
+                TRACESTATE_HEADER,
+                AMQPValue::LongString(state.as_str().into()),
+            ),
+            None => properties,
+        }
+    }
+
+    /// The context of the delivery or call being handled by the current task.
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(Clone::clone).ok()
+    }
+
+    /// Runs `future` with this context as the current one.
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT.scope(self, future).await
+    }
+
+    /// The context of `span`, child of `parent` or the root of a new trace, which is
+    /// recorded in its `trace_id` and `span_id` fields.
+    pub fn start(span: &Span, parent: Option<&TraceContext>) -> Self {
+        let context = otlp::start(span, parent).unwrap_or_else(|| match parent {
+            Some(parent) => parent.child(),
+            None => Self::root(),
+        });
+        span.record("trace_id", field::display(context.trace_id_hex()));
+        span.record("span_id", field::display(context.span_id_hex()));
+        context
+    }
+}
+
+impl fmt::Display for TraceContext {
+    /// Formats the context as a version 00 `traceparent`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id, self.span_id, self.sampled as u8
+        )
+    }
+}
+
+/// Starts the span of a message published to `exchange`, continuing the current
+/// trace if there is one, and returns it with the context to inject.
+pub fn publish_span(exchange: &str, routing_key: &str) -> (Span, TraceContext) {
+    let span = info_span!(
+        "publish",
+        exchange,
+        routing_key,
+        trace_id = field::Empty,
+        span_id = field::Empty,
+    );
+    let context = TraceContext::start(&span, TraceContext::current().as_ref());
+    (span, context)
+}
+
+/// Starts the span handling `delivery` from `queue`, continuing the trace of its
+/// publisher if it carries one, and returns it with the context to run it in.
+pub fn delivery_span(queue: &str, delivery: &message::Delivery) -> (Span, TraceContext) {
+    let span = info_span!(
+        "delivery",
+        queue,
+        routing_key = delivery.routing_key.as_str(),
+        delivery_tag = delivery.delivery_tag,
+        redelivered = delivery.redelivered,
+        trace_id = field::Empty,
+        span_id = field::Empty,
+    );
+    let parent = TraceContext::extract(&delivery.properties);
+    let context = TraceContext::start(&span, parent.as_ref());
+    (span, context)
+}
+
+fn hex(s: &str, len: usize) -> Option<&str> {
+    let lower_hex = |b: u8| b.is_ascii_digit() || (b'a'..=b'f').contains(&b);
+    (s.len() == len && s.bytes().all(lower_hex)).then_some(s)
+}
+
+#[cfg(not(feature = "otlp"))]
+mod otlp {
+    use super::TraceContext;
+    use tracing::Span;
+
+    /// Spans aren't exported, so their ids are up to [`TraceContext::start`].
+    pub(super) fn start(_span: &Span, _parent: Option<&TraceContext>) -> Option<TraceContext> {
+        None
+    }
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use super::TraceContext;
+    use opentelemetry::{
+        trace::{
+            SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState, TracerProvider,
+        },
+        Context,
+    };
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+    use std::io;
+    use tracing::{Span, Subscriber};
+    use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+    use tracing_subscriber::registry::LookupSpan;
+
+    pub(super) fn layer<S>(
+        endpoint: &str,
+        service: &str,
+    ) -> io::Result<(
+        OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>,
+        SdkTracerProvider,
+    )>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let exporter = SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()
+            .map_err(io::Error::other)?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(service.to_string())
+                    .build(),
+            )
+            .build();
+        let tracer = provider.tracer(service.to_string());
+        Ok((tracing_opentelemetry::layer().with_tracer(tracer), provider))
+    }
+
+    /// Parents `span` to `parent` and reads back the ids it will be exported with,
+    /// or `None` when it isn't exported.
+    pub(super) fn start(span: &Span, parent: Option<&TraceContext>) -> Option<TraceContext> {
+        if let Some(parent) = parent {
+            let flags = if parent.sampled {
+                TraceFlags::SAMPLED
+            } else {
+                TraceFlags::default()
+            };
+            let remote = SpanContext::new(
+                TraceId::from_bytes(parent.trace_id.to_be_bytes()),
+                SpanId::from_bytes(parent.span_id.to_be_bytes()),
+                flags,
+                true,
+                TraceState::default(),
+            );
+            let _ = span.set_parent(Context::new().with_remote_span_context(remote));
+        }
+
+        let context = span.context();
+        let exported = context.span().span_context().clone();
+        if !exported.is_valid() {
+            return None;
+        }
+        Some(TraceContext {
+            trace_id: u128::from_be_bytes(exported.trace_id().to_bytes()),
+            span_id: u64::from_be_bytes(exported.span_id().to_bytes()),
+            sampled: exported.is_sampled(),
+            state: parent.and_then(|parent| parent.state.clone()),
+        })
+    }
+}
@@ -0,0 +1,223 @@
+//! Topic exchange routing, evaluated locally.
+//!
+//! Follows RabbitMQ's semantics: keys are split into words on `.`, `*` in a
+//! binding key matches exactly one word and `#` matches zero or more words. An
+//! empty key has no words at all, so it is only matched by an empty key or `#`.
+//!
+//! [`BindingKey`] matches a single binding, [`TopicIndex`] looks up many of them
+//! at once through a trie of their words. Both remember which (pattern word, key
+//! word) pairs they already tried, so runs of `#` cost polynomial time.
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt,
+    str::FromStr,
+};
+
+/// Longest key AMQP can carry, as it travels in a short string.
+pub const MAX_KEY_LEN: usize = 255;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TopicError {
+    TooLong(usize),
+    /// A word mixes `*` or `#` with other characters, e.g. `kern*`.
+    InvalidWord(String),
+}
+
+impl fmt::Display for TopicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TopicError::TooLong(len) => {
+                write!(
+                    f,
+                    "key is {} bytes long, the maximum is {}",
+                    len, MAX_KEY_LEN
+                )
+            }
+            TopicError::InvalidWord(word) => write!(
+                f,
+                "invalid word `{}`: wildcards must make up a whole word",
+                word
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TopicError {}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Word {
+    Literal(String),
+    /// `*`
+    One,
+    /// `#`
+    Any,
+}
+
+/// A validated topic binding key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BindingKey {
+    key: String,
+    words: Vec<Word>,
+}
+
+impl BindingKey {
+    pub fn parse(key: &str) -> Result<Self, TopicError> {
+        if key.len() > MAX_KEY_LEN {
+            return Err(TopicError::TooLong(key.len()));
+        }
+        let mut words = split(key)
+            .into_iter()
+            .map(|word| match word {
+                "*" => Ok(Word::One),
+                "#" => Ok(Word::Any),
+                word if word.contains(&['*', '#'][..]) => {
+                    Err(TopicError::InvalidWord(word.to_string()))
+                }
+                word => Ok(Word::Literal(word.to_string())),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        // `#.#` matches the same keys as `#`
+        words.dedup_by(|word, previous| *word == Word::Any && *previous == Word::Any);
+        Ok(Self {
+            key: key.to_string(),
+            words,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.key
+    }
+
+    pub fn matches(&self, routing_key: &str) -> bool {
+        matches(&self.words, &split(routing_key))
+    }
+}
+
+impl FromStr for BindingKey {
+    type Err = TopicError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for BindingKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.key)
+    }
+}
+
+/// Words of a key, none for the empty key.
+fn split(key: &str) -> Vec<&str> {
+    if key.is_empty() {
+        Vec::new()
+    } else {
+        key.split('.').collect()
+    }
+}
+
+/// `matched[i][j]` tells whether `pattern[i..]` matches `words[j..]`.
+fn matches(pattern: &[Word], words: &[&str]) -> bool {
+    let (p, w) = (pattern.len(), words.len());
+    let mut matched = vec![vec![false; w + 1]; p + 1];
+    matched[p][w] = true;
+    for i in (0..p).rev() {
+        for j in (0..=w).rev() {
+            matched[i][j] = match &pattern[i] {
+                Word::Any => matched[i + 1][j] || (j < w && matched[i][j + 1]),
+                Word::One => j < w && matched[i + 1][j + 1],
+                Word::Literal(literal) => j < w && words[j] == literal && matched[i + 1][j + 1],
+            };
+        }
+    }
+    matched[0][0]
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    children: HashMap<Word, Node>,
+    /// Indices of the bindings ending at this node.
+    bindings: Vec<usize>,
+}
+
+impl Node {
+    /// Adds the bindings below this node matching `words[at..]`, skipping the
+    /// (node, position) pairs in `seen`, which were already explored.
+    fn collect(
+        &self,
+        words: &[&str],
+        at: usize,
+        seen: &mut HashSet<(*const Node, usize)>,
+        found: &mut BTreeSet<usize>,
+    ) {
+        if !seen.insert((self as *const Node, at)) {
+            return;
+        }
+        if at == words.len() {
+            found.extend(&self.bindings);
+        } else {
+            if let Some(child) = self.children.get(&Word::Literal(words[at].to_string())) {
+                child.collect(words, at + 1, seen, found);
+            }
+            if let Some(child) = self.children.get(&Word::One) {
+                child.collect(words, at + 1, seen, found);
+            }
+        }
+        if let Some(child) = self.children.get(&Word::Any) {
+            for skip in at..=words.len() {
+                child.collect(words, skip, seen, found);
+            }
+        }
+    }
+}
+
+/// Bindings indexed by the words of their keys, to find every binding a routing
+/// key matches without testing them one by one.
+#[derive(Debug)]
+pub struct TopicIndex<T> {
+    root: Node,
+    bindings: Vec<(BindingKey, T)>,
+}
+
+impl<T> Default for TopicIndex<T> {
+    fn default() -> Self {
+        Self {
+            root: Node::default(),
+            bindings: Vec::new(),
+        }
+    }
+}
+
+impl<T> TopicIndex<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, key: BindingKey, value: T) {
+        let node = key.words.iter().fold(&mut self.root, |node, word| {
+            node.children.entry(word.clone()).or_default()
+        });
+        node.bindings.push(self.bindings.len());
+        self.bindings.push((key, value));
+    }
+
+    pub fn len(&self) -> usize {
+        self.bindings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bindings.is_empty()
+    }
+
+    /// Bindings matching `routing_key`, in insertion order.
+    pub fn matches(&self, routing_key: &str) -> Vec<&(BindingKey, T)> {
+        let mut found = BTreeSet::new();
+        self.root
+            .collect(&split(routing_key), 0, &mut HashSet::new(), &mut found);
+        found
+            .into_iter()
+            .map(|index| &self.bindings[index])
+            .collect()
+    }
+}
@@ -0,0 +1,454 @@
+//! Declarative exchange, queue and binding layout, read from TOML or YAML.
+//!
+//! The tutorials take their names from a [`Topology`] instead of hard coding them:
+//! each binary looks up the entries it needs by role (e.g. the `hello` queue) in the
+//! built-in `topology.toml`, or in the file given with `--topology`, so a renamed or
+//! re-tuned queue only has to change in one place. The `topology` binary declares
+//! the same file on the broker.
+
+use crate::headers::arguments;
+use clap::Clap;
+use lapin::{
+    options::{ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions},
+    types::{AMQPValue, FieldTable},
+    Channel, ExchangeKind, Result,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Layout the tutorials use when no `--topology` file is given.
+pub const TUTORIALS: &str = include_str!("../topology.toml");
+
+#[derive(Debug)]
+pub enum TopologyError {
+    Io(PathBuf, io::Error),
+    /// The file extension is neither `.toml`, `.yaml` nor `.yml`.
+    UnknownFormat(PathBuf),
+    Invalid(String),
+    /// No entry has the role a tutorial asked for.
+    Missing {
+        kind: &'static str,
+        role: String,
+    },
+}
+
+impl fmt::Display for TopologyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TopologyError::Io(path, error) => write!(f, "{}: {}", path.display(), error),
+            TopologyError::UnknownFormat(path) => write!(
+                f,
+                "{}: expected a .toml, .yaml or .yml file",
+                path.display()
+            ),
+            TopologyError::Invalid(reason) => write!(f, "invalid topology: {}", reason),
+            TopologyError::Missing { kind, role } => {
+                write!(f, "the topology has no {} for `{}`", kind, role)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TopologyError {}
+
+impl From<TopologyError> for lapin::Error {
+    fn from(error: TopologyError) -> Self {
+        let kind = match &error {
+            TopologyError::Io(_, error) => error.kind(),
+            TopologyError::Missing { .. } => io::ErrorKind::NotFound,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, error.to_string()).into()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(Format::Toml),
+            "yaml" | "yml" => Some(Format::Yaml),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "toml" => Ok(Format::Toml),
+            "yaml" => Ok(Format::Yaml),
+            s => Err(format!("Invalid format: {}", s)),
+        }
+    }
+}
+
+/// Value of an entry in an `arguments` table.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Argument {
+    Bool(bool),
+    Int(i64),
+    String(String),
+}
+
+impl From<&Argument> for AMQPValue {
+    fn from(argument: &Argument) -> Self {
+        match argument {
+            Argument::Bool(value) => AMQPValue::Boolean(*value),
+            Argument::Int(value) => AMQPValue::LongLongInt(*value),
+            Argument::String(value) => AMQPValue::LongString(value.clone().into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExchangeType {
+    Direct,
+    Fanout,
+    Topic,
+    Headers,
+}
+
+impl From<ExchangeType> for ExchangeKind {
+    fn from(kind: ExchangeType) -> Self {
+        match kind {
+            ExchangeType::Direct => ExchangeKind::Direct,
+            ExchangeType::Fanout => ExchangeKind::Fanout,
+            ExchangeType::Topic => ExchangeKind::Topic,
+            ExchangeType::Headers => ExchangeKind::Headers,
+        }
+    }
+}
+
+impl fmt::Display for ExchangeType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            ExchangeType::Direct => "direct",
+            ExchangeType::Fanout => "fanout",
+            ExchangeType::Topic => "topic",
+            ExchangeType::Headers => "headers",
+        };
+        f.write_str(kind)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueType {
+    Classic,
+    Quorum,
+    Stream,
+}
+
+/// What a queue at `max_length` does with new messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Overflow {
+    DropHead,
+    RejectPublish,
+    RejectPublishDlx,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExchangeSpec {
+    pub name: String,
+    /// Role the tutorials look the exchange up by, `name` when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: ExchangeType,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub durable: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub auto_delete: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub internal: bool,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub arguments: BTreeMap<String, Argument>,
+}
+
+impl ExchangeSpec {
+    pub fn role(&self) -> &str {
+        self.role.as_deref().unwrap_or(&self.name)
+    }
+
+    pub fn options(&self) -> ExchangeDeclareOptions {
+        ExchangeDeclareOptions {
+            durable: self.durable,
+            auto_delete: self.auto_delete,
+            internal: self.internal,
+            ..Default::default()
+        }
+    }
+
+    pub fn arguments(&self) -> FieldTable {
+        arguments(
+            self.arguments
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.into())),
+        )
+    }
+
+    pub async fn declare(&self, channel: &Channel) -> Result<()> {
+        channel
+            .exchange_declare(
+                &self.name,
+                self.kind.into(),
+                self.options(),
+                self.arguments(),
+            )
+            .await
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QueueSpec {
+    pub name: String,
+    /// Role the tutorials look the queue up by, `name` when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub durable: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub auto_delete: bool,
+    /// `x-message-ttl`, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_ttl: Option<u64>,
+    /// `x-expires`: milliseconds the queue may stay unused before it is deleted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_length: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_length_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overflow: Option<Overflow>,
+    /// `x-dead-letter-exchange`, `""` for the default exchange
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dead_letter_exchange: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dead_letter_routing_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue_type: Option<QueueType>,
+    /// Any other `x-` argument, on top of the ones above.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub arguments: BTreeMap<String, Argument>,
+}
+
+impl QueueSpec {
+    pub fn role(&self) -> &str {
+        self.role.as_deref().unwrap_or(&self.name)
+    }
+
+    pub fn options(&self) -> QueueDeclareOptions {
+        QueueDeclareOptions {
+            durable: self.durable,
+            auto_delete: self.auto_delete,
+            ..Default::default()
+        }
+    }
+
+    pub fn arguments(&self) -> FieldTable {
+        let int = |value: &Option<u64>| value.map(|value| AMQPValue::LongLongInt(value as i64));
+        let string = |value: Option<&str>| value.map(|value| AMQPValue::LongString(value.into()));
+        let typed = vec![
+            ("x-message-ttl", int(&self.message_ttl)),
+            ("x-expires", int(&self.expires)),
+            ("x-max-length", int(&self.max_length)),
+            ("x-max-length-bytes", int(&self.max_length_bytes)),
+            (
+                "x-overflow",
+                string(self.overflow.map(|overflow| match overflow {
+                    Overflow::DropHead => "drop-head",
+                    Overflow::RejectPublish => "reject-publish",
+                    Overflow::RejectPublishDlx => "reject-publish-dlx",
+                })),
+            ),
+            (
+                "x-dead-letter-exchange",
+                string(self.dead_letter_exchange.as_deref()),
+            ),
+            (
+                "x-dead-letter-routing-key",
+                string(self.dead_letter_routing_key.as_deref()),
+            ),
+            (
+                "x-queue-type",
+                string(self.queue_type.map(|queue_type| match queue_type {
+                    QueueType::Classic => "classic",
+                    QueueType::Quorum => "quorum",
+                    QueueType::Stream => "stream",
+                })),
+            ),
+        ];
+        arguments(
+            typed
+                .into_iter()
+                .filter_map(|(name, value)| Some((name, value?)))
+                .chain(
+                    self.arguments
+                        .iter()
+                        .map(|(name, value)| (name.as_str(), value.into())),
+                ),
+        )
+    }
+
+    pub async fn declare(&self, channel: &Channel) -> Result<()> {
+        channel
+            .queue_declare(&self.name, self.options(), self.arguments())
+            .await?;
+        Ok(())
+    }
+}
+
+/// Binds `queue` to `exchange`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BindingSpec {
+    pub exchange: String,
+    pub queue: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub routing_key: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub arguments: BTreeMap<String, Argument>,
+}
+
+impl BindingSpec {
+    pub async fn declare(&self, channel: &Channel) -> Result<()> {
+        channel
+            .queue_bind(
+                &self.queue,
+                &self.exchange,
+                &self.routing_key,
+                QueueBindOptions::default(),
+                arguments(
+                    self.arguments
+                        .iter()
+                        .map(|(name, value)| (name.as_str(), value.into())),
+                ),
+            )
+            .await
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Topology {
+    #[serde(default)]
+    pub exchanges: Vec<ExchangeSpec>,
+    #[serde(default)]
+    pub queues: Vec<QueueSpec>,
+    #[serde(default)]
+    pub bindings: Vec<BindingSpec>,
+}
+
+impl Topology {
+    /// The built-in layout of the tutorials, see [`TUTORIALS`].
+    pub fn tutorials() -> Self {
+        Self::parse(TUTORIALS, Format::Toml).expect("built-in topology is valid")
+    }
+
+    pub fn parse(source: &str, format: Format) -> std::result::Result<Self, TopologyError> {
+        match format {
+            Format::Toml => {
+                toml::from_str(source).map_err(|e| TopologyError::Invalid(e.to_string()))
+            }
+            Format::Yaml => {
+                serde_yaml::from_str(source).map_err(|e| TopologyError::Invalid(e.to_string()))
+            }
+        }
+    }
+
+    /// Reads a topology file, its format given by the extension.
+    pub fn load(path: &Path) -> std::result::Result<Self, TopologyError> {
+        let format =
+            Format::from_path(path).ok_or_else(|| TopologyError::UnknownFormat(path.into()))?;
+        let source =
+            std::fs::read_to_string(path).map_err(|error| TopologyError::Io(path.into(), error))?;
+        Self::parse(&source, format)
+    }
+
+    pub fn render(&self, format: Format) -> std::result::Result<String, TopologyError> {
+        match format {
+            Format::Toml => {
+                toml::to_string(self).map_err(|e| TopologyError::Invalid(e.to_string()))
+            }
+            Format::Yaml => {
+                serde_yaml::to_string(self).map_err(|e| TopologyError::Invalid(e.to_string()))
+            }
+        }
+    }
+
+    pub fn exchange(&self, role: &str) -> std::result::Result<&ExchangeSpec, TopologyError> {
+        self.exchanges
+            .iter()
+            .find(|exchange| exchange.role() == role)
+            .ok_or_else(|| TopologyError::Missing {
+                kind: "exchange",
+                role: role.to_string(),
+            })
+    }
+
+    pub fn queue(&self, role: &str) -> std::result::Result<&QueueSpec, TopologyError> {
+        self.queues
+            .iter()
+            .find(|queue| queue.role() == role)
+            .ok_or_else(|| TopologyError::Missing {
+                kind: "queue",
+                role: role.to_string(),
+            })
+    }
+
+    /// Declares every exchange, then every queue, then every binding. Declaring
+    /// what already exists with the same settings is a no-op, different settings
+    /// make the broker close the channel with `PRECONDITION_FAILED`.
+    pub async fn apply(&self, channel: &Channel) -> Result<()> {
+        for exchange in &self.exchanges {
+            exchange.declare(channel).await?;
+        }
+        for queue in &self.queues {
+            queue.declare(channel).await?;
+        }
+        for binding in &self.bindings {
+            binding.declare(channel).await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Clap)]
+pub struct TopologyOpts {
+    /// Topology file (`.toml`, `.yaml` or `.yml`) to take exchange and queue names
+    /// from, instead of the built-in one
+    #[clap(long)]
+    pub topology: Option<PathBuf>,
+}
+
+impl TopologyOpts {
+    pub fn load(&self) -> std::result::Result<Topology, TopologyError> {
+        match &self.topology {
+            Some(path) => Topology::load(path),
+            None => Ok(Topology::tutorials()),
+        }
+    }
+}
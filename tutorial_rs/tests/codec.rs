@@ -0,0 +1,123 @@
+//! Codec round trips and content type negotiation.
+
+use lapin::BasicProperties;
+use serde::{Deserialize, Serialize};
+use tutorial_rs::{
+    codec::{Cbor, Json, MessagePack, Raw},
+    Codec, CodecError, ContentType,
+};
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Task {
+    id: u32,
+    kind: String,
+    tags: Vec<String>,
+}
+
+fn task() -> Task {
+    Task {
+        id: 7,
+        kind: "resize".to_string(),
+        tags: vec!["png".to_string()],
+    }
+}
+
+#[test]
+fn structured_codecs_round_trip() {
+    for codec in [
+        ContentType::Json,
+        ContentType::MessagePack,
+        ContentType::Cbor,
+    ] {
+        let encoded = codec.encode(&task()).unwrap();
+        assert_eq!(
+            codec.decode::<Task>(&encoded).unwrap(),
+            task(),
+            "{:?}",
+            codec
+        );
+    }
+}
+
+#[test]
+fn raw_passes_bytes_through() {
+    assert_eq!(Raw.encode(&"hello").unwrap(), b"hello".to_vec());
+    assert_eq!(Raw.encode(&vec![0u8, 255]).unwrap(), vec![0, 255]);
+    assert_eq!(Raw.decode::<String>(b"hello").unwrap(), "hello");
+    assert_eq!(Raw.decode::<Vec<u8>>(&[0, 255]).unwrap(), vec![0, 255]);
+
+    assert!(matches!(
+        Raw.encode(&task()),
+        Err(CodecError::Encode { .. })
+    ));
+    assert!(matches!(
+        Raw.decode::<String>(&[0xff, 0xfe]),
+        Err(CodecError::Decode { .. })
+    ));
+}
+
+#[test]
+fn decodes_what_other_producers_send() {
+    // {"id": 7, "kind": "resize", "tags": ["png"]} as another msgpack library writes it
+    let mut map = vec![0x83, 0xa2, b'i', b'd', 0x07, 0xa4];
+    map.extend(b"kind");
+    map.push(0xa6);
+    map.extend(b"resize");
+    map.push(0xa4);
+    map.extend(b"tags");
+    map.extend([0x91, 0xa3]);
+    map.extend(b"png");
+    assert_eq!(MessagePack.decode::<Task>(&map).unwrap(), task());
+
+    let json = br#"{"tags": ["png"], "kind": "resize", "id": 7}"#;
+    assert_eq!(Json.decode::<Task>(json).unwrap(), task());
+
+    // Structs as arrays, the compact form some encoders default to
+    let compact = rmp_serde::to_vec(&task()).unwrap();
+    assert_eq!(MessagePack.decode::<Task>(&compact).unwrap(), task());
+    let cbor = serde_cbor::to_vec(&task()).unwrap();
+    assert_eq!(Cbor.decode::<Task>(&cbor).unwrap(), task());
+}
+
+#[test]
+fn content_type_is_picked_from_properties() {
+    let cases = [
+        ("application/json", ContentType::Json),
+        ("application/json; charset=utf-8", ContentType::Json),
+        ("application/vnd.api+json", ContentType::Json),
+        ("application/x-msgpack", ContentType::MessagePack),
+        ("application/msgpack", ContentType::MessagePack),
+        ("application/cbor", ContentType::Cbor),
+        ("text/plain", ContentType::Raw),
+    ];
+    for (mime, expected) in cases.iter() {
+        let properties = BasicProperties::default().with_content_type((*mime).into());
+        assert_eq!(ContentType::of(&properties).unwrap(), *expected, "{}", mime);
+    }
+    assert_eq!(
+        ContentType::of(&BasicProperties::default()).unwrap(),
+        ContentType::Raw
+    );
+    let properties = BasicProperties::default().with_content_type("application/xml".into());
+    assert_eq!(
+        ContentType::of(&properties),
+        Err(CodecError::Unsupported("application/xml".to_string()))
+    );
+}
+
+#[test]
+fn codecs_tag_their_messages() {
+    let properties = MessagePack.properties(BasicProperties::default());
+    assert_eq!(
+        properties.content_type().as_ref().map(|mime| mime.as_str()),
+        Some("application/msgpack")
+    );
+    assert_eq!(
+        properties
+            .content_encoding()
+            .as_ref()
+            .map(|encoding| encoding.as_str()),
+        Some("binary")
+    );
+    assert_eq!(Json.content_encoding(), "utf-8");
+}
@@ -0,0 +1,138 @@
+//! Compression stages and their negotiation through `content_encoding`.
+
+use lapin::BasicProperties;
+use tutorial_rs::{
+    compression::{self, MAX_DECOMPRESSED},
+    Algorithm, Codec, CodecError, Compression, CompressionError, ContentType,
+};
+
+const ALGORITHMS: [Algorithm; 3] = [Algorithm::Gzip, Algorithm::Zstd, Algorithm::Lz4];
+
+fn encoding(properties: &BasicProperties) -> Option<&str> {
+    properties
+        .content_encoding()
+        .as_ref()
+        .map(|encoding| encoding.as_str())
+}
+
+#[test]
+fn algorithms_round_trip() {
+    let body = "all work and no play ".repeat(100).into_bytes();
+    for algorithm in ALGORITHMS {
+        let compressed = algorithm.compress(&body).unwrap();
+        assert!(compressed.len() < body.len(), "{:?}", algorithm);
+        assert_eq!(
+            algorithm.decompress(&compressed, MAX_DECOMPRESSED).unwrap(),
+            body,
+            "{:?}",
+            algorithm
+        );
+        assert_eq!(algorithm.content_encoding().parse(), Ok(algorithm));
+    }
+    // Frame formats other implementations recognize
+    assert_eq!(Algorithm::Gzip.compress(b"").unwrap()[..2], [0x1f, 0x8b]);
+    assert_eq!(
+        Algorithm::Zstd.compress(b"").unwrap()[..4],
+        [0x28, 0xb5, 0x2f, 0xfd]
+    );
+    assert_eq!(
+        Algorithm::Lz4.compress(b"").unwrap()[..4],
+        [0x04, 0x22, 0x4d, 0x18]
+    );
+}
+
+#[test]
+fn only_bodies_above_the_threshold_are_compressed() {
+    let compression = Compression::new(Algorithm::Zstd, 64);
+    let properties = ContentType::Json.properties(BasicProperties::default());
+
+    let (small, small_properties) = compression
+        .apply(b"\"hi\"".to_vec(), properties.clone())
+        .unwrap();
+    assert_eq!(small, b"\"hi\"".to_vec());
+    assert_eq!(encoding(&small_properties), Some("utf-8"));
+
+    let text = "x".repeat(64);
+    let body = ContentType::Json.encode(&text).unwrap();
+    let (large, large_properties) = compression.apply(body.clone(), properties).unwrap();
+    assert_ne!(large, body);
+    assert_eq!(encoding(&large_properties), Some("zstd"));
+    assert_eq!(
+        ContentType::decode_message::<String>(&large_properties, &large, MAX_DECOMPRESSED).unwrap(),
+        text
+    );
+}
+
+#[test]
+fn uncompressed_encodings_pass_through() {
+    for properties in [
+        BasicProperties::default(),
+        BasicProperties::default().with_content_encoding("utf-8".into()),
+        BasicProperties::default().with_content_encoding("binary".into()),
+        BasicProperties::default().with_content_encoding("identity".into()),
+    ] {
+        assert_eq!(
+            &*compression::decompress(&properties, b"body", MAX_DECOMPRESSED).unwrap(),
+            b"body"
+        );
+    }
+}
+
+#[test]
+fn unknown_and_corrupt_bodies_are_rejected() {
+    let properties = BasicProperties::default().with_content_encoding("br".into());
+    let error = compression::decompress(&properties, b"body", MAX_DECOMPRESSED).unwrap_err();
+    assert_eq!(error, CompressionError::UnknownEncoding("br".to_string()));
+    assert_eq!(error.to_string(), "unknown content encoding `br`");
+    assert_eq!(
+        ContentType::decode_message::<String>(&properties, b"body", MAX_DECOMPRESSED),
+        Err(CodecError::Compression(error))
+    );
+
+    let properties = BasicProperties::default().with_content_encoding("gzip".into());
+    assert!(matches!(
+        compression::decompress(&properties, b"not gzip", MAX_DECOMPRESSED),
+        Err(CompressionError::Corrupt {
+            algorithm: Algorithm::Gzip,
+            ..
+        })
+    ));
+    assert!("brotli".parse::<Algorithm>().is_err());
+}
+
+#[test]
+fn decompression_stops_at_the_limit() {
+    let body = vec![0; 1 << 20];
+    for algorithm in ALGORITHMS {
+        let compressed = algorithm.compress(&body).unwrap();
+        assert_eq!(algorithm.decompress(&compressed, body.len()).unwrap(), body);
+        let error = algorithm
+            .decompress(&compressed, body.len() - 1)
+            .unwrap_err();
+        assert_eq!(
+            error,
+            CompressionError::TooLarge {
+                algorithm,
+                limit: body.len() - 1
+            }
+        );
+
+        let properties =
+            BasicProperties::default().with_content_encoding(algorithm.content_encoding().into());
+        assert_eq!(
+            ContentType::decode_message::<Vec<u8>>(&properties, &compressed, 1024),
+            Err(CodecError::Compression(CompressionError::TooLarge {
+                algorithm,
+                limit: 1024
+            }))
+        );
+    }
+    assert_eq!(
+        CompressionError::TooLarge {
+            algorithm: Algorithm::Zstd,
+            limit: 1024
+        }
+        .to_string(),
+        "zstd body decompresses to more than 1024 bytes"
+    );
+}
@@ -0,0 +1,67 @@
+//! Handling deliveries concurrently and draining them on shutdown.
+
+mod support;
+
+use lapin::{
+    options::{BasicAckOptions, BasicPublishOptions, QueueDeclareOptions},
+    types::FieldTable,
+    BasicProperties,
+};
+use std::time::Duration;
+use support::{channel_with_queue, Broker, TIMEOUT};
+use tokio::{sync::mpsc, time::timeout};
+use tutorial_rs::{Consumer, DrainPolicy, Shutdown};
+
+#[tokio::test]
+async fn deliveries_acked_before_the_drain_timeout_are_not_requeued() {
+    for policy in [DrainPolicy::Finish, DrainPolicy::Requeue] {
+        let broker = Broker::start().await;
+        let channel = channel_with_queue(&broker, "drained").await;
+        channel
+            .basic_publish(
+                "",
+                "drained",
+                BasicPublishOptions::default(),
+                b"slow".to_vec(),
+                BasicProperties::default(),
+            )
+            .await
+            .unwrap();
+
+        let shutdown = Shutdown::new(policy, Duration::from_millis(200));
+        let consumer = Consumer::<Vec<u8>>::start(&channel, "drained", 1)
+            .await
+            .unwrap()
+            .with_shutdown(shutdown.clone());
+        let (acked, mut on_ack) = mpsc::unbounded_channel();
+        let trigger = tokio::spawn(async move {
+            on_ack.recv().await;
+            shutdown.trigger();
+        });
+        let consumed = consumer.for_each_concurrent(|delivery| {
+            let acked = acked.clone();
+            async move {
+                delivery.ack(BasicAckOptions::default()).await?;
+                acked.send(()).unwrap();
+                tokio::time::sleep(TIMEOUT).await;
+                Ok(())
+            }
+        });
+        timeout(TIMEOUT, consumed).await.unwrap().unwrap();
+        trigger.await.unwrap();
+
+        // The channel is still open, so the acked tag wasn't nacked as well
+        channel
+            .queue_declare(
+                "drained",
+                QueueDeclareOptions {
+                    passive: true,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await
+            .unwrap();
+        assert!(broker.ready("drained").is_empty(), "{:?}", policy);
+    }
+}
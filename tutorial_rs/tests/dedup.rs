@@ -0,0 +1,157 @@
+//! Deduplication keys and the stores remembering them.
+
+use lapin::{types::AMQPValue, BasicProperties};
+use std::{env, fmt, thread, time::Duration};
+use tutorial_rs::{
+    dedup::{Backend, MemoryStore, SledStore, SqliteStore},
+    headers::with_header,
+    Dedup, DedupKey, DedupOpts, DedupStore,
+};
+use uuid::Uuid;
+
+const TTL: Duration = Duration::from_secs(60);
+
+fn opts(backend: Backend) -> DedupOpts {
+    DedupOpts {
+        dedup: Some(backend),
+        dedup_key: DedupKey::MessageId,
+        dedup_path: None,
+        dedup_capacity: 10,
+        dedup_ttl: 60,
+    }
+}
+
+/// Opens a disk store again, waiting for the previous one to let go of it: sled's
+/// background threads may hold its lock for a moment after it is dropped.
+fn reopen<T, E: fmt::Debug>(mut open: impl FnMut() -> Result<T, E>) -> T {
+    for _ in 0..50 {
+        if let Ok(opened) = open() {
+            return opened;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    open().unwrap()
+}
+
+#[test]
+fn deliveries_are_keyed_by_message_id_or_body_hash() {
+    let by_id = Dedup::new(MemoryStore::new(10, TTL), DedupKey::MessageId);
+    let properties = BasicProperties::default().with_message_id("42".into());
+    assert_eq!(by_id.key(&properties, b"body").as_deref(), Some("42"));
+    assert_eq!(by_id.key(&BasicProperties::default(), b"body"), None);
+
+    let by_hash = Dedup::new(MemoryStore::new(10, TTL), DedupKey::ContentHash);
+    assert_eq!(
+        by_hash
+            .key(&BasicProperties::default(), b"hello")
+            .as_deref(),
+        Some("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")
+    );
+}
+
+#[test]
+fn retries_are_keyed_apart_from_the_attempt_that_failed() {
+    let dedup = Dedup::new(MemoryStore::new(10, TTL), DedupKey::MessageId);
+    let retry = with_header(
+        BasicProperties::default().with_message_id("42".into()),
+        "x-retry-count",
+        AMQPValue::LongLongInt(2),
+    );
+    assert_eq!(dedup.key(&retry, b"body").as_deref(), Some("42#2"));
+}
+
+#[test]
+fn remembered_keys_are_seen_and_counted_as_hits() {
+    let dedup = Dedup::new(MemoryStore::new(10, TTL), DedupKey::MessageId);
+    assert!(!dedup.seen("42").unwrap());
+    dedup.remember("42").unwrap();
+    assert!(dedup.seen("42").unwrap());
+    assert!(dedup.seen("42").unwrap());
+    assert!(!dedup.seen("43").unwrap());
+    assert_eq!(dedup.hits(), 2);
+}
+
+#[test]
+fn the_memory_store_forgets_the_least_recently_used_keys() {
+    let store = MemoryStore::new(2, TTL);
+    store.insert("a").unwrap();
+    store.insert("b").unwrap();
+    assert!(store.contains("a").unwrap());
+    store.insert("c").unwrap();
+
+    assert_eq!(store.len(), 2);
+    assert!(store.contains("a").unwrap());
+    assert!(!store.contains("b").unwrap());
+    assert!(store.contains("c").unwrap());
+}
+
+#[test]
+fn keys_expire_after_the_ttl() {
+    let sqlite = env::temp_dir().join(format!("dedup-{}.sqlite", Uuid::new_v4()));
+    let sled = env::temp_dir().join(format!("dedup-{}", Uuid::new_v4()));
+    let ttl = Duration::from_millis(50);
+    let stores: Vec<Box<dyn DedupStore>> = vec![
+        Box::new(MemoryStore::new(10, ttl)),
+        Box::new(SqliteStore::open(&sqlite, ttl).unwrap()),
+        Box::new(SledStore::open(&sled, ttl).unwrap()),
+    ];
+    for store in &stores {
+        store.insert("42").unwrap();
+        assert!(store.contains("42").unwrap());
+    }
+    thread::sleep(ttl * 2);
+    for store in &stores {
+        assert!(!store.contains("42").unwrap());
+    }
+    drop(stores);
+    std::fs::remove_file(sqlite).unwrap();
+    std::fs::remove_dir_all(sled).unwrap();
+}
+
+#[test]
+fn disk_stores_remember_keys_across_restarts() {
+    for backend in [Backend::Sqlite, Backend::Sled] {
+        let path = env::temp_dir().join(format!("dedup-{}-{}", backend, Uuid::new_v4()));
+        let opts = DedupOpts {
+            dedup_path: Some(path.clone()),
+            ..opts(backend)
+        };
+        opts.open().unwrap().unwrap().remember("42").unwrap();
+
+        let reopened = reopen(|| opts.open()).unwrap();
+        assert!(reopened.seen("42").unwrap(), "{}", backend);
+        assert!(!reopened.seen("43").unwrap(), "{}", backend);
+        drop(reopened);
+        match backend {
+            Backend::Sled => std::fs::remove_dir_all(path).unwrap(),
+            _ => std::fs::remove_file(path).unwrap(),
+        }
+    }
+}
+
+#[test]
+fn malformed_sled_values_are_forgotten() {
+    let path = env::temp_dir().join(format!("dedup-sled-{}", Uuid::new_v4()));
+    let tree = sled::open(&path).unwrap();
+    tree.insert("42", &[1, 2, 3]).unwrap();
+    drop(tree);
+
+    let store = reopen(|| SledStore::open(&path, TTL));
+    assert!(!store.contains("42").unwrap());
+    store.insert("42").unwrap();
+    assert!(store.contains("42").unwrap());
+    drop(store);
+    std::fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn disk_stores_need_a_path() {
+    let error = opts(Backend::Sled).open().unwrap_err();
+    assert_eq!(error.to_string(), "--dedup sled requires --dedup-path");
+    assert!(opts(Backend::Memory).open().unwrap().is_some());
+    let off = DedupOpts {
+        dedup: None,
+        ..opts(Backend::Memory)
+    };
+    assert!(off.open().unwrap().is_none());
+}
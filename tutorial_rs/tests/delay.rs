@@ -0,0 +1,62 @@
+//! Bucketing delays into delay queues.
+
+use lapin::BasicProperties;
+use quickcheck::quickcheck;
+use std::time::Duration;
+use tutorial_rs::{delay::MAX_DELAY, DelayQueues};
+
+quickcheck! {
+    fn buckets_hold_delays_of_more_than_half_their_size(millis: u32) -> bool {
+        let delay = Duration::from_millis(millis as u64);
+        let bucket = DelayQueues::bucket(delay);
+        if delay > MAX_DELAY {
+            return bucket == MAX_DELAY;
+        }
+        delay <= bucket && (bucket == Duration::from_secs(1) || delay > bucket / 2)
+    }
+}
+
+#[test]
+fn delay_queues_are_named_after_their_bucket() {
+    let delays = DelayQueues::new("task_queue");
+    assert_eq!(
+        delays.delay_queue(Duration::from_millis(10)),
+        "task_queue.delay.1000"
+    );
+    assert_eq!(
+        delays.delay_queue(Duration::from_secs(30)),
+        "task_queue.delay.32000"
+    );
+    assert_eq!(
+        delays.delay_queue(Duration::from_secs(32)),
+        "task_queue.delay.32000"
+    );
+    assert_eq!(
+        delays.delay_queue(Duration::from_secs(3600)),
+        "task_queue.delay.4096000"
+    );
+}
+
+#[test]
+fn buckets_stop_at_the_longest_delay() {
+    assert_eq!(DelayQueues::bucket(MAX_DELAY), MAX_DELAY);
+    assert_eq!(
+        DelayQueues::bucket(MAX_DELAY + Duration::from_secs(1)),
+        MAX_DELAY
+    );
+    // Doubling past this would overflow
+    assert_eq!(DelayQueues::bucket(Duration::MAX), MAX_DELAY);
+}
+
+#[test]
+fn delayed_messages_expire_after_their_delay() {
+    let properties = DelayQueues::properties(
+        BasicProperties::default().with_delivery_mode(2),
+        Duration::from_micros(30_000_900),
+    );
+    assert_eq!(
+        properties.expiration().as_ref().map(|e| e.as_str()),
+        Some("30000")
+    );
+    assert_eq!(properties.delivery_mode(), &Some(2));
+}
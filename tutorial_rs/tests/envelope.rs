@@ -0,0 +1,308 @@
+//! Sealing and opening messages with a keyring.
+
+use lapin::{types::AMQPValue, BasicProperties};
+use tutorial_rs::{
+    envelope::{Scheme, KEY_ID_HEADER, SCHEME_HEADER},
+    headers::{header_str, with_header},
+    retry::RETRY_COUNT_HEADER,
+    rpc::{DEADLINE_HEADER, DIRECT_REPLY_TO},
+    topology::Format,
+    Codec, ContentType, EnvelopeError, Keyring, KeyringError,
+};
+
+const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+/// `2024-01` retired, `2024-06` active.
+const ROTATED: &str = r#"
+active = "2024-06"
+
+[[keys]]
+id = "2024-01"
+scheme = "hmac-sha256"
+secret = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY="
+
+[[keys]]
+id = "2024-06"
+scheme = "chacha20-poly1305"
+secret = "ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA="
+"#;
+
+fn properties() -> BasicProperties {
+    ContentType::Json.properties(BasicProperties::default())
+}
+
+#[test]
+fn every_scheme_round_trips() {
+    for scheme in [
+        Scheme::Aes256Gcm,
+        Scheme::ChaCha20Poly1305,
+        Scheme::HmacSha256,
+    ] {
+        let keyring = Keyring::single("k1", scheme, SECRET).unwrap();
+        let (sealed, properties) = keyring
+            .seal("tasks", b"\"task\"".to_vec(), properties())
+            .unwrap();
+        assert_eq!(
+            sealed == b"\"task\"".to_vec(),
+            !scheme.encrypts(),
+            "{}",
+            scheme
+        );
+        assert_eq!(
+            header_str(&properties, SCHEME_HEADER).as_deref(),
+            Some(scheme.name())
+        );
+        assert_eq!(
+            header_str(&properties, KEY_ID_HEADER).as_deref(),
+            Some("k1")
+        );
+        assert_eq!(
+            keyring.open("tasks", &sealed, &properties).unwrap(),
+            b"\"task\"".to_vec(),
+            "{}",
+            scheme
+        );
+    }
+}
+
+#[test]
+fn tampering_is_detected() {
+    for scheme in [Scheme::Aes256Gcm, Scheme::HmacSha256] {
+        let keyring = Keyring::single("k1", scheme, SECRET).unwrap();
+        let (sealed, properties) = keyring
+            .seal("tasks", b"\"task\"".to_vec(), properties())
+            .unwrap();
+        let forged = Err(EnvelopeError::Forged {
+            key_id: "k1".to_string(),
+        });
+
+        let mut altered = sealed.clone();
+        *altered.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            keyring.open("tasks", &altered, &properties),
+            forged,
+            "{}",
+            scheme
+        );
+
+        let relabeled = properties
+            .clone()
+            .with_content_type("application/msgpack".into());
+        assert_eq!(
+            keyring.open("tasks", &sealed, &relabeled),
+            forged,
+            "{}",
+            scheme
+        );
+
+        let other = Keyring::single("k1", scheme, b"another secret, 32 bytes long...").unwrap();
+        assert_eq!(
+            other.open("tasks", &sealed, &properties),
+            forged,
+            "{}",
+            scheme
+        );
+    }
+}
+
+#[test]
+fn properties_headers_and_routing_key_are_authenticated() {
+    let properties = properties()
+        .with_kind("resize".into())
+        .with_reply_to("replies".into())
+        .with_correlation_id("c1".into())
+        .with_message_id("m1".into())
+        .with_timestamp(1_700_000_000);
+    let properties = with_header(properties, RETRY_COUNT_HEADER, AMQPValue::LongLongInt(1));
+    let properties = with_header(properties, DEADLINE_HEADER, AMQPValue::LongLongInt(42));
+    let altered: Vec<(&str, BasicProperties)> = vec![
+        ("type", properties.clone().with_kind("delete".into())),
+        (
+            "reply_to",
+            properties.clone().with_reply_to("elsewhere".into()),
+        ),
+        (
+            "correlation_id",
+            properties.clone().with_correlation_id("c2".into()),
+        ),
+        (
+            "message_id",
+            properties.clone().with_message_id("m2".into()),
+        ),
+        (
+            "timestamp",
+            properties.clone().with_timestamp(1_800_000_000),
+        ),
+        (
+            RETRY_COUNT_HEADER,
+            with_header(
+                properties.clone(),
+                RETRY_COUNT_HEADER,
+                AMQPValue::LongLongInt(0),
+            ),
+        ),
+        (
+            DEADLINE_HEADER,
+            with_header(
+                properties.clone(),
+                DEADLINE_HEADER,
+                AMQPValue::LongLongInt(43),
+            ),
+        ),
+    ];
+
+    for scheme in [Scheme::ChaCha20Poly1305, Scheme::HmacSha256] {
+        let keyring = Keyring::single("k1", scheme, SECRET).unwrap();
+        let forged = Err(EnvelopeError::Forged {
+            key_id: "k1".to_string(),
+        });
+        let (sealed, sealed_properties) = keyring
+            .seal("tasks", b"task".to_vec(), properties.clone())
+            .unwrap();
+        assert_eq!(
+            keyring.open("tasks", &sealed, &sealed_properties).unwrap(),
+            b"task".to_vec()
+        );
+        assert_eq!(
+            keyring.open("other", &sealed, &sealed_properties),
+            forged,
+            "{}",
+            scheme
+        );
+        for (field, properties) in &altered {
+            // Envelope headers as sealed, everything else as altered
+            let mut properties = properties.clone();
+            for name in [SCHEME_HEADER, KEY_ID_HEADER, "x-signature"] {
+                if let Some(value) = header_str(&sealed_properties, name) {
+                    properties = with_header(properties, name, AMQPValue::LongString(value.into()));
+                }
+            }
+            assert_eq!(
+                keyring.open("tasks", &sealed, &properties),
+                forged,
+                "{} with {}",
+                field,
+                scheme
+            );
+        }
+    }
+}
+
+#[test]
+fn direct_reply_to_opens_once_the_broker_rewrote_it() {
+    let keyring = Keyring::single("k1", Scheme::HmacSha256, SECRET).unwrap();
+    let (sealed, properties) = keyring
+        .seal(
+            "rpc_queue",
+            b"10".to_vec(),
+            properties().with_reply_to(DIRECT_REPLY_TO.into()),
+        )
+        .unwrap();
+    let delivered = properties.with_reply_to(format!("{}.g1h2", DIRECT_REPLY_TO).into());
+    assert!(keyring.open("rpc_queue", &sealed, &delivered).is_ok());
+}
+
+#[test]
+fn fields_cannot_shift_into_each_other() {
+    let keyring = Keyring::single("k1", Scheme::HmacSha256, SECRET).unwrap();
+    let (sealed, properties) = keyring
+        .seal(
+            "tasks",
+            b"task".to_vec(),
+            BasicProperties::default().with_content_type("a\0b".into()),
+        )
+        .unwrap();
+    // Joined with NUL separators, both would authenticate the same bytes
+    let shifted = properties
+        .clone()
+        .with_content_type("a".into())
+        .with_content_encoding("b".into());
+    assert!(keyring.open("tasks", &sealed, &shifted).is_err());
+
+    // An unset property isn't an empty one
+    let (sealed, properties) = keyring
+        .seal("tasks", b"task".to_vec(), BasicProperties::default())
+        .unwrap();
+    let emptied = properties.with_content_type("".into());
+    assert!(keyring.open("tasks", &sealed, &emptied).is_err());
+}
+
+#[test]
+fn unsealed_and_unknown_messages_are_refused() {
+    let keyring = Keyring::single("k1", Scheme::HmacSha256, SECRET).unwrap();
+    assert_eq!(
+        keyring.open("tasks", b"task", &properties()),
+        Err(EnvelopeError::Unsealed)
+    );
+
+    let (sealed, properties) = keyring
+        .seal("tasks", b"task".to_vec(), properties())
+        .unwrap();
+    let renamed = with_header(
+        properties.clone(),
+        KEY_ID_HEADER,
+        AMQPValue::LongString("k2".into()),
+    );
+    assert_eq!(
+        keyring.open("tasks", &sealed, &renamed),
+        Err(EnvelopeError::UnknownKey("k2".to_string()))
+    );
+    let rot13 = with_header(
+        properties,
+        SCHEME_HEADER,
+        AMQPValue::LongString("rot13".into()),
+    );
+    assert_eq!(
+        keyring.open("tasks", &sealed, &rot13),
+        Err(EnvelopeError::UnknownScheme("rot13".to_string()))
+    );
+}
+
+#[test]
+fn rotated_keys_still_open() {
+    let retired = Keyring::single("2024-01", Scheme::HmacSha256, SECRET).unwrap();
+    let (old, old_properties) = retired
+        .seal("tasks", b"old".to_vec(), properties())
+        .unwrap();
+
+    let keyring = Keyring::parse(ROTATED, Format::Toml).unwrap();
+    assert_eq!(keyring.active(), "2024-06");
+    assert_eq!(
+        keyring.open("tasks", &old, &old_properties).unwrap(),
+        b"old".to_vec()
+    );
+
+    let (new, new_properties) = keyring
+        .seal("tasks", b"new".to_vec(), properties())
+        .unwrap();
+    assert_eq!(
+        header_str(&new_properties, KEY_ID_HEADER).as_deref(),
+        Some("2024-06")
+    );
+    assert_eq!(
+        keyring.open("tasks", &new, &new_properties).unwrap(),
+        b"new".to_vec()
+    );
+    assert_eq!(
+        retired.open("tasks", &new, &new_properties),
+        Err(EnvelopeError::UnknownKey("2024-06".to_string()))
+    );
+}
+
+#[test]
+fn invalid_keyrings_are_rejected() {
+    let invalid = |source: &str| match Keyring::parse(source, Format::Toml) {
+        Err(KeyringError::Invalid(reason)) => reason,
+        other => panic!("{:?}", other),
+    };
+    assert!(
+        invalid(&ROTATED.replace("\"2024-06\"\n\n", "\"2024-12\"\n\n"))
+            .contains("active key `2024-12`")
+    );
+    assert!(invalid(&ROTATED.replace("2024-01", "2024-06")).contains("duplicate key `2024-06`"));
+    assert!(
+        invalid(&ROTATED.replace("ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA=", "c2hvcnQ="))
+            .contains("5 bytes long, expected 32")
+    );
+    assert!(invalid(&ROTATED.replace("hmac-sha256", "rot13")).contains("rot13"));
+}
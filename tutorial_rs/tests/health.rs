@@ -0,0 +1,56 @@
+//! Reports of the health endpoints.
+
+use tutorial_rs::Health;
+
+#[test]
+fn nothing_is_healthy_before_connecting() {
+    let health = Health::default();
+    health.consuming("ctag-1", 1);
+    health.delivered();
+
+    let report = health.report();
+    assert!(!report.healthy);
+    assert!(!report.ready);
+    assert!(!report.topology_declared);
+    assert_eq!(report.connection, "disconnected");
+    assert_eq!(report.channel, "closed");
+    assert_eq!(report.consumer_tag.as_deref(), Some("ctag-1"));
+    assert_eq!(report.prefetch, Some(1));
+}
+
+#[test]
+fn stopping_the_consumer_forgets_its_tag() {
+    let health = Health::default();
+    health.consuming("ctag-1", 4);
+    health.stopped_consuming();
+
+    assert_eq!(health.report().consumer_tag, None);
+}
+
+#[test]
+fn reports_serialize_to_json() {
+    let health = Health::default();
+    let json: serde_json::Value = serde_json::to_value(health.report()).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "healthy": false,
+            "ready": false,
+            "connection": "disconnected",
+            "channel": "closed",
+            "topology_declared": false,
+            "consumer_tag": null,
+            "prefetch": null,
+            "last_delivery": null,
+        })
+    );
+
+    health.delivered();
+    let json = serde_json::to_value(health.report()).unwrap();
+    let last_delivery = json["last_delivery"].as_str().unwrap();
+    assert!(
+        humantime::parse_rfc3339(last_delivery).is_ok(),
+        "{}",
+        last_delivery
+    );
+}
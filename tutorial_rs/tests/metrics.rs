@@ -0,0 +1,88 @@
+//! Recording and rendering publisher and consumer metrics.
+
+use std::time::Duration;
+use tutorial_rs::{Metrics, PublishOutcome};
+
+#[test]
+fn publisher_metrics_are_labelled_by_binary_and_exchange() {
+    let metrics = Metrics::new("02_work-queues");
+    metrics.published("");
+    metrics.published("logs");
+    metrics.confirmed("", &PublishOutcome::Ack, Duration::from_millis(3));
+    metrics.confirmed("logs", &PublishOutcome::Nack, Duration::from_millis(3));
+    metrics.confirmed(
+        "logs",
+        &PublishOutcome::Returned {
+            reply_code: 312,
+            reply_text: "NO_ROUTE".to_string(),
+        },
+        Duration::from_millis(3),
+    );
+
+    let rendered = metrics.render();
+    for line in &[
+        r#"amqp_published_total{exchange="amq.default",binary="02_work-queues"} 1"#,
+        r#"amqp_published_total{exchange="logs",binary="02_work-queues"} 1"#,
+        r#"amqp_confirmed_total{exchange="amq.default",binary="02_work-queues"} 1"#,
+        r#"amqp_nacked_total{exchange="logs",binary="02_work-queues"} 1"#,
+        r#"amqp_returned_total{exchange="logs",binary="02_work-queues"} 1"#,
+        r#"amqp_confirm_duration_seconds_count{exchange="logs",binary="02_work-queues"} 2"#,
+    ] {
+        assert!(rendered.contains(line), "missing {} in\n{}", line, rendered);
+    }
+}
+
+#[test]
+fn unrequested_confirms_are_not_recorded() {
+    let metrics = Metrics::new("01_hello-world");
+    metrics.confirmed("", &PublishOutcome::NotRequested, Duration::from_millis(1));
+
+    assert!(!metrics.render().contains("amq.default"));
+}
+
+#[test]
+fn queue_metrics_count_deliveries_and_in_flight_handlers() {
+    let metrics = Metrics::new("06_rpc");
+    let queue = metrics.queue("rpc_queue");
+    queue.delivered(false);
+    queue.delivered(true);
+    queue.acked();
+    queue.rejected();
+    queue.duplicate();
+
+    let handling = queue.handling();
+    assert!(metrics
+        .render()
+        .contains(r#"amqp_in_flight{queue="rpc_queue",binary="06_rpc"} 1"#));
+    queue.handled(Duration::from_millis(20));
+    drop(handling);
+
+    let rendered = metrics.render();
+    for line in &[
+        r#"amqp_delivered_total{queue="rpc_queue",binary="06_rpc"} 2"#,
+        r#"amqp_redelivered_total{queue="rpc_queue",binary="06_rpc"} 1"#,
+        r#"amqp_acked_total{queue="rpc_queue",binary="06_rpc"} 1"#,
+        r#"amqp_rejected_total{queue="rpc_queue",binary="06_rpc"} 1"#,
+        r#"amqp_dedup_hits_total{queue="rpc_queue",binary="06_rpc"} 1"#,
+        r#"amqp_in_flight{queue="rpc_queue",binary="06_rpc"} 0"#,
+        r#"amqp_handler_duration_seconds_count{queue="rpc_queue",binary="06_rpc"} 1"#,
+    ] {
+        assert!(rendered.contains(line), "missing {} in\n{}", line, rendered);
+    }
+}
+
+#[test]
+fn slot_busy_time_is_counted_by_slot() {
+    let metrics = Metrics::new("02_work-queues");
+    metrics.slot_busy(0, Duration::from_millis(1500));
+    metrics.slot_busy(0, Duration::from_millis(500));
+    metrics.slot_busy(2, Duration::from_millis(250));
+
+    let rendered = metrics.render();
+    for line in &[
+        r#"amqp_slot_busy_seconds_total{slot="0",binary="02_work-queues"} 2"#,
+        r#"amqp_slot_busy_seconds_total{slot="2",binary="02_work-queues"} 0.25"#,
+    ] {
+        assert!(rendered.contains(line), "missing {} in\n{}", line, rendered);
+    }
+}
@@ -0,0 +1,100 @@
+//! Running handlers on a bounded pool of tasks.
+
+use futures::future;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+use tutorial_rs::{Pool, TraceContext};
+
+#[tokio::test]
+async fn handlers_run_in_parallel_up_to_the_pool_size() {
+    let pool = Pool::new(3);
+    let running = Arc::new(AtomicUsize::new(0));
+    let most = Arc::new(AtomicUsize::new(0));
+
+    let started = Instant::now();
+    let runs = (0..6).map(|_| {
+        let running = running.clone();
+        let most = most.clone();
+        pool.run(async move {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            most.fetch_max(now, Ordering::SeqCst);
+            sleep(Duration::from_millis(100)).await;
+            running.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        })
+    });
+    for result in future::join_all(runs).await {
+        result.unwrap();
+    }
+
+    assert_eq!(most.load(Ordering::SeqCst), 3);
+    assert!(started.elapsed() < Duration::from_millis(500));
+}
+
+#[tokio::test]
+async fn utilisation_is_the_busy_share_of_each_slot() {
+    let pool = Pool::new(2);
+    pool.utilisation();
+    pool.run(async {
+        sleep(Duration::from_millis(200)).await;
+        Ok(())
+    })
+    .await
+    .unwrap();
+
+    let utilisation = pool.utilisation();
+    assert_eq!(utilisation.shares.len(), 2);
+    assert!(utilisation.shares[0] > 0.8, "{}", utilisation);
+    assert!(utilisation.shares[1] == 0.0, "{}", utilisation);
+    assert!((utilisation.mean() - utilisation.shares[0] / 2.0).abs() < 1e-9);
+
+    // Each report covers the time since the previous one
+    assert_eq!(pool.utilisation().shares, vec![0.0, 0.0]);
+}
+
+#[tokio::test]
+async fn dropping_a_run_aborts_its_task() {
+    let pool = Pool::new(1);
+    let finished = Arc::new(AtomicUsize::new(0));
+    let counted = finished.clone();
+    let run = pool.run(async move {
+        sleep(Duration::from_millis(100)).await;
+        counted.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    });
+    assert!(tokio::time::timeout(Duration::from_millis(20), run)
+        .await
+        .is_err());
+
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(finished.load(Ordering::SeqCst), 0);
+    // The slot was freed along the way
+    pool.run(async { Ok(()) }).await.unwrap();
+}
+
+#[tokio::test]
+async fn handlers_keep_the_trace_context_they_were_run_in() {
+    let pool = Pool::new(1);
+    let context = TraceContext::root();
+    let expected = context.clone();
+    let seen = context
+        .scope(async {
+            let (sender, receiver) = tokio::sync::oneshot::channel();
+            pool.run(async move {
+                sender.send(TraceContext::current()).unwrap();
+                Ok(())
+            })
+            .await
+            .unwrap();
+            receiver.await.unwrap()
+        })
+        .await;
+
+    assert_eq!(seen, Some(expected));
+}
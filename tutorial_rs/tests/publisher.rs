@@ -0,0 +1,61 @@
+//! Publishing with confirms, batched and counted in metrics.
+
+mod support;
+
+use lapin::BasicProperties;
+use support::{channel_with_queue, Broker};
+use tutorial_rs::{Message, Metrics, Publisher};
+
+#[tokio::test]
+async fn batches_report_unroutable_messages_by_index() {
+    let broker = Broker::start().await;
+    let channel = channel_with_queue(&broker, "batch").await;
+
+    let messages = ["batch", "nowhere", "batch", "nowhere"]
+        .iter()
+        .map(|queue| Message {
+            exchange: String::new(),
+            routing_key: queue.to_string(),
+            payload: queue.as_bytes().to_vec(),
+            properties: BasicProperties::default(),
+        })
+        .collect();
+    let publisher = Publisher::new(channel, true).await.unwrap();
+    let report = publisher.publish_batch(messages, 2, 0).await.unwrap();
+
+    assert_eq!(report.published, 4);
+    assert_eq!(report.acked, 2);
+    let mut returned = report.returned;
+    returned.sort_unstable();
+    assert_eq!(returned, vec![1, 3]);
+    assert!(report.nacked.is_empty());
+    assert_eq!(broker.ready("batch").len(), 2);
+}
+
+#[tokio::test]
+async fn published_messages_are_counted_in_the_metrics() {
+    let broker = Broker::start().await;
+    let channel = channel_with_queue(&broker, "counted").await;
+    let metrics = Metrics::new("publisher");
+    let publisher = Publisher::new(channel, true)
+        .await
+        .unwrap()
+        .with_metrics(Some(metrics.clone()));
+
+    for queue in &["counted", "counted", "nowhere"] {
+        publisher
+            .publish("", queue, b"hello".to_vec(), BasicProperties::default())
+            .await
+            .unwrap();
+    }
+
+    let rendered = metrics.render();
+    for line in &[
+        r#"amqp_published_total{exchange="amq.default",binary="publisher"} 3"#,
+        r#"amqp_confirmed_total{exchange="amq.default",binary="publisher"} 2"#,
+        r#"amqp_returned_total{exchange="amq.default",binary="publisher"} 1"#,
+        r#"amqp_confirm_duration_seconds_count{exchange="amq.default",binary="publisher"} 3"#,
+    ] {
+        assert!(rendered.contains(line), "missing {} in\n{}", line, rendered);
+    }
+}
@@ -0,0 +1,72 @@
+//! Recording job events in the SQLite results store.
+
+use serde_json::json;
+use std::env;
+use tutorial_rs::{JobEvent, JobStatus, ResultStore};
+use uuid::Uuid;
+
+fn event(status: JobStatus) -> JobEvent {
+    JobEvent::new("42", Some("wordcount"), status)
+}
+
+#[tokio::test]
+async fn the_latest_event_of_a_job_is_kept() {
+    let store = ResultStore::in_memory().unwrap();
+    assert_eq!(store.get("42").await.unwrap(), None);
+
+    store
+        .record(&event(JobStatus::Started { attempt: 1 }))
+        .await
+        .unwrap();
+    store
+        .record(&event(JobStatus::Progress { percent: 40 }))
+        .await
+        .unwrap();
+    let failed = JobStatus::Failed {
+        error: "boom".to_string(),
+        retrying: true,
+    };
+    store.record(&event(failed)).await.unwrap();
+    let record = store.get("42").await.unwrap().unwrap();
+    assert_eq!(record.state, "failed");
+    assert_eq!(record.progress, Some(40));
+    assert_eq!(record.error.as_deref(), Some("boom"));
+    assert!(record.retrying);
+
+    store
+        .record(&event(JobStatus::Started { attempt: 2 }))
+        .await
+        .unwrap();
+    let succeeded = JobStatus::Succeeded {
+        result: json!({ "words": 3 }),
+    };
+    store.record(&event(succeeded)).await.unwrap();
+    let record = store.get("42").await.unwrap().unwrap();
+    assert_eq!(record.kind.as_deref(), Some("wordcount"));
+    assert_eq!(record.state, "succeeded");
+    assert_eq!(record.attempt, 2);
+    assert_eq!(record.progress, Some(100));
+    assert_eq!(record.result, Some(json!({ "words": 3 })));
+    assert_eq!(record.error, None);
+    assert!(!record.retrying);
+}
+
+#[tokio::test]
+async fn records_outlive_the_store() {
+    let path = env::temp_dir().join(format!("results-{}.sqlite", Uuid::new_v4()));
+    let store = ResultStore::open(&path).unwrap();
+    store
+        .record(&event(JobStatus::Started { attempt: 1 }))
+        .await
+        .unwrap();
+    drop(store);
+
+    let record = ResultStore::open(&path)
+        .unwrap()
+        .get("42")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(record.state, "started");
+    std::fs::remove_file(path).unwrap();
+}
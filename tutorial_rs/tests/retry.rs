@@ -0,0 +1,128 @@
+//! Settling failed deliveries through retry queues.
+
+mod support;
+
+use futures::StreamExt;
+use lapin::{
+    options::{BasicPublishOptions, QueueDeclareOptions},
+    BasicProperties,
+};
+use std::{sync::Arc, time::Duration};
+use support::{channel_with_queue, Broker};
+use tutorial_rs::{
+    envelope::Scheme, Consumer, Failure, FailureAction, Keyring, PublishOutcome, Publisher,
+    RetryPolicy,
+};
+
+const DELAY: Duration = Duration::from_secs(60);
+const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+#[tokio::test]
+async fn originals_go_once_their_retry_is_confirmed_and_are_requeued_otherwise() {
+    let broker = Broker::start().await;
+    let channel = channel_with_queue(&broker, "side").await;
+    let policy = RetryPolicy::new("work", vec![DELAY]);
+    policy
+        .declare(&channel, QueueDeclareOptions::default())
+        .await
+        .unwrap();
+    let retries = Publisher::new(channel.clone(), true).await.unwrap();
+    channel
+        .basic_publish(
+            "",
+            "work",
+            BasicPublishOptions::default(),
+            b"task".to_vec(),
+            BasicProperties::default(),
+        )
+        .await
+        .unwrap();
+    let mut consumer = Consumer::<Vec<u8>>::start(&channel, "work", 1)
+        .await
+        .unwrap();
+    let failure = Failure::Transient("boom".to_string());
+
+    let delivery = consumer.next().await.unwrap().unwrap();
+    let action = policy.fail(&retries, &delivery, &failure).await.unwrap();
+    assert_eq!(
+        action,
+        FailureAction::Retried {
+            attempt: 1,
+            delay: DELAY
+        }
+    );
+    assert_eq!(broker.ready("work.retry.60000"), vec![b"task".to_vec()]);
+
+    // Without its retry queue, the retry comes back and the original stays
+    let elsewhere = RetryPolicy::new("work", vec![Duration::from_secs(1)]);
+    channel
+        .basic_publish(
+            "",
+            "work",
+            BasicPublishOptions::default(),
+            b"again".to_vec(),
+            BasicProperties::default(),
+        )
+        .await
+        .unwrap();
+    let delivery = consumer.next().await.unwrap().unwrap();
+    let action = elsewhere.fail(&retries, &delivery, &failure).await.unwrap();
+    assert!(
+        matches!(
+            action,
+            FailureAction::Requeued {
+                outcome: PublishOutcome::Returned { .. }
+            }
+        ),
+        "{}",
+        action
+    );
+    let redelivered = consumer.next().await.unwrap().unwrap();
+    assert_eq!(redelivered.data, b"again".to_vec());
+    assert!(redelivered.redelivered);
+}
+
+#[tokio::test]
+async fn sealed_retries_are_resealed_for_the_work_queue() {
+    let broker = Broker::start().await;
+    let channel = channel_with_queue(&broker, "side").await;
+    let policy = RetryPolicy::new("work", vec![DELAY]);
+    policy
+        .declare(&channel, QueueDeclareOptions::default())
+        .await
+        .unwrap();
+    let keyring = Arc::new(Keyring::single("k1", Scheme::HmacSha256, SECRET).unwrap());
+    Publisher::new(channel.clone(), true)
+        .await
+        .unwrap()
+        .with_keyring(Some(keyring.clone()))
+        .publish("", "work", b"task".to_vec(), BasicProperties::default())
+        .await
+        .unwrap();
+    let retries = Publisher::new(channel.clone(), true)
+        .await
+        .unwrap()
+        .with_keyring(Some(keyring.clone()))
+        .with_delivery_routing_key(Some("work".to_string()));
+
+    let mut consumer = Consumer::<Vec<u8>>::start(&channel, "work", 1)
+        .await
+        .unwrap();
+    let mut delivery = consumer.next().await.unwrap().unwrap();
+    delivery.open(&keyring).unwrap();
+    let failure = Failure::Transient("boom".to_string());
+    policy.fail(&retries, &delivery, &failure).await.unwrap();
+
+    // As the retry queue dead-letters it back into `work`
+    let mut retried = Consumer::<Vec<u8>>::start(&channel, "work.retry.60000", 1)
+        .await
+        .unwrap();
+    let retry = retried.next().await.unwrap().unwrap();
+    assert_eq!(RetryPolicy::retry_count(&retry.properties), 1);
+    assert_eq!(
+        keyring
+            .open("work", &retry.data, &retry.properties)
+            .unwrap(),
+        b"task".to_vec()
+    );
+}
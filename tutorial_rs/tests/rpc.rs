@@ -0,0 +1,33 @@
+//! Requests as an RPC client publishes them.
+
+mod support;
+
+use futures::StreamExt;
+use std::time::Duration;
+use support::{channel_with_queue, Broker};
+use tutorial_rs::{Consumer, ReplyMode, RpcClient, RpcError};
+
+#[tokio::test]
+async fn requests_are_published_with_a_message_id() {
+    let broker = Broker::start().await;
+    let channel = channel_with_queue(&broker, "rpc_queue").await;
+    let client = RpcClient::<u64, u64>::new(channel.clone(), "rpc_queue", ReplyMode::Queue)
+        .await
+        .unwrap()
+        .with_timeout(Duration::from_millis(100));
+
+    for _ in 0..2 {
+        assert!(matches!(client.call(&10).await, Err(RpcError::Timeout(_))));
+    }
+
+    let mut requests = Consumer::<Vec<u8>>::start(&channel, "rpc_queue", 0)
+        .await
+        .unwrap();
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let request = requests.next().await.unwrap().unwrap();
+        assert_eq!(request.data, b"10".to_vec());
+        ids.push(request.properties.message_id().clone().unwrap());
+    }
+    assert_ne!(ids[0], ids[1]);
+}
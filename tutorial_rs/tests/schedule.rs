@@ -0,0 +1,100 @@
+//! Reading schedule files and finding the jobs due next.
+
+use chrono::{TimeZone, Utc};
+use tutorial_rs::{topology::Format, Schedule, ScheduleError};
+
+const SCHEDULE: &str = r#"
+[[jobs]]
+name = "nightly-report"
+cron = "0 2 * * *"
+task = "report..."
+type = "sleep"
+
+[[jobs]]
+name = "hourly-count"
+cron = "0 0 * * * *"
+task = "count these words"
+type = "wordcount"
+
+[[jobs]]
+name = "cleanup"
+cron = "0 2 * * *"
+task = "cleanup"
+"#;
+
+#[test]
+fn jobs_are_read_from_toml_and_yaml() {
+    let toml = Schedule::parse(SCHEDULE, Format::Toml).unwrap();
+    assert_eq!(toml.jobs.len(), 3);
+    assert_eq!(toml.jobs[0].spec.name, "nightly-report");
+    assert_eq!(toml.jobs[1].spec.kind.as_deref(), Some("wordcount"));
+    assert_eq!(toml.jobs[2].spec.kind, None);
+
+    let yaml = Schedule::parse(
+        "jobs:\n  - name: ping\n    cron: '*/5 * * * *'\n    task: ping\n",
+        Format::Yaml,
+    )
+    .unwrap();
+    assert_eq!(yaml.jobs[0].spec.cron, "*/5 * * * *");
+}
+
+#[test]
+fn five_field_expressions_run_on_the_minute() {
+    let schedule = Schedule::parse(SCHEDULE, Format::Toml).unwrap();
+    let after = Utc.with_ymd_and_hms(2030, 1, 1, 1, 59, 30).unwrap();
+
+    assert_eq!(
+        schedule.jobs[0].next_after(after),
+        Some(Utc.with_ymd_and_hms(2030, 1, 1, 2, 0, 0).unwrap())
+    );
+    // Strictly after: a job due right now comes back a day later
+    assert_eq!(
+        schedule.jobs[0].next_after(Utc.with_ymd_and_hms(2030, 1, 1, 2, 0, 0).unwrap()),
+        Some(Utc.with_ymd_and_hms(2030, 1, 2, 2, 0, 0).unwrap())
+    );
+}
+
+#[test]
+fn jobs_due_at_the_same_time_come_together() {
+    let schedule = Schedule::parse(SCHEDULE, Format::Toml).unwrap();
+
+    let (at, jobs) = schedule
+        .next_after(Utc.with_ymd_and_hms(2030, 1, 1, 1, 30, 0).unwrap())
+        .unwrap();
+    assert_eq!(at, Utc.with_ymd_and_hms(2030, 1, 1, 2, 0, 0).unwrap());
+    let names = jobs
+        .iter()
+        .map(|job| job.spec.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["nightly-report", "hourly-count", "cleanup"]);
+
+    let (at, jobs) = schedule.next_after(at).unwrap();
+    assert_eq!(at, Utc.with_ymd_and_hms(2030, 1, 1, 3, 0, 0).unwrap());
+    assert_eq!(jobs.len(), 1);
+    assert_eq!(jobs[0].spec.name, "hourly-count");
+
+    assert!(Schedule::parse("", Format::Toml)
+        .unwrap()
+        .next_after(at)
+        .is_none());
+}
+
+#[test]
+fn invalid_schedules_are_rejected() {
+    let error = Schedule::parse(
+        "[[jobs]]\nname = \"broken\"\ncron = \"every day\"\ntask = \"x\"\n",
+        Format::Toml,
+    )
+    .unwrap_err();
+    assert!(matches!(&error, ScheduleError::InvalidCron { job, .. } if job == "broken"));
+
+    let twice = format!(
+        "{}\n[[jobs]]\nname = \"cleanup\"\ncron = \"* * * * *\"\ntask = \"x\"\n",
+        SCHEDULE
+    );
+    let error = Schedule::parse(&twice, Format::Toml).unwrap_err();
+    assert_eq!(error.to_string(), "job `cleanup` is listed twice");
+
+    let error = Schedule::load("jobs.ini".as_ref()).unwrap_err();
+    assert!(matches!(error, ScheduleError::UnknownFormat(_)));
+}
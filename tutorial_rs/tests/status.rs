@@ -0,0 +1,103 @@
+//! The shape of job status events and how they are routed.
+
+use serde_json::json;
+use tutorial_rs::{JobEvent, JobStatus};
+
+#[test]
+fn events_serialize_flat_with_their_state() {
+    let event = JobEvent {
+        job_id: "42".to_string(),
+        kind: Some("wordcount".to_string()),
+        status: JobStatus::Succeeded {
+            result: json!({ "words": 3 }),
+        },
+        at: "2030-01-01T09:00:00.000Z".to_string(),
+    };
+    let value = serde_json::to_value(&event).unwrap();
+    assert_eq!(
+        value,
+        json!({
+            "job_id": "42",
+            "type": "wordcount",
+            "state": "succeeded",
+            "result": { "words": 3 },
+            "at": "2030-01-01T09:00:00.000Z",
+        })
+    );
+    assert_eq!(serde_json::from_value::<JobEvent>(value).unwrap(), event);
+}
+
+#[test]
+fn events_are_routed_by_job_and_state() {
+    let started = JobEvent::new("42", None, JobStatus::Started { attempt: 1 });
+    assert_eq!(started.routing_key(), "42.started");
+    assert!(serde_json::to_value(&started)
+        .unwrap()
+        .get("type")
+        .is_none());
+
+    let retrying = JobStatus::Failed {
+        error: "boom".to_string(),
+        retrying: true,
+    };
+    assert_eq!(
+        JobEvent::new("42", None, retrying).routing_key(),
+        "42.failed"
+    );
+}
+
+#[test]
+fn only_success_skips_and_failures_without_retry_are_final() {
+    let failed = |retrying| JobStatus::Failed {
+        error: "boom".to_string(),
+        retrying,
+    };
+    assert!(!JobStatus::Started { attempt: 1 }.is_final());
+    assert!(!JobStatus::Progress { percent: 50 }.is_final());
+    assert!(!failed(true).is_final());
+    assert!(failed(false).is_final());
+    assert!(JobStatus::Succeeded {
+        result: json!(null)
+    }
+    .is_final());
+    assert!(JobStatus::Skipped {
+        reason: "duplicate".to_string()
+    }
+    .is_final());
+}
+
+#[test]
+fn statuses_read_as_progress_lines() {
+    let lines = vec![
+        JobStatus::Started { attempt: 2 }.to_string(),
+        JobStatus::Progress { percent: 50 }.to_string(),
+        JobStatus::Failed {
+            error: "boom".to_string(),
+            retrying: true,
+        }
+        .to_string(),
+        JobStatus::Succeeded {
+            result: json!(null),
+        }
+        .to_string(),
+        JobStatus::Succeeded {
+            result: json!({ "words": 3 }),
+        }
+        .to_string(),
+        JobStatus::Skipped {
+            reason: "duplicate 42".to_string(),
+        }
+        .to_string(),
+    ];
+    assert_eq!(
+        lines,
+        vec![
+            "started (attempt 2)",
+            "50% done",
+            "failed: boom, retrying",
+            "succeeded",
+            r#"succeeded: {"words":3}"#,
+            "skipped: duplicate 42",
+        ]
+    );
+}
@@ -0,0 +1,1107 @@
+//! Minimal in-process AMQP 0-9-1 broker.
+//!
+//! Listens on a random localhost port and speaks enough of the protocol for the
+//! tutorials: connection and channel lifecycle, direct/fanout/topic exchanges,
+//! queues (server-named, exclusive and dead-lettering on reject), bindings,
+//! publishing with `mandatory` returns, consuming with prefetch, acks, nacks,
+//! publisher confirms and direct reply-to.
+//!
+//! Any credentials, SASL mechanism and vhost are accepted, heartbeats are ignored, and TTLs
+//! (`x-message-ttl`, `expiration`) are not enforced.
+
+use amq_protocol::{
+    frame::{gen_frame, parse_frame, AMQPContentHeader, AMQPFrame, WriteContext},
+    protocol::{basic, channel, confirm, connection, exchange, queue, AMQPClass, BasicProperties},
+    types::{AMQPValue, FieldTable, LongLongUInt, ShortUInt},
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tutorial_rs::BindingKey;
+use uuid::Uuid;
+
+const FRAME_MAX: u32 = 131_072;
+const DIRECT_REPLY_TO: &str = "amq.rabbitmq.reply-to";
+
+const NO_ROUTE: ShortUInt = 312;
+const NOT_FOUND: ShortUInt = 404;
+const RESOURCE_LOCKED: ShortUInt = 405;
+const PRECONDITION_FAILED: ShortUInt = 406;
+const NOT_IMPLEMENTED: ShortUInt = 540;
+
+type ConnectionId = u64;
+type ChannelKey = (ConnectionId, ShortUInt);
+type Writer = mpsc::UnboundedSender<AMQPFrame>;
+
+/// Handle to a running broker. The listener stops when it is dropped.
+pub struct Broker {
+    port: u16,
+    state: Arc<Mutex<State>>,
+    accept: JoinHandle<()>,
+}
+
+impl Broker {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind broker");
+        let port = listener.local_addr().expect("broker address").port();
+        let state = Arc::new(Mutex::new(State::new()));
+
+        let shared = state.clone();
+        let accept = tokio::spawn(async move {
+            let next_id = AtomicU64::new(1);
+            while let Ok((stream, _)) = listener.accept().await {
+                let id = next_id.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(serve_connection(shared.clone(), stream, id));
+            }
+        });
+
+        Self {
+            port,
+            state,
+            accept,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn uri(&self) -> String {
+        format!("amqp://127.0.0.1:{}/%2f", self.port)
+    }
+
+    pub fn queue_exists(&self, queue: &str) -> bool {
+        self.state.lock().unwrap().queues.contains_key(queue)
+    }
+
+    /// Bodies of the messages waiting in `queue`, oldest first.
+    pub fn ready(&self, queue: &str) -> Vec<Vec<u8>> {
+        self.state
+            .lock()
+            .unwrap()
+            .queues
+            .get(queue)
+            .map(|queue| queue.messages.iter().map(|m| m.body.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// `(queue, routing key)` pairs bound to `exchange`.
+    pub fn bindings(&self, exchange: &str) -> Vec<(String, String)> {
+        self.state
+            .lock()
+            .unwrap()
+            .exchanges
+            .get(exchange)
+            .map(|exchange| {
+                exchange
+                    .bindings
+                    .iter()
+                    .map(|binding| (binding.queue.clone(), binding.routing_key.clone()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Argument `name` of `queue`, for string and integer values.
+    pub fn queue_argument(&self, queue: &str, name: &str) -> Option<String> {
+        self.state.lock().unwrap().queues.get(queue)?.argument(name)
+    }
+
+    /// SASL mechanism each connection authenticated with, in connection order.
+    pub fn mechanisms(&self) -> Vec<String> {
+        self.state.lock().unwrap().mechanisms.clone()
+    }
+
+    /// Number of consumers attached to `queue`.
+    pub fn consumers(&self, queue: &str) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .queues
+            .get(queue)
+            .map_or(0, |queue| queue.consumers.len())
+    }
+}
+
+impl Drop for Broker {
+    fn drop(&mut self) {
+        self.accept.abort();
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Message {
+    exchange: String,
+    routing_key: String,
+    properties: BasicProperties,
+    body: Vec<u8>,
+    redelivered: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExchangeKind {
+    Direct,
+    Fanout,
+    Topic,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Binding {
+    queue: String,
+    routing_key: String,
+}
+
+#[derive(Debug)]
+struct Exchange {
+    kind: ExchangeKind,
+    bindings: Vec<Binding>,
+}
+
+impl Exchange {
+    fn new(kind: ExchangeKind) -> Self {
+        Self {
+            kind,
+            bindings: Vec::new(),
+        }
+    }
+
+    fn routes(&self, routing_key: &str) -> Vec<String> {
+        self.bindings
+            .iter()
+            .filter(|binding| match self.kind {
+                ExchangeKind::Direct => binding.routing_key == routing_key,
+                ExchangeKind::Fanout => true,
+                ExchangeKind::Topic => BindingKey::parse(&binding.routing_key)
+                    .is_ok_and(|key| key.matches(routing_key)),
+            })
+            .map(|binding| binding.queue.clone())
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct Consumer {
+    channel: ChannelKey,
+    tag: String,
+    no_ack: bool,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    messages: VecDeque<Message>,
+    consumers: Vec<Consumer>,
+    /// Connection that declared the queue exclusive.
+    owner: Option<ConnectionId>,
+    arguments: FieldTable,
+    /// Round-robin position among the consumers.
+    next_consumer: usize,
+}
+
+impl Queue {
+    fn argument(&self, name: &str) -> Option<String> {
+        match self.arguments.inner().get(name)? {
+            AMQPValue::LongString(value) => Some(value.to_string()),
+            AMQPValue::ShortString(value) => Some(value.to_string()),
+            AMQPValue::LongLongInt(value) => Some(value.to_string()),
+            AMQPValue::LongInt(value) => Some(value.to_string()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Channel {
+    writer: Writer,
+    prefetch: u16,
+    next_delivery_tag: LongLongUInt,
+    unacked: BTreeMap<LongLongUInt, (String, Message)>,
+    confirm: bool,
+    next_publish_tag: LongLongUInt,
+    /// Queue standing in for `amq.rabbitmq.reply-to` on this channel.
+    reply_queue: Option<String>,
+}
+
+impl Channel {
+    fn new(writer: Writer) -> Self {
+        Self {
+            writer,
+            prefetch: 0,
+            next_delivery_tag: 1,
+            unacked: BTreeMap::new(),
+            confirm: false,
+            next_publish_tag: 1,
+            reply_queue: None,
+        }
+    }
+
+    fn has_capacity(&self) -> bool {
+        self.prefetch == 0 || self.unacked.len() < self.prefetch as usize
+    }
+}
+
+/// Error closing the channel it happened on.
+#[derive(Debug)]
+struct ChannelError {
+    code: ShortUInt,
+    text: String,
+}
+
+impl ChannelError {
+    fn new(code: ShortUInt, text: impl Into<String>) -> Self {
+        Self {
+            code,
+            text: text.into(),
+        }
+    }
+}
+
+type ChannelResult = Result<(), ChannelError>;
+
+/// How a delivery is settled.
+#[derive(Debug, Clone, Copy)]
+enum Settle {
+    Ack,
+    Requeue,
+    Discard,
+}
+
+#[derive(Debug)]
+struct State {
+    exchanges: HashMap<String, Exchange>,
+    queues: HashMap<String, Queue>,
+    channels: HashMap<ChannelKey, Channel>,
+    /// SASL mechanism of every connection so far.
+    mechanisms: Vec<String>,
+}
+
+impl State {
+    fn new() -> Self {
+        let mut exchanges = HashMap::new();
+        exchanges.insert(
+            "amq.direct".to_string(),
+            Exchange::new(ExchangeKind::Direct),
+        );
+        exchanges.insert(
+            "amq.fanout".to_string(),
+            Exchange::new(ExchangeKind::Fanout),
+        );
+        exchanges.insert("amq.topic".to_string(), Exchange::new(ExchangeKind::Topic));
+        Self {
+            exchanges,
+            queues: HashMap::new(),
+            channels: HashMap::new(),
+            mechanisms: Vec::new(),
+        }
+    }
+
+    /// Queues a message published to `exchange` ends up in, `None` when the
+    /// exchange does not exist.
+    fn route(&self, exchange: &str, routing_key: &str) -> Option<Vec<String>> {
+        if exchange.is_empty() {
+            return Some(
+                self.queues
+                    .contains_key(routing_key)
+                    .then(|| routing_key.to_string())
+                    .into_iter()
+                    .collect(),
+            );
+        }
+        let mut queues = self.exchanges.get(exchange)?.routes(routing_key);
+        let mut seen = HashSet::new();
+        queues.retain(|queue| seen.insert(queue.clone()));
+        Some(queues)
+    }
+
+    fn enqueue(&mut self, queues: &[String], message: &Message) {
+        for name in queues {
+            if let Some(queue) = self.queues.get_mut(name) {
+                queue.messages.push_back(message.clone());
+            }
+        }
+    }
+
+    /// Hands ready messages to consumers with room for them, round-robin.
+    fn dispatch(&mut self) {
+        let names = self.queues.keys().cloned().collect::<Vec<_>>();
+        for name in names {
+            loop {
+                let queue = self.queues.get_mut(&name).unwrap();
+                if queue.messages.is_empty() || queue.consumers.is_empty() {
+                    break;
+                }
+                let count = queue.consumers.len();
+                let channels = &self.channels;
+                let chosen = (0..count)
+                    .map(|offset| (queue.next_consumer + offset) % count)
+                    .find(|&index| {
+                        let consumer = &queue.consumers[index];
+                        consumer.no_ack
+                            || channels
+                                .get(&consumer.channel)
+                                .is_some_and(Channel::has_capacity)
+                    });
+                let index = match chosen {
+                    Some(index) => index,
+                    None => break,
+                };
+                queue.next_consumer = (index + 1) % count;
+                let message = queue.messages.pop_front().unwrap();
+                let consumer = &queue.consumers[index];
+                let (key, tag, no_ack) = (consumer.channel, consumer.tag.clone(), consumer.no_ack);
+
+                let channel = self.channels.get_mut(&key).unwrap();
+                let delivery_tag = channel.next_delivery_tag;
+                channel.next_delivery_tag += 1;
+                let deliver = AMQPClass::Basic(basic::AMQPMethod::Deliver(basic::Deliver {
+                    consumer_tag: tag.into(),
+                    delivery_tag,
+                    redelivered: message.redelivered,
+                    exchange: message.exchange.clone().into(),
+                    routing_key: message.routing_key.clone().into(),
+                }));
+                send_content(&channel.writer, key.1, deliver, &message);
+                if !no_ack {
+                    channel
+                        .unacked
+                        .insert(delivery_tag, (name.clone(), message));
+                }
+            }
+        }
+    }
+
+    fn settle(
+        &mut self,
+        key: ChannelKey,
+        delivery_tag: LongLongUInt,
+        multiple: bool,
+        settle: Settle,
+    ) -> ChannelResult {
+        let channel = self.channels.get_mut(&key).unwrap();
+        let tags = if multiple {
+            channel
+                .unacked
+                .range(..=delivery_tag)
+                .map(|(tag, _)| *tag)
+                .collect::<Vec<_>>()
+        } else if channel.unacked.contains_key(&delivery_tag) {
+            vec![delivery_tag]
+        } else {
+            return Err(ChannelError::new(
+                PRECONDITION_FAILED,
+                format!(
+                    "PRECONDITION_FAILED - unknown delivery tag {}",
+                    delivery_tag
+                ),
+            ));
+        };
+
+        let settled = tags
+            .into_iter()
+            .filter_map(|tag| channel.unacked.remove(&tag))
+            .collect::<Vec<_>>();
+        match settle {
+            Settle::Ack => {}
+            Settle::Requeue => self.requeue(settled),
+            Settle::Discard => {
+                for (queue, message) in settled {
+                    self.dead_letter(&queue, message);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Puts deliveries back at the head of their queues, in their original order.
+    fn requeue(&mut self, deliveries: Vec<(String, Message)>) {
+        for (queue, mut message) in deliveries.into_iter().rev() {
+            if let Some(queue) = self.queues.get_mut(&queue) {
+                message.redelivered = true;
+                queue.messages.push_front(message);
+            }
+        }
+    }
+
+    /// Republishes a rejected message to its queue's dead-letter exchange, if any.
+    fn dead_letter(&mut self, queue: &str, mut message: Message) {
+        let queue = match self.queues.get(queue) {
+            Some(queue) => queue,
+            None => return,
+        };
+        let exchange = match queue.argument("x-dead-letter-exchange") {
+            Some(exchange) => exchange,
+            None => return,
+        };
+        let routing_key = queue
+            .argument("x-dead-letter-routing-key")
+            .unwrap_or_else(|| message.routing_key.clone());
+        if let Some(queues) = self.route(&exchange, &routing_key) {
+            message.exchange = exchange;
+            message.routing_key = routing_key;
+            message.redelivered = false;
+            self.enqueue(&queues, &message);
+        }
+    }
+
+    fn close_channel(&mut self, key: ChannelKey) {
+        for queue in self.queues.values_mut() {
+            queue.consumers.retain(|consumer| consumer.channel != key);
+        }
+        if let Some(channel) = self.channels.remove(&key) {
+            self.requeue(channel.unacked.into_values().collect());
+        }
+        self.dispatch();
+    }
+
+    fn close_connection(&mut self, id: ConnectionId) {
+        let keys = self
+            .channels
+            .keys()
+            .filter(|key| key.0 == id)
+            .copied()
+            .collect::<Vec<_>>();
+        for key in keys {
+            self.close_channel(key);
+        }
+        let owned = self
+            .queues
+            .iter()
+            .filter(|(_, queue)| queue.owner == Some(id))
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for name in owned {
+            self.delete_queue(&name);
+        }
+    }
+
+    fn delete_queue(&mut self, name: &str) -> Option<Queue> {
+        for exchange in self.exchanges.values_mut() {
+            exchange.bindings.retain(|binding| binding.queue != name);
+        }
+        self.queues.remove(name)
+    }
+}
+
+fn method(method: AMQPClass) -> AMQPFrame {
+    AMQPFrame::Method(0, method)
+}
+
+fn send(writer: &Writer, channel: ShortUInt, method: AMQPClass) {
+    let _ = writer.send(AMQPFrame::Method(channel, method));
+}
+
+/// Sends a method carrying `message` as content: header and body frames follow it.
+fn send_content(writer: &Writer, channel: ShortUInt, method: AMQPClass, message: &Message) {
+    let _ = writer.send(AMQPFrame::Method(channel, method));
+    let _ = writer.send(AMQPFrame::Header(
+        channel,
+        60,
+        Box::new(AMQPContentHeader {
+            class_id: 60,
+            body_size: message.body.len() as LongLongUInt,
+            properties: message.properties.clone(),
+        }),
+    ));
+    for chunk in message.body.chunks(FRAME_MAX as usize - 8) {
+        let _ = writer.send(AMQPFrame::Body(channel, chunk.to_vec()));
+    }
+}
+
+/// Length of the frame at the start of `buffer`, once it has been fully received.
+fn frame_len(buffer: &[u8]) -> Option<usize> {
+    let len = match buffer.first()? {
+        b'A' => 8,
+        _ if buffer.len() >= 7 => {
+            7 + u32::from_be_bytes([buffer[3], buffer[4], buffer[5], buffer[6]]) as usize + 1
+        }
+        _ => return None,
+    };
+    (buffer.len() >= len).then_some(len)
+}
+
+/// A `basic.publish` waiting for its content header and body.
+struct PendingPublish {
+    publish: basic::Publish,
+    properties: BasicProperties,
+    body_size: usize,
+    body: Vec<u8>,
+}
+
+struct Session {
+    id: ConnectionId,
+    state: Arc<Mutex<State>>,
+    writer: Writer,
+    publishes: HashMap<ShortUInt, PendingPublish>,
+    /// Channels closed on error, whose frames are ignored until `close-ok`.
+    closing: HashSet<ShortUInt>,
+}
+
+async fn serve_connection(state: Arc<Mutex<State>>, stream: TcpStream, id: ConnectionId) {
+    let (mut reader, mut write_half) = stream.into_split();
+    let (writer, mut outgoing) = mpsc::unbounded_channel::<AMQPFrame>();
+    tokio::spawn(async move {
+        while let Some(frame) = outgoing.recv().await {
+            let bytes = match gen_frame(&frame)(WriteContext::from(Vec::new())) {
+                Ok(context) => context.write,
+                Err(_) => break,
+            };
+            if write_half.write_all(&bytes).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut session = Session {
+        id,
+        state: state.clone(),
+        writer,
+        publishes: HashMap::new(),
+        closing: HashSet::new(),
+    };
+    let mut buffer = Vec::new();
+    let mut read = [0u8; 8192];
+    'connection: loop {
+        while let Some(len) = frame_len(&buffer) {
+            let frame = match parse_frame(&buffer[..len]) {
+                Ok((_, frame)) => frame,
+                Err(_) => break 'connection,
+            };
+            buffer.drain(..len);
+            if !session.handle(frame) {
+                break 'connection;
+            }
+        }
+        match reader.read(&mut read).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => buffer.extend_from_slice(&read[..n]),
+        }
+    }
+
+    state.lock().unwrap().close_connection(id);
+}
+
+impl Session {
+    /// Handles a frame, returning false once the connection is over.
+    fn handle(&mut self, frame: AMQPFrame) -> bool {
+        match frame {
+            AMQPFrame::ProtocolHeader(_) => {
+                let mut server_properties = FieldTable::default();
+                server_properties.insert(
+                    "product".into(),
+                    AMQPValue::LongString("tutorial_rs test broker".into()),
+                );
+                let _ = self.writer.send(method(AMQPClass::Connection(
+                    connection::AMQPMethod::Start(connection::Start {
+                        version_major: 0,
+                        version_minor: 9,
+                        server_properties,
+                        mechanisms: "PLAIN AMQPLAIN EXTERNAL".into(),
+                        locales: "en_US".into(),
+                    }),
+                )));
+                true
+            }
+            AMQPFrame::Heartbeat(_) => true,
+            AMQPFrame::Method(channel, class) => self.handle_method(channel, class),
+            AMQPFrame::Header(channel, _, header) => {
+                if let Some(pending) = self.publishes.get_mut(&channel) {
+                    pending.properties = header.properties;
+                    pending.body_size = header.body_size as usize;
+                }
+                self.complete_publish(channel);
+                true
+            }
+            AMQPFrame::Body(channel, data) => {
+                if let Some(pending) = self.publishes.get_mut(&channel) {
+                    pending.body.extend(data);
+                }
+                self.complete_publish(channel);
+                true
+            }
+        }
+    }
+
+    fn handle_method(&mut self, channel: ShortUInt, class: AMQPClass) -> bool {
+        match class {
+            AMQPClass::Connection(connection::AMQPMethod::StartOk(start_ok)) => {
+                self.state
+                    .lock()
+                    .unwrap()
+                    .mechanisms
+                    .push(start_ok.mechanism.to_string());
+                send(
+                    &self.writer,
+                    0,
+                    AMQPClass::Connection(connection::AMQPMethod::Tune(connection::Tune {
+                        channel_max: 2047,
+                        frame_max: FRAME_MAX,
+                        heartbeat: 0,
+                    })),
+                );
+            }
+            AMQPClass::Connection(connection::AMQPMethod::TuneOk(_)) => {}
+            AMQPClass::Connection(connection::AMQPMethod::Open(_)) => send(
+                &self.writer,
+                0,
+                AMQPClass::Connection(connection::AMQPMethod::OpenOk(connection::OpenOk {})),
+            ),
+            AMQPClass::Connection(connection::AMQPMethod::Close(_)) => {
+                // The client closes the socket once it gets `close-ok`
+                self.state.lock().unwrap().close_connection(self.id);
+                send(
+                    &self.writer,
+                    0,
+                    AMQPClass::Connection(connection::AMQPMethod::CloseOk(connection::CloseOk {})),
+                );
+            }
+            AMQPClass::Connection(connection::AMQPMethod::CloseOk(_)) => return false,
+            AMQPClass::Channel(channel::AMQPMethod::Open(_)) => {
+                self.closing.remove(&channel);
+                self.state
+                    .lock()
+                    .unwrap()
+                    .channels
+                    .insert((self.id, channel), Channel::new(self.writer.clone()));
+                send(
+                    &self.writer,
+                    channel,
+                    AMQPClass::Channel(channel::AMQPMethod::OpenOk(channel::OpenOk {})),
+                );
+            }
+            AMQPClass::Channel(channel::AMQPMethod::Close(_)) => {
+                self.publishes.remove(&channel);
+                self.state.lock().unwrap().close_channel((self.id, channel));
+                send(
+                    &self.writer,
+                    channel,
+                    AMQPClass::Channel(channel::AMQPMethod::CloseOk(channel::CloseOk {})),
+                );
+            }
+            AMQPClass::Channel(channel::AMQPMethod::CloseOk(_)) => {
+                self.closing.remove(&channel);
+            }
+            _ if self.closing.contains(&channel) => {}
+            class => {
+                let (class_id, method_id) = (class.get_amqp_class_id(), class.get_amqp_method_id());
+                if let Err(error) = self.channel_method(channel, class) {
+                    self.fail_channel(channel, error, class_id, method_id);
+                }
+            }
+        }
+        true
+    }
+
+    fn fail_channel(
+        &mut self,
+        channel: ShortUInt,
+        error: ChannelError,
+        class_id: ShortUInt,
+        method_id: ShortUInt,
+    ) {
+        self.publishes.remove(&channel);
+        self.closing.insert(channel);
+        self.state.lock().unwrap().close_channel((self.id, channel));
+        send(
+            &self.writer,
+            channel,
+            AMQPClass::Channel(channel::AMQPMethod::Close(channel::Close {
+                reply_code: error.code,
+                reply_text: error.text.into(),
+                class_id,
+                method_id,
+            })),
+        );
+    }
+
+    fn reply(&self, channel: ShortUInt, nowait: bool, method: AMQPClass) {
+        if !nowait {
+            send(&self.writer, channel, method);
+        }
+    }
+
+    fn channel_method(&mut self, channel: ShortUInt, class: AMQPClass) -> ChannelResult {
+        let key = (self.id, channel);
+        let mut state = self.state.lock().unwrap();
+        if !state.channels.contains_key(&key) {
+            return Err(ChannelError::new(
+                NOT_FOUND,
+                format!("NOT_FOUND - channel {} is not open", channel),
+            ));
+        }
+
+        match class {
+            AMQPClass::Exchange(exchange::AMQPMethod::Declare(declare)) => {
+                let name = declare.exchange.to_string();
+                let kind = match declare.kind.as_str() {
+                    "direct" => ExchangeKind::Direct,
+                    "fanout" => ExchangeKind::Fanout,
+                    "topic" => ExchangeKind::Topic,
+                    kind => {
+                        return Err(ChannelError::new(
+                            NOT_IMPLEMENTED,
+                            format!("NOT_IMPLEMENTED - exchange type '{}'", kind),
+                        ))
+                    }
+                };
+                match state.exchanges.get(&name) {
+                    // Like RabbitMQ, a passive declaration only checks existence
+                    Some(existing) if existing.kind != kind && !declare.passive => {
+                        return Err(ChannelError::new(
+                            PRECONDITION_FAILED,
+                            format!("PRECONDITION_FAILED - inequivalent type for '{}'", name),
+                        ))
+                    }
+                    Some(_) => {}
+                    None if declare.passive => {
+                        return Err(ChannelError::new(
+                            NOT_FOUND,
+                            format!("NOT_FOUND - no exchange '{}'", name),
+                        ))
+                    }
+                    None => {
+                        state.exchanges.insert(name, Exchange::new(kind));
+                    }
+                }
+                self.reply(
+                    channel,
+                    declare.nowait,
+                    AMQPClass::Exchange(exchange::AMQPMethod::DeclareOk(exchange::DeclareOk {})),
+                );
+            }
+            AMQPClass::Exchange(exchange::AMQPMethod::Delete(delete)) => {
+                state.exchanges.remove(delete.exchange.as_str());
+                self.reply(
+                    channel,
+                    delete.nowait,
+                    AMQPClass::Exchange(exchange::AMQPMethod::DeleteOk(exchange::DeleteOk {})),
+                );
+            }
+            AMQPClass::Queue(queue::AMQPMethod::Declare(declare)) => {
+                let name = match declare.queue.as_str() {
+                    "" => format!("amq.gen-{}", Uuid::new_v4().to_simple()),
+                    name => name.to_string(),
+                };
+                match state.queues.get(&name) {
+                    Some(existing) if existing.owner.is_some_and(|owner| owner != self.id) => {
+                        return Err(ChannelError::new(
+                            RESOURCE_LOCKED,
+                            format!("RESOURCE_LOCKED - queue '{}' is exclusive", name),
+                        ))
+                    }
+                    Some(existing)
+                        if !declare.passive && existing.arguments != declare.arguments =>
+                    {
+                        return Err(ChannelError::new(
+                            PRECONDITION_FAILED,
+                            format!(
+                                "PRECONDITION_FAILED - inequivalent arguments for '{}'",
+                                name
+                            ),
+                        ))
+                    }
+                    Some(_) => {}
+                    None if declare.passive => {
+                        return Err(ChannelError::new(
+                            NOT_FOUND,
+                            format!("NOT_FOUND - no queue '{}'", name),
+                        ))
+                    }
+                    None => {
+                        let queue = Queue {
+                            owner: declare.exclusive.then_some(self.id),
+                            arguments: declare.arguments,
+                            ..Default::default()
+                        };
+                        state.queues.insert(name.clone(), queue);
+                    }
+                }
+                let queue = &state.queues[&name];
+                let declare_ok = queue::DeclareOk {
+                    queue: name.as_str().into(),
+                    message_count: queue.messages.len() as u32,
+                    consumer_count: queue.consumers.len() as u32,
+                };
+                self.reply(
+                    channel,
+                    declare.nowait,
+                    AMQPClass::Queue(queue::AMQPMethod::DeclareOk(declare_ok)),
+                );
+            }
+            AMQPClass::Queue(queue::AMQPMethod::Bind(bind)) => {
+                let binding = Binding {
+                    queue: bind.queue.to_string(),
+                    routing_key: bind.routing_key.to_string(),
+                };
+                if !state.queues.contains_key(&binding.queue) {
+                    return Err(ChannelError::new(
+                        NOT_FOUND,
+                        format!("NOT_FOUND - no queue '{}'", binding.queue),
+                    ));
+                }
+                let exchange =
+                    state
+                        .exchanges
+                        .get_mut(bind.exchange.as_str())
+                        .ok_or_else(|| {
+                            ChannelError::new(
+                                NOT_FOUND,
+                                format!("NOT_FOUND - no exchange '{}'", bind.exchange),
+                            )
+                        })?;
+                if !exchange.bindings.contains(&binding) {
+                    exchange.bindings.push(binding);
+                }
+                self.reply(
+                    channel,
+                    bind.nowait,
+                    AMQPClass::Queue(queue::AMQPMethod::BindOk(queue::BindOk {})),
+                );
+            }
+            AMQPClass::Queue(queue::AMQPMethod::Unbind(unbind)) => {
+                if let Some(exchange) = state.exchanges.get_mut(unbind.exchange.as_str()) {
+                    exchange.bindings.retain(|binding| {
+                        binding.queue != unbind.queue.as_str()
+                            || binding.routing_key != unbind.routing_key.as_str()
+                    });
+                }
+                send(
+                    &self.writer,
+                    channel,
+                    AMQPClass::Queue(queue::AMQPMethod::UnbindOk(queue::UnbindOk {})),
+                );
+            }
+            AMQPClass::Queue(queue::AMQPMethod::Purge(purge)) => {
+                let message_count = state
+                    .queues
+                    .get_mut(purge.queue.as_str())
+                    .map_or(0, |queue| queue.messages.drain(..).count());
+                self.reply(
+                    channel,
+                    purge.nowait,
+                    AMQPClass::Queue(queue::AMQPMethod::PurgeOk(queue::PurgeOk {
+                        message_count: message_count as u32,
+                    })),
+                );
+            }
+            AMQPClass::Queue(queue::AMQPMethod::Delete(delete)) => {
+                let message_count = state
+                    .delete_queue(delete.queue.as_str())
+                    .map_or(0, |queue| queue.messages.len());
+                self.reply(
+                    channel,
+                    delete.nowait,
+                    AMQPClass::Queue(queue::AMQPMethod::DeleteOk(queue::DeleteOk {
+                        message_count: message_count as u32,
+                    })),
+                );
+            }
+            AMQPClass::Basic(basic::AMQPMethod::Qos(qos)) => {
+                state.channels.get_mut(&key).unwrap().prefetch = qos.prefetch_count;
+                send(
+                    &self.writer,
+                    channel,
+                    AMQPClass::Basic(basic::AMQPMethod::QosOk(basic::QosOk {})),
+                );
+                state.dispatch();
+            }
+            AMQPClass::Basic(basic::AMQPMethod::Consume(consume)) => {
+                let name = if consume.queue.as_str() == DIRECT_REPLY_TO {
+                    if !consume.no_ack {
+                        return Err(ChannelError::new(
+                            PRECONDITION_FAILED,
+                            "PRECONDITION_FAILED - reply consumer cannot acknowledge",
+                        ));
+                    }
+                    let name = format!("{}.{}", DIRECT_REPLY_TO, Uuid::new_v4().to_simple());
+                    let queue = Queue {
+                        owner: Some(self.id),
+                        ..Default::default()
+                    };
+                    state.queues.insert(name.clone(), queue);
+                    state.channels.get_mut(&key).unwrap().reply_queue = Some(name.clone());
+                    name
+                } else {
+                    consume.queue.to_string()
+                };
+                let tag = match consume.consumer_tag.as_str() {
+                    "" => format!("amq.ctag-{}", Uuid::new_v4().to_simple()),
+                    tag => tag.to_string(),
+                };
+                let queue = state.queues.get_mut(&name).ok_or_else(|| {
+                    ChannelError::new(NOT_FOUND, format!("NOT_FOUND - no queue '{}'", name))
+                })?;
+                if queue.owner.is_some_and(|owner| owner != self.id) {
+                    return Err(ChannelError::new(
+                        RESOURCE_LOCKED,
+                        format!("RESOURCE_LOCKED - queue '{}' is exclusive", name),
+                    ));
+                }
+                queue.consumers.push(Consumer {
+                    channel: key,
+                    tag: tag.clone(),
+                    no_ack: consume.no_ack,
+                });
+                self.reply(
+                    channel,
+                    consume.nowait,
+                    AMQPClass::Basic(basic::AMQPMethod::ConsumeOk(basic::ConsumeOk {
+                        consumer_tag: tag.into(),
+                    })),
+                );
+                state.dispatch();
+            }
+            AMQPClass::Basic(basic::AMQPMethod::Cancel(cancel)) => {
+                for queue in state.queues.values_mut() {
+                    queue.consumers.retain(|consumer| {
+                        consumer.channel != key || consumer.tag != cancel.consumer_tag.as_str()
+                    });
+                }
+                self.reply(
+                    channel,
+                    cancel.nowait,
+                    AMQPClass::Basic(basic::AMQPMethod::CancelOk(basic::CancelOk {
+                        consumer_tag: cancel.consumer_tag,
+                    })),
+                );
+            }
+            AMQPClass::Basic(basic::AMQPMethod::Publish(publish)) => {
+                self.publishes.insert(
+                    channel,
+                    PendingPublish {
+                        publish,
+                        properties: BasicProperties::default(),
+                        body_size: usize::MAX,
+                        body: Vec::new(),
+                    },
+                );
+            }
+            AMQPClass::Basic(basic::AMQPMethod::Ack(ack)) => {
+                state.settle(key, ack.delivery_tag, ack.multiple, Settle::Ack)?;
+                state.dispatch();
+            }
+            AMQPClass::Basic(basic::AMQPMethod::Nack(nack)) => {
+                let settle = if nack.requeue {
+                    Settle::Requeue
+                } else {
+                    Settle::Discard
+                };
+                state.settle(key, nack.delivery_tag, nack.multiple, settle)?;
+                state.dispatch();
+            }
+            AMQPClass::Basic(basic::AMQPMethod::Reject(reject)) => {
+                let settle = if reject.requeue {
+                    Settle::Requeue
+                } else {
+                    Settle::Discard
+                };
+                state.settle(key, reject.delivery_tag, false, settle)?;
+                state.dispatch();
+            }
+            AMQPClass::Confirm(confirm::AMQPMethod::Select(select)) => {
+                state.channels.get_mut(&key).unwrap().confirm = true;
+                self.reply(
+                    channel,
+                    select.nowait,
+                    AMQPClass::Confirm(confirm::AMQPMethod::SelectOk(confirm::SelectOk {})),
+                );
+            }
+            class => {
+                return Err(ChannelError::new(
+                    NOT_IMPLEMENTED,
+                    format!("NOT_IMPLEMENTED - {:?}", class),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Routes a publish once its whole body has arrived.
+    fn complete_publish(&mut self, channel: ShortUInt) {
+        match self.publishes.get(&channel) {
+            Some(pending) if pending.body.len() >= pending.body_size => {}
+            _ => return,
+        }
+        let pending = self.publishes.remove(&channel).unwrap();
+        if let Err(error) = self.publish(channel, pending) {
+            let publish = basic::Publish::default();
+            self.fail_channel(
+                channel,
+                error,
+                publish.get_amqp_class_id(),
+                publish.get_amqp_method_id(),
+            );
+        }
+    }
+
+    fn publish(&mut self, channel: ShortUInt, pending: PendingPublish) -> ChannelResult {
+        let key = (self.id, channel);
+        let mut state = self.state.lock().unwrap();
+        let PendingPublish {
+            publish,
+            mut properties,
+            body,
+            ..
+        } = pending;
+
+        let reply_queue = state
+            .channels
+            .get(&key)
+            .and_then(|channel| channel.reply_queue.clone());
+        if let Some(reply_queue) = reply_queue {
+            if properties.reply_to().as_ref().map(|r| r.as_str()) == Some(DIRECT_REPLY_TO) {
+                properties = properties.with_reply_to(reply_queue.into());
+            }
+        }
+
+        let message = Message {
+            exchange: publish.exchange.to_string(),
+            routing_key: publish.routing_key.to_string(),
+            properties,
+            body,
+            redelivered: false,
+        };
+        let queues = state
+            .route(&message.exchange, &message.routing_key)
+            .ok_or_else(|| {
+                ChannelError::new(
+                    NOT_FOUND,
+                    format!("NOT_FOUND - no exchange '{}'", message.exchange),
+                )
+            })?;
+        state.enqueue(&queues, &message);
+
+        let channel_state = state.channels.get_mut(&key).unwrap();
+        if queues.is_empty() && publish.mandatory {
+            let returned = AMQPClass::Basic(basic::AMQPMethod::Return(basic::Return {
+                reply_code: NO_ROUTE,
+                reply_text: "NO_ROUTE".into(),
+                exchange: publish.exchange,
+                routing_key: publish.routing_key,
+            }));
+            send_content(&self.writer, channel, returned, &message);
+        }
+        if channel_state.confirm {
+            let delivery_tag = channel_state.next_publish_tag;
+            channel_state.next_publish_tag += 1;
+            send(
+                &self.writer,
+                channel,
+                AMQPClass::Basic(basic::AMQPMethod::Ack(basic::Ack {
+                    delivery_tag,
+                    multiple: false,
+                })),
+            );
+        }
+        state.dispatch();
+        Ok(())
+    }
+}
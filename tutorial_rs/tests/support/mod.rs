@@ -0,0 +1,150 @@
+//! Helpers shared by the integration tests: an in-process broker and a way to run
+//! the tutorial binaries against it.
+
+#![allow(dead_code, unused_imports)]
+
+pub mod broker;
+pub mod tls;
+
+pub use broker::Broker;
+pub use tls::{fixture, TlsProxy};
+
+use clap::Clap;
+use lapin::{options::QueueDeclareOptions, types::FieldTable, Channel};
+use std::{process::Stdio, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Lines},
+    net::TcpStream,
+    process::{Child, ChildStdout, Command},
+    time::timeout,
+};
+use tutorial_rs::ConnectionOpts;
+
+/// How long to wait for a binary to print an expected line or to exit.
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Command running the tutorial binary at `path` (see `CARGO_BIN_EXE_<name>`)
+/// against `broker`.
+pub fn tutorial(path: &str, broker: &Broker) -> Command {
+    tutorial_at(path, broker.port())
+}
+
+/// Command running the tutorial binary at `path` against whatever listens on
+/// `port`, e.g. a [`TlsProxy`].
+pub fn tutorial_at(path: &str, port: u16) -> Command {
+    let mut command = Command::new(path);
+    command
+        .args(["--port", &port.to_string()])
+        .stdout(Stdio::piped())
+        .kill_on_drop(true);
+    command
+}
+
+/// A channel to `broker` on which `queue` is declared.
+pub async fn channel_with_queue(broker: &Broker, queue: &str) -> Channel {
+    let port = broker.port().to_string();
+    let connection = ConnectionOpts::try_parse_from(["tutorial", "--port", &port])
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let channel = connection.create_channel().await.unwrap();
+    channel
+        .queue_declare(queue, QueueDeclareOptions::default(), FieldTable::default())
+        .await
+        .unwrap();
+    channel
+}
+
+/// Runs `command` to completion, asserting it succeeds, and returns its stdout.
+pub async fn run(mut command: Command) -> String {
+    let output = timeout(TIMEOUT, command.output())
+        .await
+        .expect("command timed out")
+        .expect("command failed to start");
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    assert!(
+        output.status.success(),
+        "{:?}: {}{}",
+        output.status,
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    stdout
+}
+
+/// Runs `command` to completion, asserting it fails, and returns its stderr.
+pub async fn run_failing(mut command: Command) -> String {
+    let output = timeout(TIMEOUT, command.stderr(Stdio::piped()).output())
+        .await
+        .expect("command timed out")
+        .expect("command failed to start");
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    assert!(!output.status.success(), "{:?}: {}", output.status, stderr);
+    stderr
+}
+
+/// Fetches `path` from the HTTP server on `addr` and returns the whole response,
+/// status line and headers included.
+pub async fn http_get(addr: &str, path: &str) -> String {
+    let request = async {
+        let mut stream = TcpStream::connect(addr).await.expect("connect");
+        let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, addr);
+        stream.write_all(request.as_bytes()).await.expect("send");
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.expect("read");
+        response
+    };
+    timeout(TIMEOUT, request).await.expect("request timed out")
+}
+
+/// A binary running in the background, typically a consumer.
+pub struct Running {
+    child: Child,
+    lines: Lines<BufReader<ChildStdout>>,
+}
+
+impl Running {
+    pub fn spawn(mut command: Command) -> Self {
+        let mut child = command.spawn().expect("command failed to start");
+        let stdout = child.stdout.take().expect("piped stdout");
+        Self {
+            child,
+            lines: BufReader::new(stdout).lines(),
+        }
+    }
+
+    /// Reads stdout until a line containing `needle` shows up and returns it.
+    pub async fn wait_for(&mut self, needle: &str) -> String {
+        let lines = &mut self.lines;
+        let found = timeout(TIMEOUT, async {
+            while let Some(line) = lines.next_line().await.expect("read stdout") {
+                if line.contains(needle) {
+                    return Some(line);
+                }
+            }
+            None
+        })
+        .await;
+        match found {
+            Ok(Some(line)) => line,
+            Ok(None) => panic!("exited before printing {:?}", needle),
+            Err(_) => panic!("timed out waiting for {:?}", needle),
+        }
+    }
+
+    /// Sends SIGTERM and waits for a clean exit.
+    pub async fn stop(mut self) {
+        let pid = self.child.id().expect("still running");
+        let killed = std::process::Command::new("kill")
+            .args(["-TERM", &pid.to_string()])
+            .status()
+            .expect("run kill");
+        assert!(killed.success());
+        let status = timeout(TIMEOUT, self.child.wait())
+            .await
+            .expect("no exit after SIGTERM")
+            .expect("wait for exit");
+        assert!(status.success(), "{:?}", status);
+    }
+}
@@ -0,0 +1,113 @@
+//! TLS-terminating stand-in in front of the test broker.
+//!
+//! Presents `tests/fixtures/tls/server.pem`, issued by `ca.pem` to `rabbitmq.test`
+//! only, and requires a client certificate from the same CA, such as `client.pem`.
+//! The fixtures are P-256 keys and certificates valid for 100 years, made with
+//! `openssl req` and `openssl x509`; the CA key was discarded.
+
+use openssl::{
+    nid::Nid,
+    ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod, SslVerifyMode},
+};
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::copy_bidirectional,
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+use tokio_openssl::SslStream;
+
+/// Path of a file in `tests/fixtures/tls`.
+pub fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+/// Handle to a running stand-in. The listener stops when it is dropped.
+pub struct TlsProxy {
+    port: u16,
+    clients: Arc<Mutex<Vec<String>>>,
+    accept: JoinHandle<()>,
+}
+
+impl TlsProxy {
+    /// Terminates TLS on a random localhost port and forwards to `upstream`.
+    pub async fn start(upstream: u16) -> Self {
+        let mut builder =
+            SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).expect("TLS acceptor");
+        builder
+            .set_private_key_file(fixture("server.key"), SslFiletype::PEM)
+            .expect("server key");
+        builder
+            .set_certificate_chain_file(fixture("server.pem"))
+            .expect("server certificate");
+        builder.set_ca_file(fixture("ca.pem")).expect("CA");
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        let acceptor = Arc::new(builder.build());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind proxy");
+        let port = listener.local_addr().expect("proxy address").port();
+        let clients = Arc::new(Mutex::new(Vec::new()));
+
+        let accepted = clients.clone();
+        let accept = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(forward(
+                    acceptor.clone(),
+                    stream,
+                    upstream,
+                    accepted.clone(),
+                ));
+            }
+        });
+
+        Self {
+            port,
+            clients,
+            accept,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Common names of the client certificates of every completed handshake.
+    pub fn clients(&self) -> Vec<String> {
+        self.clients.lock().unwrap().clone()
+    }
+}
+
+impl Drop for TlsProxy {
+    fn drop(&mut self) {
+        self.accept.abort();
+    }
+}
+
+async fn forward(
+    acceptor: Arc<SslAcceptor>,
+    stream: TcpStream,
+    upstream: u16,
+    clients: Arc<Mutex<Vec<String>>>,
+) {
+    let ssl = Ssl::new(acceptor.context()).expect("TLS session");
+    let mut tls = SslStream::new(ssl, stream).expect("TLS stream");
+    // Clients failing verification are hung up on, as a real broker would
+    if Pin::new(&mut tls).accept().await.is_err() {
+        return;
+    }
+    let common_name = tls.ssl().peer_certificate().and_then(|certificate| {
+        let entry = certificate
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()?;
+        entry.data().to_string().ok()
+    });
+    clients.lock().unwrap().extend(common_name);
+
+    if let Ok(mut upstream) = TcpStream::connect(("127.0.0.1", upstream)).await {
+        let _ = copy_bidirectional(&mut tls, &mut upstream).await;
+    }
+}
@@ -0,0 +1,64 @@
+//! Reading the kind of a task and registering its handlers.
+
+use futures::future::{self, BoxFuture, FutureExt};
+use lapin::{types::AMQPValue, BasicProperties};
+use serde_json::Value;
+use tutorial_rs::{
+    headers::with_header, Delivery, Failure, JobReporter, TaskHandler, TaskRegistry,
+};
+
+struct Noop;
+
+impl TaskHandler for Noop {
+    fn handle<'a>(
+        &'a self,
+        _: &'a Delivery<Vec<u8>>,
+        _: &'a JobReporter,
+    ) -> BoxFuture<'a, Result<Value, Failure>> {
+        future::ready(Ok(Value::Null)).boxed()
+    }
+}
+
+#[test]
+fn the_type_property_names_the_task_kind() {
+    let properties = BasicProperties::default().with_kind("resize".into());
+    assert_eq!(
+        TaskRegistry::task_type(&properties).as_deref(),
+        Some("resize")
+    );
+}
+
+#[test]
+fn a_type_header_names_it_when_the_property_is_missing() {
+    let header = with_header(
+        BasicProperties::default(),
+        "type",
+        AMQPValue::LongString("thumbnail".into()),
+    );
+    assert_eq!(
+        TaskRegistry::task_type(&header).as_deref(),
+        Some("thumbnail")
+    );
+
+    let both = header.with_kind("resize".into());
+    assert_eq!(TaskRegistry::task_type(&both).as_deref(), Some("resize"));
+    assert_eq!(TaskRegistry::task_type(&BasicProperties::default()), None);
+}
+
+#[test]
+fn registered_kinds_are_listed_in_order() {
+    let registry = TaskRegistry::new()
+        .register("thumbnail", Noop)
+        .register("resize", Noop)
+        .register("resize", Noop)
+        .with_default("resize");
+
+    assert_eq!(
+        registry.kinds().collect::<Vec<_>>(),
+        vec!["resize", "thumbnail"]
+    );
+    assert_eq!(
+        format!("{:?}", registry),
+        r#"TaskRegistry { kinds: ["resize", "thumbnail"], default: Some("resize") }"#
+    );
+}
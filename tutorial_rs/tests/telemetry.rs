@@ -0,0 +1,114 @@
+//! W3C trace context parsing and propagation through message headers.
+
+use lapin::{types::AMQPValue, BasicProperties};
+use quickcheck::quickcheck;
+use tutorial_rs::{
+    headers::{header_str, with_header},
+    telemetry::{self, TRACEPARENT_HEADER, TRACESTATE_HEADER},
+    TraceContext,
+};
+
+const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+quickcheck! {
+    fn traceparent_round_trips(trace_id: u128, span_id: u64, sampled: bool) -> bool {
+        let context = TraceContext {
+            trace_id: trace_id.max(1),
+            span_id: span_id.max(1),
+            sampled,
+            state: None,
+        };
+        TraceContext::parse(&context.to_string(), None) == Some(context)
+    }
+}
+
+#[test]
+fn traceparent_is_parsed() {
+    let context = TraceContext::parse(TRACEPARENT, Some("vendor=abc")).unwrap();
+
+    assert_eq!(context.trace_id_hex(), "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_eq!(context.span_id_hex(), "00f067aa0ba902b7");
+    assert!(context.sampled);
+    assert_eq!(context.state.as_deref(), Some("vendor=abc"));
+    assert_eq!(context.to_string(), TRACEPARENT);
+}
+
+#[test]
+fn invalid_traceparents_are_ignored() {
+    for traceparent in &[
+        "",
+        "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+        "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+        "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+        "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+    ] {
+        assert_eq!(
+            TraceContext::parse(traceparent, None),
+            None,
+            "{}",
+            traceparent
+        );
+    }
+    // Later versions may carry more fields
+    assert!(TraceContext::parse(
+        "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-extra",
+        None
+    )
+    .is_some());
+}
+
+#[test]
+fn context_travels_in_the_headers() {
+    let context = TraceContext::parse(TRACEPARENT, Some("vendor=abc")).unwrap();
+    let properties = context.inject(BasicProperties::default());
+
+    assert_eq!(
+        header_str(&properties, TRACEPARENT_HEADER).as_deref(),
+        Some(TRACEPARENT)
+    );
+    assert_eq!(
+        header_str(&properties, TRACESTATE_HEADER).as_deref(),
+        Some("vendor=abc")
+    );
+    assert_eq!(TraceContext::extract(&properties), Some(context));
+}
+
+#[test]
+fn malformed_headers_start_no_trace() {
+    let properties = with_header(
+        BasicProperties::default(),
+        TRACEPARENT_HEADER,
+        AMQPValue::LongString("not a traceparent".into()),
+    );
+
+    assert_eq!(TraceContext::extract(&properties), None);
+    assert_eq!(TraceContext::extract(&BasicProperties::default()), None);
+}
+
+#[test]
+fn children_stay_in_the_trace() {
+    let parent = TraceContext::parse(TRACEPARENT, Some("vendor=abc")).unwrap();
+    let child = parent.child();
+
+    assert_eq!(child.trace_id, parent.trace_id);
+    assert_ne!(child.span_id, parent.span_id);
+    assert_eq!(child.state, parent.state);
+}
+
+#[tokio::test]
+async fn publishes_continue_the_current_trace() {
+    assert_eq!(TraceContext::current(), None);
+    let (_span, root) = telemetry::publish_span("", "hello");
+    assert_ne!(root.trace_id, 0);
+
+    let parent = TraceContext::parse(TRACEPARENT, None).unwrap();
+    let (_span, published) = parent
+        .clone()
+        .scope(async { telemetry::publish_span("logs", "info") })
+        .await;
+    assert_eq!(published.trace_id, parent.trace_id);
+    assert_ne!(published.span_id, parent.span_id);
+}
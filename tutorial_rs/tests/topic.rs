@@ -0,0 +1,221 @@
+//! Topic matching against RabbitMQ's semantics, by example and by property.
+
+use quickcheck::{quickcheck, Arbitrary, Gen};
+use tutorial_rs::{topic::TopicError, BindingKey, TopicIndex};
+
+/// A binding key over a tiny alphabet, so that matches are common.
+#[derive(Debug, Clone)]
+struct Pattern(Vec<String>);
+
+/// A routing key over the same alphabet.
+#[derive(Debug, Clone)]
+struct Key(Vec<String>);
+
+fn words(g: &mut Gen, alphabet: &[&str]) -> Vec<String> {
+    let len = usize::arbitrary(g) % 6;
+    (0..len)
+        .map(|_| g.choose(alphabet).unwrap().to_string())
+        .collect()
+}
+
+impl Arbitrary for Pattern {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Pattern(words(g, &["a", "b", "c", "", "*", "#"]))
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        Box::new(self.0.shrink().map(Pattern))
+    }
+}
+
+impl Arbitrary for Key {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Key(words(g, &["a", "b", "c", ""]))
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        Box::new(self.0.shrink().map(Key))
+    }
+}
+
+impl Pattern {
+    fn key(&self) -> BindingKey {
+        BindingKey::parse(&self.0.join(".")).unwrap()
+    }
+}
+
+impl Key {
+    fn as_string(&self) -> String {
+        self.0.join(".")
+    }
+}
+
+fn split(key: &str) -> Vec<&str> {
+    if key.is_empty() {
+        Vec::new()
+    } else {
+        key.split('.').collect()
+    }
+}
+
+/// Straightforward dynamic programming version of the matching rules:
+/// `matched[i][j]` tells whether `pattern[i..]` matches `words[j..]`.
+fn reference(pattern: &str, key: &str) -> bool {
+    let (pattern, words) = (split(pattern), split(key));
+    let (p, w) = (pattern.len(), words.len());
+    let mut matched = vec![vec![false; w + 1]; p + 1];
+    matched[p][w] = true;
+    for i in (0..p).rev() {
+        for j in (0..=w).rev() {
+            matched[i][j] = match pattern[i] {
+                "#" => matched[i + 1][j] || (j < w && matched[i][j + 1]),
+                "*" => j < w && matched[i + 1][j + 1],
+                literal => j < w && words[j] == literal && matched[i + 1][j + 1],
+            };
+        }
+    }
+    matched[0][0]
+}
+
+#[test]
+fn tutorial_examples() {
+    let cases = [
+        ("*.orange.*", "quick.orange.rabbit", true),
+        ("*.orange.*", "lazy.orange.elephant", true),
+        ("*.orange.*", "quick.orange.fox", true),
+        ("*.orange.*", "quick.brown.fox", false),
+        ("*.orange.*", "orange", false),
+        ("*.orange.*", "quick.orange.male.rabbit", false),
+        ("*.*.rabbit", "quick.orange.rabbit", true),
+        ("*.*.rabbit", "lazy.orange.male.rabbit", false),
+        ("lazy.#", "lazy.orange.male.rabbit", true),
+        ("lazy.#", "lazy", true),
+        ("lazy.#", "lazy.", true),
+        ("lazy.#", "lazyness", false),
+        ("#", "", true),
+        ("#", "anything.at.all", true),
+        ("*", "", false),
+        ("", "", true),
+        ("", "a", false),
+        ("a.#.b", "a.b", true),
+        ("a.#.b", "a.x.y.b", true),
+        ("#.#", "a", true),
+        ("a..b", "a..b", true),
+        ("a.*.b", "a..b", true),
+    ];
+    for (binding, routing_key, expected) in cases.iter() {
+        let key = BindingKey::parse(binding).unwrap();
+        assert_eq!(
+            key.matches(routing_key),
+            *expected,
+            "{} against {}",
+            binding,
+            routing_key
+        );
+    }
+}
+
+#[test]
+fn invalid_binding_keys_are_rejected() {
+    assert_eq!(
+        BindingKey::parse("kern*"),
+        Err(TopicError::InvalidWord("kern*".to_string()))
+    );
+    assert_eq!(
+        BindingKey::parse("a.##"),
+        Err(TopicError::InvalidWord("##".to_string()))
+    );
+    assert_eq!(
+        BindingKey::parse(&"a".repeat(256)),
+        Err(TopicError::TooLong(256))
+    );
+    assert!(BindingKey::parse(&"a".repeat(255)).is_ok());
+}
+
+#[test]
+fn index_returns_matches_in_insertion_order() {
+    let mut index = TopicIndex::new();
+    for (i, binding) in ["#", "kern.*", "*.critical", "kern.critical", "cron.#"]
+        .iter()
+        .enumerate()
+    {
+        index.insert(BindingKey::parse(binding).unwrap(), i);
+    }
+    let matched = index
+        .matches("kern.critical")
+        .into_iter()
+        .map(|(_, i)| *i)
+        .collect::<Vec<_>>();
+    assert_eq!(matched, vec![0, 1, 2, 3]);
+    assert_eq!(index.len(), 5);
+}
+
+#[test]
+fn many_hashes_against_a_long_key_are_quick() {
+    let binding = BindingKey::parse("#.#.#.#.#.#.#.z").unwrap();
+    let pattern = ["#"; 20].join(".") + ".a.#.#.#.z";
+    let long = BindingKey::parse(&pattern).unwrap();
+    let key = ["a"; 40].join(".");
+    let mut index = TopicIndex::new();
+    index.insert(binding.clone(), 0);
+    index.insert(long.clone(), 1);
+    for (binding, text) in [(&binding, "#.#.#.#.#.#.#.z"), (&long, pattern.as_str())].iter() {
+        assert!(!binding.matches(&key));
+        assert!(binding.matches(&(key.clone() + ".z")));
+        assert_eq!(binding.matches(&key), reference(text, &key));
+    }
+    assert!(index.matches(&key).is_empty());
+    assert_eq!(index.matches(&(key + ".z")).len(), 2);
+}
+
+#[test]
+fn matches_like_the_reference() {
+    fn prop(pattern: Pattern, key: Key) -> bool {
+        let routing_key = key.as_string();
+        pattern.key().matches(&routing_key) == reference(&pattern.0.join("."), &routing_key)
+    }
+    quickcheck(prop as fn(Pattern, Key) -> bool);
+}
+
+#[test]
+fn index_agrees_with_matching_each_binding() {
+    fn prop(patterns: Vec<Pattern>, key: Key) -> bool {
+        let routing_key = key.as_string();
+        let mut index = TopicIndex::new();
+        for (i, pattern) in patterns.iter().enumerate() {
+            index.insert(pattern.key(), i);
+        }
+        let indexed = index
+            .matches(&routing_key)
+            .into_iter()
+            .map(|(_, i)| *i)
+            .collect::<Vec<_>>();
+        let expected = patterns
+            .iter()
+            .enumerate()
+            .filter(|(_, pattern)| pattern.key().matches(&routing_key))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        indexed == expected
+    }
+    quickcheck(prop as fn(Vec<Pattern>, Key) -> bool);
+}
+
+#[test]
+fn filling_in_wildcards_gives_a_matching_key() {
+    fn prop(pattern: Pattern, fillers: Vec<Key>) -> bool {
+        let mut fillers = fillers.into_iter().cycle();
+        let mut words = Vec::new();
+        for word in &pattern.0 {
+            match word.as_str() {
+                "*" => words.push("x".to_string()),
+                "#" => words.extend(fillers.next().map(|key| key.0).unwrap_or_default()),
+                literal => words.push(literal.to_string()),
+            }
+        }
+        let routing_key = words.join(".");
+        // An empty word list and a single empty word both join into ""
+        words.len() == 1 && words[0].is_empty() || pattern.key().matches(&routing_key)
+    }
+    quickcheck(prop as fn(Pattern, Vec<Key>) -> bool);
+}
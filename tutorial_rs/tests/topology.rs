@@ -0,0 +1,126 @@
+//! Parsing, rendering and argument mapping of topology files.
+
+use lapin::types::AMQPValue;
+use std::path::Path;
+use tutorial_rs::{
+    topology::{Format, TopologyError},
+    Topology,
+};
+
+const YAML: &str = r#"
+exchanges:
+  - name: events
+    type: topic
+    durable: true
+queues:
+  - name: jobs
+    role: task_queue
+    durable: true
+    message_ttl: 60000
+    max_length: 1000
+    overflow: reject-publish
+    dead_letter_exchange: jobs.dlx
+    queue_type: quorum
+    arguments:
+      x-single-active-consumer: true
+bindings:
+  - exchange: events
+    queue: jobs
+    routing_key: "job.#"
+"#;
+
+const TOML: &str = r#"
+[[exchanges]]
+name = "events"
+type = "topic"
+durable = true
+
+[[queues]]
+name = "jobs"
+role = "task_queue"
+durable = true
+message_ttl = 60000
+max_length = 1000
+overflow = "reject-publish"
+dead_letter_exchange = "jobs.dlx"
+queue_type = "quorum"
+arguments = { x-single-active-consumer = true }
+
+[[bindings]]
+exchange = "events"
+queue = "jobs"
+routing_key = "job.#"
+"#;
+
+#[test]
+fn toml_and_yaml_describe_the_same_topology() {
+    let toml = Topology::parse(TOML, Format::Toml).unwrap();
+    let yaml = Topology::parse(YAML, Format::Yaml).unwrap();
+    assert_eq!(toml, yaml);
+
+    for format in [Format::Toml, Format::Yaml] {
+        let rendered = toml.render(format).unwrap();
+        assert_eq!(Topology::parse(&rendered, format).unwrap(), toml);
+    }
+}
+
+#[test]
+fn queue_settings_become_arguments() {
+    let topology = Topology::parse(TOML, Format::Toml).unwrap();
+    let queue = topology.queue("task_queue").unwrap();
+    assert_eq!(queue.name, "jobs");
+    assert!(queue.options().durable);
+
+    let arguments = queue.arguments();
+    let argument = |name: &str| arguments.inner().get(name).cloned();
+    assert_eq!(
+        argument("x-message-ttl"),
+        Some(AMQPValue::LongLongInt(60000))
+    );
+    assert_eq!(argument("x-max-length"), Some(AMQPValue::LongLongInt(1000)));
+    assert_eq!(
+        argument("x-overflow"),
+        Some(AMQPValue::LongString("reject-publish".into()))
+    );
+    assert_eq!(
+        argument("x-dead-letter-exchange"),
+        Some(AMQPValue::LongString("jobs.dlx".into()))
+    );
+    assert_eq!(
+        argument("x-queue-type"),
+        Some(AMQPValue::LongString("quorum".into()))
+    );
+    assert_eq!(
+        argument("x-single-active-consumer"),
+        Some(AMQPValue::Boolean(true))
+    );
+    assert_eq!(argument("x-expires"), None);
+}
+
+#[test]
+fn built_in_topology_has_every_tutorial_role() {
+    let topology = Topology::tutorials();
+    for queue in ["hello", "task_queue", "rpc_queue"] {
+        assert_eq!(topology.queue(queue).unwrap().name, queue);
+    }
+    for exchange in ["logs", "direct_logs", "topic_logs"] {
+        assert_eq!(topology.exchange(exchange).unwrap().name, exchange);
+    }
+}
+
+#[test]
+fn mistakes_are_reported() {
+    let typo = "[[queues]]\nname = \"hello\"\nmessage_tll = 5\n";
+    assert!(matches!(
+        Topology::parse(typo, Format::Toml),
+        Err(TopologyError::Invalid(reason)) if reason.contains("message_tll")
+    ));
+    assert!(matches!(
+        Topology::default().queue("hello"),
+        Err(TopologyError::Missing { kind: "queue", .. })
+    ));
+    assert!(matches!(
+        Topology::load(Path::new("topology.json")),
+        Err(TopologyError::UnknownFormat(_))
+    ));
+}
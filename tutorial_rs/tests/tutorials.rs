@@ -0,0 +1,994 @@
+//! Runs every tutorial end to end against the in-process broker.
+
+mod support;
+
+use lapin::{options::ExchangeDeclareOptions, types::FieldTable, ExchangeKind};
+use std::env;
+use support::{
+    channel_with_queue, fixture, http_get, run, run_failing, tutorial, tutorial_at, Broker,
+    Running, TlsProxy,
+};
+use uuid::Uuid;
+
+const HELLO_WORLD: &str = env!("CARGO_BIN_EXE_01_hello-world");
+const WORK_QUEUES: &str = env!("CARGO_BIN_EXE_02_work-queues");
+const PUBSUB: &str = env!("CARGO_BIN_EXE_03_pubsub");
+const ROUTING: &str = env!("CARGO_BIN_EXE_04_routing");
+const TOPICS: &str = env!("CARGO_BIN_EXE_05_topics");
+const RPC: &str = env!("CARGO_BIN_EXE_06_rpc");
+const TOPOLOGY: &str = env!("CARGO_BIN_EXE_topology");
+const SCHEDULER: &str = env!("CARGO_BIN_EXE_scheduler");
+
+const KEYRING: &str = r#"
+active = "k1"
+
+[[keys]]
+id = "k1"
+scheme = "aes-256-gcm"
+secret = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY="
+"#;
+
+/// Writes [`KEYRING`] to a file of its own and returns its path.
+fn keyring_file() -> String {
+    let path = env::temp_dir().join(format!("keyring-{}.toml", Uuid::new_v4()));
+    std::fs::write(&path, KEYRING).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn hello_world_is_queued_then_received() {
+    let broker = Broker::start().await;
+
+    let mut send = tutorial(HELLO_WORLD, &broker);
+    send.arg("--confirm");
+    let sent = run(send).await;
+    assert!(sent.contains("confirm=ack"), "{}", sent);
+    assert_eq!(broker.ready("hello"), vec![b"Hello World!".to_vec()]);
+
+    let mut receive = tutorial(HELLO_WORLD, &broker);
+    receive.arg("--receive");
+    let mut receiver = Running::spawn(receive);
+    receiver.wait_for("Received Hello World!").await;
+    receiver.stop().await;
+    assert!(broker.ready("hello").is_empty());
+}
+
+#[tokio::test]
+async fn receivers_skip_duplicates() {
+    let broker = Broker::start().await;
+    for _ in 0..2 {
+        run(tutorial(HELLO_WORLD, &broker)).await;
+    }
+
+    // Every message gets a message_id of its own, so only the body gives them away
+    let mut receive = tutorial(HELLO_WORLD, &broker);
+    receive.args(["--receive", "--dedup", "memory"]);
+    let mut receiver = Running::spawn(receive);
+    receiver.wait_for("Received Hello World!").await;
+    receiver.wait_for("Received Hello World!").await;
+    receiver.stop().await;
+
+    for _ in 0..2 {
+        run(tutorial(HELLO_WORLD, &broker)).await;
+    }
+    let mut receive = tutorial(HELLO_WORLD, &broker);
+    receive.args([
+        "--receive",
+        "--dedup",
+        "memory",
+        "--dedup-key",
+        "content-hash",
+    ]);
+    let mut receiver = Running::spawn(receive);
+    receiver.wait_for("Received Hello World!").await;
+    receiver.wait_for("Skipping duplicate").await;
+    receiver.stop().await;
+    assert!(broker.ready("hello").is_empty());
+}
+
+#[tokio::test]
+async fn receivers_decode_any_content_type() {
+    let broker = Broker::start().await;
+
+    for content_type in ["msgpack", "json", "cbor"] {
+        let mut send = tutorial(HELLO_WORLD, &broker);
+        send.args(["--content-type", content_type]);
+        run(send).await;
+    }
+    let ready = broker.ready("hello");
+    assert_eq!(ready[0], b"\xacHello World!".to_vec());
+    assert_eq!(ready[1], b"\"Hello World!\"".to_vec());
+
+    let mut receive = tutorial(HELLO_WORLD, &broker);
+    receive.arg("--receive");
+    let mut receiver = Running::spawn(receive);
+    for _ in 0..3 {
+        receiver.wait_for("Received Hello World!").await;
+    }
+    receiver.stop().await;
+}
+
+#[tokio::test]
+async fn compressed_messages_are_decompressed_by_receivers() {
+    let broker = Broker::start().await;
+
+    for algorithm in ["gzip", "zstd", "lz4"] {
+        let mut send = tutorial(HELLO_WORLD, &broker);
+        send.args(["--compress", algorithm, "--compress-threshold", "0"]);
+        run(send).await;
+    }
+    // Below the default threshold the body goes out as is
+    let mut send = tutorial(HELLO_WORLD, &broker);
+    send.args(["--compress", "gzip"]);
+    run(send).await;
+    let ready = broker.ready("hello");
+    assert_eq!(ready[0][..2], [0x1f, 0x8b]);
+    assert_ne!(ready[1], b"Hello World!".to_vec());
+    assert_eq!(ready[3], b"Hello World!".to_vec());
+
+    let mut receive = tutorial(HELLO_WORLD, &broker);
+    receive.arg("--receive");
+    let mut receiver = Running::spawn(receive);
+    for _ in 0..4 {
+        receiver.wait_for("Received Hello World!").await;
+    }
+    receiver.stop().await;
+
+    // A body decompressing past the limit is dropped rather than decoded
+    let mut send = tutorial(HELLO_WORLD, &broker);
+    send.args(["--compress", "zstd", "--compress-threshold", "0"]);
+    run(send).await;
+    let mut receive = tutorial(HELLO_WORLD, &broker);
+    receive.args(["--receive", "--max-decompressed", "8"]);
+    let mut receiver = Running::spawn(receive);
+    receiver
+        .wait_for("zstd body decompresses to more than 8 bytes")
+        .await;
+    receiver.stop().await;
+}
+
+#[tokio::test]
+async fn work_queue_batch_is_shared_between_workers() {
+    let broker = Broker::start().await;
+
+    let mut workers = Vec::new();
+    for _ in 0..2 {
+        let mut work = tutorial(WORK_QUEUES, &broker);
+        work.arg("--worker");
+        let mut worker = Running::spawn(work);
+        worker.wait_for("Waiting for messages").await;
+        workers.push(worker);
+    }
+
+    // Each dot keeps a worker busy for a second, so both get a fair share
+    let mut send = tutorial(WORK_QUEUES, &broker);
+    send.args(["--count", "4", "task."]);
+    let sent = run(send).await;
+    assert!(sent.contains("4 published, 4 acked"), "{}", sent);
+
+    for worker in &mut workers {
+        worker.wait_for("Done").await;
+        worker.wait_for("Done").await;
+    }
+    for worker in workers {
+        worker.stop().await;
+    }
+    assert!(broker.ready("task_queue").is_empty());
+}
+
+#[tokio::test]
+async fn concurrent_worker_handles_tasks_in_parallel() {
+    let broker = Broker::start().await;
+
+    let mut work = tutorial(WORK_QUEUES, &broker);
+    work.args([
+        "--worker",
+        "--concurrency",
+        "3",
+        "--utilisation-interval",
+        "1",
+    ]);
+    let mut worker = Running::spawn(work);
+    worker.wait_for("Waiting for messages").await;
+
+    // Two seconds each: one after the other, they would take six
+    let started = std::time::Instant::now();
+    let mut send = tutorial(WORK_QUEUES, &broker);
+    send.args(["--count", "3", "task.."]);
+    run(send).await;
+    for _ in 0..3 {
+        worker.wait_for("Done").await;
+    }
+    assert!(started.elapsed().as_secs() < 4, "{:?}", started.elapsed());
+
+    let utilisation = worker.wait_for("Slot utilisation").await;
+    assert_eq!(utilisation.matches('%').count(), 4, "{}", utilisation);
+    worker.stop().await;
+    assert!(broker.ready("task_queue").is_empty());
+}
+
+#[tokio::test]
+async fn tasks_are_dispatched_by_type() {
+    let broker = Broker::start().await;
+
+    let mut work = tutorial(WORK_QUEUES, &broker);
+    work.arg("--worker");
+    let mut worker = Running::spawn(work);
+    worker.wait_for("Waiting for messages").await;
+
+    let mut send = tutorial(WORK_QUEUES, &broker);
+    send.args(["--type", "wordcount", "three little words"]);
+    run(send).await;
+    worker.wait_for("Counted 3 words").await;
+
+    let mut send = tutorial(WORK_QUEUES, &broker);
+    send.args(["--type", "transcode", "movie.mp4"]);
+    run(send).await;
+    let failed = worker.wait_for("Failed (").await;
+    assert!(
+        failed.ends_with("permanent failure: no handler for task type `transcode`): parked"),
+        "{}",
+        failed
+    );
+    worker.stop().await;
+    assert_eq!(
+        broker.ready("task_queue.parking-lot"),
+        vec![b"movie.mp4".to_vec()]
+    );
+}
+
+#[tokio::test]
+async fn senders_wait_for_the_status_of_their_job() {
+    let broker = Broker::start().await;
+    let path = env::temp_dir().join(format!("results-{}.sqlite", Uuid::new_v4()));
+    let results = path.to_str().unwrap();
+
+    let mut work = tutorial(WORK_QUEUES, &broker);
+    work.args(["--worker", "--results", results]);
+    let mut worker = Running::spawn(work);
+    worker.wait_for("Waiting for messages").await;
+
+    let mut send = tutorial(WORK_QUEUES, &broker);
+    send.args(["--wait", "task.."]);
+    let sent = run(send).await;
+    for status in ["started (attempt 1)", "50% done", "succeeded"] {
+        assert!(sent.contains(&format!(": {}", status)), "{}", sent);
+    }
+
+    let mut send = tutorial(WORK_QUEUES, &broker);
+    send.args(["--wait", "--type", "wordcount", "three little words"]);
+    let sent = run(send).await;
+    assert!(sent.contains(r#"succeeded: {"words":3}"#), "{}", sent);
+    let job = sent
+        .split("job=")
+        .nth(1)
+        .and_then(|rest| rest.split_whitespace().next())
+        .expect("the job id is logged");
+
+    let mut query = tutorial(WORK_QUEUES, &broker);
+    query.args(["--results", results, "--job", job]);
+    let record: serde_json::Value = serde_json::from_str(&run(query).await).unwrap();
+    assert_eq!(record["state"], "succeeded");
+    assert_eq!(record["type"], "wordcount");
+    assert_eq!(record["result"]["words"], 3);
+    worker.stop().await;
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn senders_hear_of_jobs_settled_without_a_handler() {
+    let broker = Broker::start().await;
+    let keyring = keyring_file();
+
+    let mut work = tutorial(WORK_QUEUES, &broker);
+    work.args([
+        "--worker",
+        "--keyring",
+        &keyring,
+        "--dedup",
+        "memory",
+        "--dedup-key",
+        "content-hash",
+    ]);
+    let mut worker = Running::spawn(work);
+    worker.wait_for("Waiting for messages").await;
+
+    // Parked right away, as it can't be opened
+    let mut send = tutorial(WORK_QUEUES, &broker);
+    send.args(["--wait", "--wait-timeout", "5", "unsealed"]);
+    let failed = run_failing(send).await;
+    assert!(
+        failed.contains("failed: message is not sealed"),
+        "{}",
+        failed
+    );
+
+    let mut send = tutorial(WORK_QUEUES, &broker);
+    send.args(["--wait", "--keyring", &keyring, "twice"]);
+    let sent = run(send).await;
+    assert!(sent.contains(": succeeded"), "{}", sent);
+    let mut send = tutorial(WORK_QUEUES, &broker);
+    send.args([
+        "--wait",
+        "--wait-timeout",
+        "5",
+        "--keyring",
+        &keyring,
+        "twice",
+    ]);
+    let sent = run(send).await;
+    assert!(sent.contains(": skipped: duplicate"), "{}", sent);
+    worker.stop().await;
+}
+
+#[tokio::test]
+async fn delayed_tasks_wait_in_a_delay_queue() {
+    let broker = Broker::start().await;
+
+    let mut send = tutorial(WORK_QUEUES, &broker);
+    send.args(["--delay", "30s", "later"]);
+    let sent = run(send).await;
+    assert!(
+        sent.contains("Delaying by 30s through task_queue.delay.32000"),
+        "{}",
+        sent
+    );
+
+    let mut send = tutorial(WORK_QUEUES, &broker);
+    send.args(["--at", "2000-01-01T00:00:00Z", "overdue"]);
+    run(send).await;
+
+    assert_eq!(
+        broker.ready("task_queue.delay.32000"),
+        vec![b"later".to_vec()]
+    );
+    assert_eq!(
+        broker
+            .queue_argument("task_queue.delay.32000", "x-dead-letter-routing-key")
+            .as_deref(),
+        Some("task_queue")
+    );
+    assert_eq!(broker.ready("task_queue"), vec![b"overdue".to_vec()]);
+
+    let mut send = tutorial(WORK_QUEUES, &broker);
+    send.args(["--delay", "100years", "never"]);
+    let failed = run_failing(send).await;
+    assert!(
+        failed.contains("cannot hold a task back for 100years"),
+        "{}",
+        failed
+    );
+}
+
+#[tokio::test]
+async fn a_single_scheduler_fires_jobs_and_another_takes_over() {
+    let broker = Broker::start().await;
+    let path = env::temp_dir().join(format!("schedule-{}.toml", Uuid::new_v4()));
+    std::fs::write(
+        &path,
+        "[[jobs]]\nname = \"tick\"\ncron = \"* * * * * *\"\ntask = \"tick\"\n",
+    )
+    .unwrap();
+    let schedule = path.to_str().unwrap();
+    let mut send = tutorial(WORK_QUEUES, &broker);
+    send.arg("declared");
+    run(send).await;
+
+    let mut first = tutorial(SCHEDULER, &broker);
+    first.args([schedule, "--lock-retry", "1"]);
+    let mut first = Running::spawn(first);
+    first.wait_for("Leading").await;
+    let mut second = tutorial(SCHEDULER, &broker);
+    second.args([schedule, "--lock-retry", "1"]);
+    let mut second = Running::spawn(second);
+    second.wait_for("Standing by").await;
+
+    let enqueued = first.wait_for("Enqueued tick").await;
+    assert!(enqueued.contains("confirm=ack"), "{}", enqueued);
+    first.stop().await;
+    second.wait_for("Leading").await;
+    second.wait_for("Enqueued tick").await;
+    second.stop().await;
+
+    let ready = broker.ready("task_queue");
+    assert_eq!(ready[0], b"declared".to_vec());
+    assert!(ready.len() >= 3, "{:?}", ready);
+    assert!(ready[1..].iter().all(|task| task == b"tick"));
+}
+
+#[tokio::test]
+async fn scheduled_tasks_are_sealed_and_compressed_like_sent_ones() {
+    let broker = Broker::start().await;
+    let keyring = keyring_file();
+    let path = env::temp_dir().join(format!("schedule-{}.toml", Uuid::new_v4()));
+    std::fs::write(
+        &path,
+        "[[jobs]]\nname = \"tick\"\ncron = \"* * * * * *\"\ntask = \"sealed tick\"\n",
+    )
+    .unwrap();
+
+    let mut work = tutorial(WORK_QUEUES, &broker);
+    work.args(["--worker", "--keyring", &keyring]);
+    let mut worker = Running::spawn(work);
+    worker.wait_for("Waiting for messages").await;
+
+    let mut schedule = tutorial(SCHEDULER, &broker);
+    schedule.args([path.to_str().unwrap(), "--keyring", &keyring]);
+    schedule.args(["--compress", "zstd", "--compress-threshold", "0"]);
+    let mut scheduler = Running::spawn(schedule);
+    scheduler.wait_for("Enqueued sealed tick").await;
+    worker.wait_for("Received sealed tick").await;
+    scheduler.stop().await;
+    worker.stop().await;
+    assert!(broker.ready("task_queue.parking-lot").is_empty());
+}
+
+#[tokio::test]
+async fn failed_task_goes_to_its_retry_queue() {
+    let broker = Broker::start().await;
+
+    let mut work = tutorial(WORK_QUEUES, &broker);
+    work.args(["--worker", "--retry-delays", "60"]);
+    let mut worker = Running::spawn(work);
+    worker.wait_for("Waiting for messages").await;
+
+    let mut send = tutorial(WORK_QUEUES, &broker);
+    send.arg("please fail");
+    run(send).await;
+
+    let failed = worker.wait_for("Failed (").await;
+    assert!(failed.contains("retry #1 in 60s"), "{}", failed);
+    worker.stop().await;
+    assert_eq!(
+        broker.ready("task_queue.retry.60000"),
+        vec![b"please fail".to_vec()]
+    );
+    assert!(broker.queue_exists("task_queue.parking-lot"));
+}
+
+#[tokio::test]
+async fn workers_serve_their_metrics() {
+    let broker = Broker::start().await;
+
+    let mut work = tutorial(WORK_QUEUES, &broker);
+    work.args(["--worker", "--metrics-addr", "127.0.0.1:0"]);
+    let mut worker = Running::spawn(work);
+    let serving = worker.wait_for("Serving metrics on").await;
+    let addr = serving
+        .split("http://")
+        .nth(1)
+        .unwrap()
+        .trim_end_matches("/metrics")
+        .to_string();
+    worker.wait_for("Waiting for messages").await;
+
+    let mut send = tutorial(WORK_QUEUES, &broker);
+    send.arg("measured");
+    run(send).await;
+    worker.wait_for("Done").await;
+
+    // The task is acked and timed right after "Done" is printed
+    let lines = [
+        r#"amqp_delivered_total{queue="task_queue",binary="02_work-queues"} 1"#,
+        r#"amqp_acked_total{queue="task_queue",binary="02_work-queues"} 1"#,
+        r#"amqp_handler_duration_seconds_count{queue="task_queue",binary="02_work-queues"} 1"#,
+        r#"amqp_in_flight{queue="task_queue",binary="02_work-queues"} 0"#,
+        r#"amqp_slot_busy_seconds_total{slot="0",binary="02_work-queues"}"#,
+        // Its job started and succeeded
+        r#"amqp_published_total{exchange="task_status",binary="02_work-queues"} 2"#,
+    ];
+    let mut metrics = http_get(&addr, "/metrics").await;
+    for _ in 0..50 {
+        if lines.iter().all(|line| metrics.contains(line)) {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        metrics = http_get(&addr, "/metrics").await;
+    }
+    assert!(metrics.starts_with("HTTP/1.0 200"), "{}", metrics);
+    for line in &lines {
+        assert!(metrics.contains(line), "missing {} in\n{}", line, metrics);
+    }
+    assert!(http_get(&addr, "/").await.starts_with("HTTP/1.0 404"));
+    worker.stop().await;
+}
+
+#[tokio::test]
+async fn workers_report_their_health() {
+    let broker = Broker::start().await;
+
+    let mut work = tutorial(WORK_QUEUES, &broker);
+    work.args(["--worker", "--health-addr", "127.0.0.1:0"]);
+    let mut worker = Running::spawn(work);
+    let serving = worker.wait_for("Serving health checks on").await;
+    let addr = serving.split("http://").nth(1).unwrap().trim().to_string();
+    worker.wait_for("Waiting for messages").await;
+
+    let ready = http_get(&addr, "/readyz").await;
+    assert!(ready.starts_with("HTTP/1.0 200"), "{}", ready);
+    for field in &[
+        r#""ready":true"#,
+        r#""topology_declared":true"#,
+        r#""consumer_tag":"amq.ctag-"#,
+        r#""prefetch":1"#,
+        r#""last_delivery":null"#,
+    ] {
+        assert!(ready.contains(field), "missing {} in\n{}", field, ready);
+    }
+
+    let mut send = tutorial(WORK_QUEUES, &broker);
+    send.arg("checked");
+    run(send).await;
+    worker.wait_for("Done").await;
+
+    let healthy = http_get(&addr, "/healthz").await;
+    assert!(healthy.starts_with("HTTP/1.0 200"), "{}", healthy);
+    assert!(
+        healthy.contains(r#""connection":"connected""#),
+        "{}",
+        healthy
+    );
+    assert!(!healthy.contains(r#""last_delivery":null"#), "{}", healthy);
+    assert!(http_get(&addr, "/").await.starts_with("HTTP/1.0 404"));
+    worker.stop().await;
+}
+
+#[tokio::test]
+async fn sealed_tasks_are_opened_and_forged_ones_parked() {
+    let broker = Broker::start().await;
+    let keyring = keyring_file();
+
+    let mut work = tutorial(WORK_QUEUES, &broker);
+    work.args(["--worker", "--keyring", &keyring]);
+    let mut worker = Running::spawn(work);
+    worker.wait_for("Waiting for messages").await;
+
+    let mut send = tutorial(WORK_QUEUES, &broker);
+    send.args(["--keyring", &keyring, "sealed task"]);
+    run(send).await;
+    worker.wait_for("Received sealed task").await;
+
+    let mut forge = tutorial(WORK_QUEUES, &broker);
+    forge.arg("forged task");
+    run(forge).await;
+    worker
+        .wait_for("Rejecting message: message is not sealed")
+        .await;
+    worker.stop().await;
+    assert_eq!(
+        broker.ready("task_queue.parking-lot"),
+        vec![b"forged task".to_vec()]
+    );
+}
+
+#[tokio::test]
+async fn sealed_rpc_calls_are_answered_and_others_ignored() {
+    let broker = Broker::start().await;
+    let keyring = keyring_file();
+
+    let mut serve = tutorial(RPC, &broker);
+    serve.args(["--server", "--keyring", &keyring]);
+    let mut server = Running::spawn(serve);
+    server.wait_for("Awaiting RPC requests").await;
+
+    let mut call = tutorial(RPC, &broker);
+    call.args(["--keyring", &keyring, "10"]);
+    let replies = run(call).await;
+    assert!(replies.contains("Fib(10) = 55"), "{}", replies);
+
+    let mut call = tutorial(RPC, &broker);
+    call.args(["--timeout", "1", "10"]);
+    let replies = run(call).await;
+    assert!(
+        replies.contains("Fib(10) failed: no reply after 1s"),
+        "{}",
+        replies
+    );
+    server
+        .wait_for("Rejecting message: message is not sealed")
+        .await;
+    server.stop().await;
+}
+
+/// The `trace_id` of the span around the JSON log line whose message is `message`.
+fn trace_id_of(output: &str, message: &str) -> String {
+    output
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .find(|event| event["fields"]["message"] == message)
+        .and_then(|event| event["span"]["trace_id"].as_str().map(String::from))
+        .unwrap_or_else(|| panic!("no trace id for {:?} in\n{}", message, output))
+}
+
+#[tokio::test]
+async fn rpc_calls_and_their_handling_share_a_trace() {
+    let broker = Broker::start().await;
+
+    let mut serve = tutorial(RPC, &broker);
+    serve.args(["--server", "--log-format", "json"]);
+    let mut server = Running::spawn(serve);
+    server.wait_for("Awaiting RPC requests").await;
+
+    let mut call = tutorial(RPC, &broker);
+    call.args(["--log-format", "json", "10", "20"]);
+    let replies = run(call).await;
+    let first = server.wait_for("Handling Fib(").await;
+    let handled = format!("{}\n{}", first, server.wait_for("Handling Fib(").await);
+    server.stop().await;
+
+    let fib_10 = trace_id_of(&replies, "Fib(10) = 55");
+    let fib_20 = trace_id_of(&replies, "Fib(20) = 6765");
+    assert_ne!(fib_10, fib_20);
+    assert_eq!(trace_id_of(&handled, "Handling Fib(10)"), fib_10);
+    assert_eq!(trace_id_of(&handled, "Handling Fib(20)"), fib_20);
+}
+
+#[tokio::test]
+async fn pubsub_reaches_subscribers_and_returns_otherwise() {
+    let broker = Broker::start().await;
+
+    let mut emit = tutorial(PUBSUB, &broker);
+    emit.args(["--confirm", "nobody listens"]);
+    let emitted = run(emit).await;
+    assert!(emitted.contains("confirm=returned (312"), "{}", emitted);
+
+    let mut receivers = Vec::new();
+    for _ in 0..2 {
+        let mut receive = tutorial(PUBSUB, &broker);
+        receive.arg("--receiver");
+        let mut receiver = Running::spawn(receive);
+        receiver.wait_for("Waiting for messages").await;
+        receivers.push(receiver);
+    }
+
+    let mut emit = tutorial(PUBSUB, &broker);
+    emit.args(["--confirm", "info: hi all"]);
+    let emitted = run(emit).await;
+    assert!(emitted.contains("confirm=ack"), "{}", emitted);
+    for mut receiver in receivers {
+        receiver.wait_for("Received info: hi all").await;
+        receiver.stop().await;
+    }
+    assert!(broker.bindings("logs").is_empty());
+}
+
+#[tokio::test]
+async fn routing_delivers_by_severity() {
+    let broker = Broker::start().await;
+
+    let mut receive = tutorial(ROUTING, &broker);
+    receive.args(["--receiver", "error warning"]);
+    let mut receiver = Running::spawn(receive);
+    receiver.wait_for("Waiting for logs").await;
+
+    for (severity, msg) in &[("info", "ignored"), ("error", "disk full")] {
+        let mut emit = tutorial(ROUTING, &broker);
+        emit.args([*severity, *msg]);
+        run(emit).await;
+    }
+    let line = receiver.wait_for("Received ").await;
+    assert!(line.ends_with("Received \"error:disk full\""), "{}", line);
+    receiver.stop().await;
+}
+
+#[tokio::test]
+async fn routing_receivers_skip_duplicates() {
+    let broker = Broker::start().await;
+
+    let mut receive = tutorial(ROUTING, &broker);
+    receive.args([
+        "--receiver",
+        "--dedup",
+        "memory",
+        "--dedup-key",
+        "content-hash",
+        "error",
+    ]);
+    let mut receiver = Running::spawn(receive);
+    receiver.wait_for("Waiting for logs").await;
+
+    for msg in &["disk full", "disk full", "disk freed"] {
+        let mut emit = tutorial(ROUTING, &broker);
+        emit.args(["error", *msg]);
+        run(emit).await;
+    }
+    receiver.wait_for("Received \"error:disk full\"").await;
+    receiver.wait_for("Skipping duplicate").await;
+    receiver.wait_for("Received \"error:disk freed\"").await;
+    receiver.stop().await;
+}
+
+#[tokio::test]
+async fn topics_match_wildcards() {
+    let broker = Broker::start().await;
+
+    let mut receive = tutorial(TOPICS, &broker);
+    receive.args(["--receiver", "kern.* *.critical"]);
+    let mut receiver = Running::spawn(receive);
+    receiver.wait_for("Waiting for logs").await;
+
+    for key in &["cron.info", "kern.disk.info", "app.critical"] {
+        let mut emit = tutorial(TOPICS, &broker);
+        emit.args([*key, "msg"]);
+        run(emit).await;
+    }
+    let line = receiver.wait_for("Received ").await;
+    assert!(line.ends_with("Received \"app.critical:msg\""), "{}", line);
+    receiver.stop().await;
+}
+
+#[tokio::test]
+async fn topics_dry_run_lists_matching_bindings() {
+    let broker = Broker::start().await;
+
+    let mut emit = tutorial(TOPICS, &broker);
+    emit.args(["--dry-run", "kern.* *.critical lazy.#", "kern.critical"]);
+    let listed = run(emit).await;
+    assert!(
+        listed.contains("     kern.*\n     *.critical\n"),
+        "{}",
+        listed
+    );
+    assert!(!listed.contains("lazy.#"), "{}", listed);
+    assert!(broker.bindings("topic_logs").is_empty());
+}
+
+#[tokio::test]
+async fn rpc_replies_through_both_reply_modes() {
+    let broker = Broker::start().await;
+
+    let mut serve = tutorial(RPC, &broker);
+    serve.arg("--server");
+    let mut server = Running::spawn(serve);
+    server.wait_for("Awaiting RPC requests").await;
+
+    let mut call = tutorial(RPC, &broker);
+    call.args(["10", "20"]);
+    let replies = run(call).await;
+    assert!(replies.contains("Fib(10) = 55"), "{}", replies);
+    assert!(replies.contains("Fib(20) = 6765"), "{}", replies);
+    assert!(replies.contains("amq.rabbitmq.reply-to"), "{}", replies);
+
+    let mut call = tutorial(RPC, &broker);
+    call.args([
+        "--reply-queue",
+        "--content-type",
+        "msgpack",
+        "--procedure",
+        "factorial",
+        "5",
+        "30",
+    ]);
+    let replies = run(call).await;
+    assert!(replies.contains("Factorial(5) = 120"), "{}", replies);
+    assert!(
+        replies.contains("Factorial(30) failed: server error: out_of_range"),
+        "{}",
+        replies
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn rpc_answers_the_largest_fib_quickly() {
+    let broker = Broker::start().await;
+
+    let mut serve = tutorial(RPC, &broker);
+    serve.arg("--server");
+    let mut server = Running::spawn(serve);
+    server.wait_for("Awaiting RPC requests").await;
+
+    let mut call = tutorial(RPC, &broker);
+    call.args(["--timeout", "2", "93"]);
+    let replies = run(call).await;
+    assert!(
+        replies.contains("Fib(93) = 12200160415121876738"),
+        "{}",
+        replies
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn rpc_servers_answer_duplicate_requests_with_their_first_reply() {
+    let broker = Broker::start().await;
+
+    let mut serve = tutorial(RPC, &broker);
+    serve.args([
+        "--server",
+        "--dedup",
+        "memory",
+        "--dedup-key",
+        "content-hash",
+    ]);
+    let mut server = Running::spawn(serve);
+    server.wait_for("Awaiting RPC requests").await;
+
+    for _ in 0..2 {
+        let mut call = tutorial(RPC, &broker);
+        call.args(["--timeout", "2", "10"]);
+        let replies = run(call).await;
+        assert!(replies.contains("Fib(10) = 55"), "{}", replies);
+    }
+    server.wait_for("Answering duplicate").await;
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn topology_is_applied_idempotently() {
+    let broker = Broker::start().await;
+
+    let mut diff = tutorial(TOPOLOGY, &broker);
+    diff.arg("diff");
+    let missing = run(diff).await;
+    assert!(
+        missing.contains(" [+] exchange `topic_logs` (topic)"),
+        "{}",
+        missing
+    );
+    assert!(missing.contains(" [*] 12 to create"), "{}", missing);
+
+    for _ in 0..2 {
+        let mut apply = tutorial(TOPOLOGY, &broker);
+        apply.arg("apply");
+        run(apply).await;
+    }
+    assert_eq!(
+        broker.queue_argument("task_queue.retry.5000", "x-message-ttl"),
+        Some("5000".to_string())
+    );
+    assert_eq!(
+        broker.bindings("task_queue.dlx"),
+        vec![("task_queue.parking-lot".to_string(), "".to_string())]
+    );
+
+    let mut diff = tutorial(TOPOLOGY, &broker);
+    diff.arg("diff");
+    let declared = run(diff).await;
+    assert!(declared.contains(" [=] queue `hello`"), "{}", declared);
+    assert!(declared.contains(" [*] 0 to create"), "{}", declared);
+
+    // The work queue the tutorial declares agrees with the topology
+    let mut send = tutorial(WORK_QUEUES, &broker);
+    send.arg("task");
+    run(send).await;
+    assert_eq!(broker.ready("task_queue"), vec![b"task".to_vec()]);
+}
+
+#[tokio::test]
+async fn topology_diff_reports_entities_declared_differently() {
+    let broker = Broker::start().await;
+    // Without the dead-letter exchange and the fanout where the topology has a topic
+    let channel = channel_with_queue(&broker, "task_queue").await;
+    for exchange in &["topic_logs", "logs"] {
+        channel
+            .exchange_declare(
+                exchange,
+                ExchangeKind::Fanout,
+                ExchangeDeclareOptions::default(),
+                FieldTable::default(),
+            )
+            .await
+            .unwrap();
+    }
+
+    let mut diff = tutorial(TOPOLOGY, &broker);
+    diff.arg("diff");
+    let differing = run(diff).await;
+    for line in &[
+        " [~] exchange `topic_logs` (topic) differs",
+        " [~] queue `task_queue` differs",
+        " [=] exchange `logs` (fanout)",
+        " [+] queue `hello`",
+        " [*] 9 to create, 2 differing",
+    ] {
+        assert!(
+            differing.contains(line),
+            "missing {} in\n{}",
+            line,
+            differing
+        );
+    }
+    // Nothing was created or changed along the way
+    assert!(!broker.queue_exists("hello"));
+    assert_eq!(
+        broker.queue_argument("task_queue", "x-dead-letter-exchange"),
+        None
+    );
+}
+
+#[tokio::test]
+async fn workers_exit_when_the_queue_exists_with_other_arguments() {
+    let broker = Broker::start().await;
+    let path = env::temp_dir().join(format!("topology-{}.yaml", Uuid::new_v4()));
+    std::fs::write(
+        &path,
+        "queues:\n  - name: task_queue\n    message_ttl: 60000\nexchanges:\n  - name: task_status\n    type: topic\n",
+    )
+    .unwrap();
+    let mut send = tutorial(WORK_QUEUES, &broker);
+    send.args(["--topology", path.to_str().unwrap()]);
+    run(send).await;
+
+    // Declared with another TTL, which reconnecting would never fix
+    let mut work = tutorial(WORK_QUEUES, &broker);
+    work.arg("--worker");
+    let failed = run_failing(work).await;
+    assert!(failed.contains("PRECONDITION_FAILED"), "{}", failed);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn tutorials_take_names_from_the_topology_file() {
+    let broker = Broker::start().await;
+    let path = env::temp_dir().join(format!("topology-{}.yaml", Uuid::new_v4()));
+    std::fs::write(
+        &path,
+        "queues:\n  - name: greetings\n    role: hello\n    message_ttl: 60000\n",
+    )
+    .unwrap();
+    let path = path.to_str().unwrap();
+
+    let mut send = tutorial(HELLO_WORLD, &broker);
+    send.args(["--topology", path]);
+    run(send).await;
+    assert_eq!(broker.ready("greetings"), vec![b"Hello World!".to_vec()]);
+    assert!(!broker.queue_exists("hello"));
+
+    let mut pubsub = tutorial(PUBSUB, &broker);
+    pubsub.args(["--topology", path]);
+    let output = pubsub.output().await.unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no exchange for `logs`"));
+
+    // Redeclaring with other arguments is refused by the broker
+    std::fs::write(path, "queues:\n  - name: greetings\n").unwrap();
+    let mut apply = tutorial(TOPOLOGY, &broker);
+    apply.args(["--topology", path, "apply"]);
+    let output = apply.output().await.unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("PRECONDITION"));
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn tutorials_connect_over_tls_with_a_client_certificate() {
+    let broker = Broker::start().await;
+    let proxy = TlsProxy::start(broker.port()).await;
+    let ca = fixture("ca.pem");
+    let trust = ["--tls", "--ca-file", &ca];
+    let client = [
+        "--cert",
+        &fixture("client.pem"),
+        "--key",
+        &fixture("client.key"),
+    ];
+
+    let mut send = tutorial_at(HELLO_WORLD, proxy.port());
+    send.args(trust)
+        .args(client)
+        .args(["--tls-server-name", "rabbitmq.test", "--confirm"]);
+    let sent = run(send).await;
+    assert!(sent.contains("confirm=ack"), "{}", sent);
+    assert_eq!(broker.ready("hello"), vec![b"Hello World!".to_vec()]);
+    assert_eq!(proxy.clients(), vec!["tutorial-client".to_string()]);
+    assert_eq!(broker.mechanisms(), vec!["EXTERNAL".to_string()]);
+
+    // The certificate is issued to `rabbitmq.test`, not to the address connected to
+    let mut mismatch = tutorial_at(HELLO_WORLD, proxy.port());
+    mismatch.args(trust).args(client);
+    let output = mismatch.output().await.unwrap();
+    assert!(!output.status.success());
+
+    // The stand-in requires a client certificate
+    let mut anonymous = tutorial_at(HELLO_WORLD, proxy.port());
+    anonymous
+        .args(trust)
+        .args(["--tls-server-name", "rabbitmq.test"]);
+    let output = anonymous.output().await.unwrap();
+    assert!(!output.status.success());
+    assert_eq!(broker.ready("hello").len(), 1);
+}